@@ -0,0 +1,294 @@
+use crate::*;
+use std::io;
+
+// The format libsparse/img2simg write and fastboot flashes; see system/core/libsparse/sparse_format.h.
+pub const MAGIC : u32 = 0xED26_FF3A;
+const MAJOR_VERSION : u16 = 1;
+const FILE_HEADER_LEN : usize = 28;
+const CHUNK_HEADER_LEN : usize = 12;
+
+const CHUNK_RAW : u16 = 0xCAC1;
+const CHUNK_FILL : u16 = 0xCAC2;
+const CHUNK_DONT_CARE : u16 = 0xCAC3;
+const CHUNK_CRC32 : u16 = 0xCAC4;
+
+// Byte offset of total_chunks in the file header, patched once the image is finished.
+const TOTAL_CHUNKS_OFFSET : u64 = 20;
+
+fn le_u16(bytes : &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+fn le_u16_bytes(val : u16) -> [u8 ; 2] {
+    [val as u8, (val >> 8) as u8]
+}
+
+fn invalid(message : String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Android sparse image: {}", message))
+}
+
+pub fn has_magic(bytes : &[u8]) -> bool {
+    bytes.len() >= 4 && bot::le_u32(&bytes[0..4]) == MAGIC
+}
+
+/// Writes an Android sparse image: data as RAW chunks and zeros as FILL chunks,
+/// so that flashing it writes every block. The output is only valid after `finish`.
+pub struct SparseWriter<W : Write + Seek> {
+    out : W,
+    block_size : u32,
+    total_blocks : u32,
+    written_blocks : u32,
+    chunks : u32,
+    pending_zero_blocks : u32,
+}
+
+impl <W : Write + Seek> SparseWriter<W> {
+    /// `block_size` must be a multiple of 4, and everything written a multiple of it.
+    pub fn new(mut out : W, block_size : u32, total_blocks : u32) -> io::Result<SparseWriter<W>> {
+        if block_size == 0 || block_size % 4 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Sparse block size {} is not a multiple of 4.", block_size)));
+        }
+        let mut header = [0 ; FILE_HEADER_LEN];
+        header[0..4].copy_from_slice(&bot::le_u32_bytes(MAGIC));
+        header[4..6].copy_from_slice(&le_u16_bytes(MAJOR_VERSION));
+        header[8..10].copy_from_slice(&le_u16_bytes(FILE_HEADER_LEN as u16));
+        header[10..12].copy_from_slice(&le_u16_bytes(CHUNK_HEADER_LEN as u16));
+        header[12..16].copy_from_slice(&bot::le_u32_bytes(block_size));
+        header[16..20].copy_from_slice(&bot::le_u32_bytes(total_blocks));
+        out.write_all(&header)?;
+        Ok(SparseWriter { out, block_size, total_blocks, written_blocks : 0, chunks : 0, pending_zero_blocks : 0 })
+    }
+
+    fn blocks_in(&self, len : u64) -> io::Result<u32> {
+        if len % self.block_size as u64 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} bytes is not a whole number of {}-byte sparse blocks.", len, self.block_size)));
+        }
+        let blocks = len / self.block_size as u64;
+        if self.written_blocks as u64 + self.pending_zero_blocks as u64 + blocks > self.total_blocks as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Writing {} more blocks would pass the {} the header promised.", blocks, self.total_blocks)));
+        }
+        Ok(blocks as u32)
+    }
+
+    fn write_chunk_header(&mut self, chunk_type : u16, blocks : u32, data_len : usize) -> io::Result<()> {
+        let mut header = [0 ; CHUNK_HEADER_LEN];
+        header[0..2].copy_from_slice(&le_u16_bytes(chunk_type));
+        header[4..8].copy_from_slice(&bot::le_u32_bytes(blocks));
+        header[8..12].copy_from_slice(&bot::le_u32_bytes((CHUNK_HEADER_LEN + data_len) as u32));
+        self.out.write_all(&header)?;
+        self.chunks += 1;
+        self.written_blocks += blocks;
+        Ok(())
+    }
+
+    fn flush_zeros(&mut self) -> io::Result<()> {
+        if self.pending_zero_blocks > 0 {
+            let blocks = self.pending_zero_blocks;
+            self.pending_zero_blocks = 0;
+            self.write_chunk_header(CHUNK_FILL, blocks, 4)?;
+            self.out.write_all(&[0 ; 4])?;
+        }
+        Ok(())
+    }
+
+    pub fn write_data(&mut self, data : &[u8]) -> io::Result<()> {
+        let blocks = self.blocks_in(data.len() as u64)?;
+        self.flush_zeros()?;
+        self.write_chunk_header(CHUNK_RAW, blocks, data.len())?;
+        self.out.write_all(data)
+    }
+
+    /// Adjacent runs of zeros are merged into a single FILL chunk.
+    pub fn write_zeros(&mut self, len : u64) -> io::Result<()> {
+        let blocks = self.blocks_in(len)?;
+        self.pending_zero_blocks += blocks;
+        Ok(())
+    }
+
+    /// Pads any blocks never written with zeros and fills in the chunk count.
+    pub fn finish(mut self) -> io::Result<W> {
+        let missing = self.total_blocks - self.written_blocks - self.pending_zero_blocks;
+        self.pending_zero_blocks += missing;
+        self.flush_zeros()?;
+        self.out.seek(SeekFrom::Start(TOTAL_CHUNKS_OFFSET))?;
+        self.out.write_all(&bot::le_u32_bytes(self.chunks))?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+enum Chunk {
+    Raw,
+    Fill([u8 ; 4]),
+    Zero,
+    DontCare,
+}
+
+/// Expands an Android sparse image back into the raw bytes it describes.
+/// DONT_CARE chunks read as zeros unless they're passed over with `skip_dont_care`.
+pub struct SparseReader<R : Read> {
+    input : R,
+    block_size : u32,
+    total_blocks : u32,
+    chunk_header_len : usize,
+    chunks_left : u32,
+    blocks_seen : u64,
+    chunk : Chunk,
+    // Bytes of output left in the current chunk, and how far into it we are.
+    chunk_left : u64,
+    chunk_pos : u64,
+}
+
+impl <R : Read> SparseReader<R> {
+    pub fn new(mut input : R) -> io::Result<SparseReader<R>> {
+        let mut header = [0 ; FILE_HEADER_LEN];
+        input.read_exact(&mut header)?;
+        if !has_magic(&header) {
+            return Err(invalid("bad magic".to_owned()));
+        }
+        let major = le_u16(&header[4..6]);
+        if major != MAJOR_VERSION {
+            return Err(invalid(format!("unsupported major version {}", major)));
+        }
+        let file_header_len = le_u16(&header[8..10]) as usize;
+        let chunk_header_len = le_u16(&header[10..12]) as usize;
+        if file_header_len < FILE_HEADER_LEN || chunk_header_len < CHUNK_HEADER_LEN {
+            return Err(invalid(format!("header sizes {} and {} are too small", file_header_len, chunk_header_len)));
+        }
+        let block_size = bot::le_u32(&header[12..16]);
+        if block_size == 0 || block_size % 4 != 0 {
+            return Err(invalid(format!("block size {}", block_size)));
+        }
+        // Later versions may grow the header; skip what we don't know about.
+        io::copy(&mut (&mut input).take((file_header_len - FILE_HEADER_LEN) as u64), &mut io::sink())?;
+        Ok(SparseReader {
+            input,
+            block_size,
+            total_blocks : bot::le_u32(&header[16..20]),
+            chunk_header_len,
+            chunks_left : bot::le_u32(&header[20..24]),
+            blocks_seen : 0,
+            chunk : Chunk::Zero,
+            chunk_left : 0,
+            chunk_pos : 0,
+        })
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// The length of the expanded image.
+    pub fn raw_len(&self) -> u64 {
+        self.total_blocks as u64 * self.block_size as u64
+    }
+
+    // Reads chunk headers until one produces output. Returns false at the end of the image.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        loop {
+            if self.chunks_left == 0 {
+                // A short image is padded out to the length its header promised.
+                let missing = (self.total_blocks as u64).saturating_sub(self.blocks_seen);
+                if missing == 0 {
+                    return Ok(false);
+                }
+                self.blocks_seen += missing;
+                self.start_chunk(Chunk::Zero, missing);
+                return Ok(true);
+            }
+            self.chunks_left -= 1;
+            let mut header = vec![0 ; self.chunk_header_len];
+            self.input.read_exact(&mut header)?;
+            let chunk_type = le_u16(&header[0..2]);
+            let blocks = bot::le_u32(&header[4..8]) as u64;
+            let total_len = bot::le_u32(&header[8..12]) as u64;
+            let data_len = total_len.checked_sub(self.chunk_header_len as u64)
+                .ok_or_else(|| invalid(format!("chunk length {} is shorter than its header", total_len)))?;
+            self.blocks_seen += blocks;
+            if self.blocks_seen > self.total_blocks as u64 {
+                return Err(invalid(format!("chunks describe more than the {} blocks in the header", self.total_blocks)));
+            }
+            match chunk_type {
+                CHUNK_RAW => {
+                    if data_len != blocks * self.block_size as u64 {
+                        return Err(invalid(format!("RAW chunk of {} blocks carries {} bytes", blocks, data_len)));
+                    }
+                    self.start_chunk(Chunk::Raw, blocks);
+                },
+                CHUNK_FILL => {
+                    if data_len != 4 {
+                        return Err(invalid(format!("FILL chunk carries {} bytes", data_len)));
+                    }
+                    let mut pattern = [0 ; 4];
+                    self.input.read_exact(&mut pattern)?;
+                    self.start_chunk(Chunk::Fill(pattern), blocks);
+                },
+                CHUNK_DONT_CARE => self.start_chunk(Chunk::DontCare, blocks),
+                CHUNK_CRC32 => {
+                    io::copy(&mut (&mut self.input).take(data_len), &mut io::sink())?;
+                    continue;
+                },
+                other => return Err(invalid(format!("unknown chunk type 0x{:04X}", other))),
+            }
+            if self.chunk_left > 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Consumes the rest of the DONT_CARE chunk at the current position, if that's where
+    /// the reader is, and returns how many bytes of output it stood for.
+    pub fn skip_dont_care(&mut self) -> io::Result<u64> {
+        if self.chunk_left == 0 && !self.next_chunk()? {
+            return Ok(0);
+        }
+        match self.chunk {
+            Chunk::DontCare => {
+                let len = self.chunk_left;
+                self.chunk_left = 0;
+                Ok(len)
+            },
+            _ => Ok(0),
+        }
+    }
+
+    fn start_chunk(&mut self, chunk : Chunk, blocks : u64) {
+        self.chunk = chunk;
+        self.chunk_left = blocks * self.block_size as u64;
+        self.chunk_pos = 0;
+    }
+}
+
+impl <R : Read> Read for SparseReader<R> {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        if self.chunk_left == 0 && !self.next_chunk()? {
+            return Ok(0);
+        }
+        let count = (buf.len() as u64).min(self.chunk_left) as usize;
+        let count = match self.chunk {
+            Chunk::Raw => {
+                let red = self.input.read(&mut buf[.. count])?;
+                if red == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Android sparse image ends inside a RAW chunk."));
+                }
+                red
+            },
+            Chunk::Fill(pattern) => {
+                for (idx, byte) in buf[.. count].iter_mut().enumerate() {
+                    *byte = pattern[((self.chunk_pos + idx as u64) % 4) as usize];
+                }
+                count
+            },
+            Chunk::Zero | Chunk::DontCare => {
+                for byte in buf[.. count].iter_mut() {
+                    *byte = 0;
+                }
+                count
+            },
+        };
+        self.chunk_left -= count as u64;
+        self.chunk_pos += count as u64;
+        Ok(count)
+    }
+}
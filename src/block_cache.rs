@@ -0,0 +1,240 @@
+use crate::*;
+use std::io;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub hits : u64,
+    pub misses : u64,
+    pub evictions : u64,
+    pub writebacks : u64,
+    pub prefetched : u64,
+}
+
+struct CachedBlock {
+    lba : u64,
+    data : Vec<u8>,
+    dirty : bool,
+    last_used : u64,
+}
+
+/// A write-back cache of up to `capacity` device blocks with LRU eviction.
+pub struct BlockCache {
+    blocks : Vec<CachedBlock>,
+    capacity : usize,
+    clock : u64,
+    stats : CacheStats,
+}
+
+impl BlockCache {
+    pub fn new(capacity : usize) -> BlockCache {
+        BlockCache {
+            blocks : Vec::with_capacity(capacity.max(1)),
+            capacity : capacity.max(1),
+            clock : 0,
+            stats : CacheStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    pub fn set_capacity<D : BlockDevice>(&mut self, device : &mut D, capacity : usize) -> io::Result<()> {
+        self.capacity = capacity.max(1);
+        while self.blocks.len() > self.capacity {
+            self.evict_one(device)?;
+        }
+        Ok(())
+    }
+
+    fn find(&self, lba : u64) -> Option<usize> {
+        self.blocks.iter().position(|block| block.lba == lba)
+    }
+
+    fn touch(&mut self, idx : usize) {
+        self.clock += 1;
+        self.blocks[idx].last_used = self.clock;
+    }
+
+    fn write_back<D : BlockDevice>(device : &mut D, block : &mut CachedBlock, stats : &mut CacheStats) -> io::Result<()> {
+        if block.dirty {
+            device.write_blocks(block.lba, &block.data)?;
+            block.dirty = false;
+            stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    fn evict_one<D : BlockDevice>(&mut self, device : &mut D) -> io::Result<()> {
+        let victim = match self.blocks.iter().enumerate().min_by_key(|(_, block)| block.last_used) {
+            Some((idx, _)) => idx,
+            None => return Ok(()),
+        };
+        BlockCache::write_back(device, &mut self.blocks[victim], &mut self.stats)?;
+        self.blocks.swap_remove(victim);
+        self.stats.evictions += 1;
+        Ok(())
+    }
+
+    pub fn contains(&self, lba : u64) -> bool {
+        self.find(lba).is_some()
+    }
+
+    // Returns the slot holding `lba`. On a miss, reads `lba` and up to
+    // `readahead` following blocks in one request; blocks already cached are left alone.
+    fn load<D : BlockDevice>(&mut self, device : &mut D, lba : u64, readahead : usize) -> io::Result<usize> {
+        if let Some(idx) = self.find(lba) {
+            self.stats.hits += 1;
+            self.touch(idx);
+            return Ok(idx);
+        }
+        self.stats.misses += 1;
+        let block_size = device.block_size();
+        // Prefetching more than capacity - 1 blocks would evict the one asked for.
+        let prefetch = readahead.min(self.capacity - 1) as u64;
+        let count = (prefetch + 1)
+            .min(device.block_count().saturating_sub(lba))
+            .max(1) as usize;
+        let mut data = vec![0 ; count * block_size];
+        device.read_blocks(lba, &mut data)?;
+        for extra in (1..count).rev() {
+            let extra_lba = lba + extra as u64;
+            if self.contains(extra_lba) {
+                continue;
+            }
+            let start = extra * block_size;
+            self.insert(device, extra_lba, data[start .. start + block_size].to_vec(), false)?;
+            self.stats.prefetched += 1;
+        }
+        data.truncate(block_size);
+        self.insert(device, lba, data, false)
+    }
+
+    fn insert<D : BlockDevice>(&mut self, device : &mut D, lba : u64, data : Vec<u8>, dirty : bool) -> io::Result<usize> {
+        while self.blocks.len() >= self.capacity {
+            self.evict_one(device)?;
+        }
+        self.blocks.push(CachedBlock { lba, data, dirty, last_used : 0 });
+        let idx = self.blocks.len() - 1;
+        self.touch(idx);
+        Ok(idx)
+    }
+
+    pub fn block<D : BlockDevice>(&mut self, device : &mut D, lba : u64) -> io::Result<&[u8]> {
+        self.block_with_readahead(device, lba, 0)
+    }
+
+    pub fn block_with_readahead<D : BlockDevice>(&mut self, device : &mut D, lba : u64, readahead : usize) -> io::Result<&[u8]> {
+        let idx = self.load(device, lba, readahead)?;
+        Ok(&self.blocks[idx].data)
+    }
+
+    /// Copies `bytes` into the cached copy of `lba` at `offset`, only marking
+    /// the block dirty if its contents actually change.
+    pub fn write_bytes<D : BlockDevice>(&mut self, device : &mut D, lba : u64, offset : usize, bytes : &[u8]) -> io::Result<()> {
+        let idx = self.load(device, lba, 0)?;
+        let block = &mut self.blocks[idx];
+        let target = &mut block.data[offset .. offset + bytes.len()];
+        if target != bytes {
+            target.copy_from_slice(bytes);
+            block.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Writes back every dirty block in LBA order, keeping them cached.
+    pub fn flush<D : BlockDevice>(&mut self, device : &mut D) -> io::Result<()> {
+        let mut dirty : Vec<usize> = (0..self.blocks.len()).filter(|idx| self.blocks[*idx].dirty).collect();
+        dirty.sort_by_key(|idx| self.blocks[*idx].lba);
+        for idx in dirty {
+            BlockCache::write_back(device, &mut self.blocks[idx], &mut self.stats)?;
+        }
+        Ok(())
+    }
+
+    /// Copies any cached blocks within `lba..lba + buffer.len() / block_size`
+    /// over `buffer`, so data read straight from the device reflects unflushed writes.
+    pub fn overlay(&self, lba : u64, buffer : &mut [u8]) {
+        let block_size = match self.blocks.first() {
+            Some(block) => block.data.len(),
+            None => return,
+        };
+        let end = lba + (buffer.len() / block_size) as u64;
+        for block in self.blocks.iter().filter(|block| block.lba >= lba && block.lba < end) {
+            let start = (block.lba - lba) as usize * block_size;
+            buffer[start .. start + block_size].copy_from_slice(&block.data);
+        }
+    }
+
+    /// Drops cached copies of `count` blocks starting at `lba` without writing them back.
+    pub fn discard_range(&mut self, lba : u64, count : u64) {
+        self.blocks.retain(|block| block.lba < lba || block.lba >= lba + count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_dev::tests::RecordingDisk;
+
+    #[test]
+    fn eviction_writes_back_dirty_blocks() {
+        let mut disk = RecordingDisk::new(512, 16);
+        let mut cache = BlockCache::new(2);
+        cache.write_bytes(&mut disk, 3, 10, b"dirty").unwrap();
+        // Rewriting what's already there doesn't make a block dirty.
+        cache.write_bytes(&mut disk, 4, 0, &[0 ; 4]).unwrap();
+        assert!(disk.writes.is_empty());
+
+        cache.block(&mut disk, 5).unwrap();
+        assert!(!cache.contains(3));
+        assert_eq!(disk.writes, vec![(3, 1)]);
+        let mut block = vec![0 ; 512];
+        disk.disk.read_blocks(3, &mut block).unwrap();
+        assert_eq!(&block[10 .. 15], b"dirty");
+
+        cache.block(&mut disk, 6).unwrap();
+        assert_eq!(disk.writes, vec![(3, 1)]);
+        let stats = cache.stats();
+        assert_eq!((stats.evictions, stats.writebacks), (2, 1));
+    }
+
+    #[test]
+    fn flush_writes_in_lba_order() {
+        let mut disk = RecordingDisk::new(512, 16);
+        let mut cache = BlockCache::new(8);
+        for &lba in &[9, 2, 7, 4] {
+            cache.write_bytes(&mut disk, lba, 0, &[lba as u8 + 1]).unwrap();
+        }
+        cache.block(&mut disk, 5).unwrap();
+        cache.flush(&mut disk).unwrap();
+        assert_eq!(disk.writes, vec![(2, 1), (4, 1), (7, 1), (9, 1)]);
+        // Flushed blocks stay cached but are clean.
+        assert!(cache.contains(9));
+        cache.flush(&mut disk).unwrap();
+        assert_eq!(disk.writes.len(), 4);
+    }
+
+    #[test]
+    fn prefetch_never_evicts_the_requested_block() {
+        let mut disk = RecordingDisk::new(512, 64);
+        let mut cache = BlockCache::new(4);
+        cache.block_with_readahead(&mut disk, 10, 100).unwrap();
+        assert_eq!(disk.reads, vec![(10, 4)]);
+        assert!((10 .. 14).all(|lba| cache.contains(lba)));
+        assert_eq!(cache.stats().prefetched, 3);
+
+        // Near the end of the device there's less to prefetch.
+        cache.block_with_readahead(&mut disk, 62, 100).unwrap();
+        assert_eq!(disk.reads[1], (62, 2));
+        assert!(cache.contains(62) && cache.contains(63));
+    }
+}
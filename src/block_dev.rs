@@ -0,0 +1,367 @@
+use crate::*;
+use std::io;
+
+/// A fixed-size array of blocks addressed by LBA.
+/// Buffers passed to `read_blocks`/`write_blocks` must be a whole number of blocks long.
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()>;
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+
+    fn byte_len(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+impl <'b, D : BlockDevice + ?Sized> BlockDevice for &'b mut D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+        (**self).read_blocks(lba, buffer)
+    }
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+        (**self).write_blocks(lba, buffer)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+impl <D : BlockDevice + ?Sized> BlockDevice for Box<D> {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+        (**self).read_blocks(lba, buffer)
+    }
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+        (**self).write_blocks(lba, buffer)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+pub fn check_block_range<D : BlockDevice + ?Sized>(device : &D, lba : u64, len : usize) -> io::Result<u64> {
+    let block_size = device.block_size();
+    if len % block_size != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Buffer length {} is not a multiple of the block size {}.", len, block_size)));
+    }
+    let blocks = (len / block_size) as u64;
+    if lba.checked_add(blocks).map(|end| end > device.block_count()).unwrap_or(true) {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Blocks {}..{} are past the end of the device ({} blocks).", lba, lba.saturating_add(blocks), device.block_count())));
+    }
+    Ok(blocks)
+}
+
+pub struct FileBlockDevice {
+    file : File,
+    block_size : usize,
+    block_count : u64,
+}
+
+impl FileBlockDevice {
+    pub fn open<P : AsRef<Path>>(path : P, block_size : usize) -> io::Result<FileBlockDevice> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        FileBlockDevice::from_file(file, block_size)
+    }
+
+    pub fn from_file(file : File, block_size : usize) -> io::Result<FileBlockDevice> {
+        let len = file.metadata()?.len();
+        Ok(FileBlockDevice {
+            file,
+            block_size,
+            block_count : len / block_size as u64,
+        })
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        self.file.seek(SeekFrom::Start(lba * self.block_size as u64))?;
+        self.file.read_exact(buffer)
+    }
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        self.file.seek(SeekFrom::Start(lba * self.block_size as u64))?;
+        self.file.write_all(buffer)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Opens a disk image, recognising QCOW2 and VHD images by their contents and treating
+/// anything else as raw blocks of `raw_block_size` bytes.
+pub fn open_image<P : AsRef<Path>>(path : P, raw_block_size : usize) -> io::Result<Box<dyn BlockDevice>> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut magic = Vec::new();
+    (&mut file).take(4).read_to_end(&mut magic)?;
+    if qcow2::has_magic(&magic) {
+        return Ok(Box::new(qcow2::Qcow2Disk::from_file(file)?));
+    }
+    if vhd::read_footer(&mut file)?.is_some() {
+        return Ok(Box::new(vhd::VhdDisk::from_file(file)?));
+    }
+    Ok(Box::new(FileBlockDevice::from_file(file, raw_block_size)?))
+}
+
+/// A disk held in memory, for tests that need a backing device.
+#[cfg(test)]
+pub struct RamDisk {
+    data : Vec<u8>,
+    block_size : usize,
+}
+
+#[cfg(test)]
+impl RamDisk {
+    pub fn new(block_size : usize, block_count : u64) -> RamDisk {
+        RamDisk {
+            data : vec![0 ; block_size * block_count as usize],
+            block_size,
+        }
+    }
+
+    pub fn from_vec(mut data : Vec<u8>, block_size : usize) -> RamDisk {
+        let whole_blocks = data.len() - data.len() % block_size;
+        data.truncate(whole_blocks);
+        RamDisk {
+            data,
+            block_size,
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn block_count(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        let start = lba as usize * self.block_size;
+        buffer.copy_from_slice(&self.data[start .. start + buffer.len()]);
+        Ok(())
+    }
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        let start = lba as usize * self.block_size;
+        self.data[start .. start + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A run of `block_count` blocks starting at `start_lba` on another device,
+/// addressed from 0, e.g. a single partition.
+pub struct BlockRange<D : BlockDevice> {
+    device : D,
+    start_lba : u64,
+    block_count : u64,
+}
+
+impl <D : BlockDevice> BlockRange<D> {
+    pub fn new(device : D, start_lba : u64, block_count : u64) -> io::Result<BlockRange<D>> {
+        if start_lba.checked_add(block_count).map(|end| end > device.block_count()).unwrap_or(true) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Blocks {} + {} are past the end of the device ({} blocks).", start_lba, block_count, device.block_count())));
+        }
+        Ok(BlockRange { device, start_lba, block_count })
+    }
+
+    pub fn start_lba(&self) -> u64 {
+        self.start_lba
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl <D : BlockDevice> BlockDevice for BlockRange<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        self.device.read_blocks(self.start_lba + lba, buffer)
+    }
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        self.device.write_blocks(self.start_lba + lba, buffer)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A file in the temp directory, removed again when the test is done with it.
+    pub struct TempFile(pub PathBuf);
+
+    impl TempFile {
+        pub fn new(name : &str, len : u64) -> TempFile {
+            let path = std::env::temp_dir().join(format!("rust-usb-experiments-{}-{}", std::process::id(), name));
+            File::create(&path).unwrap().set_len(len).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// A `RamDisk` that logs every request made of it as (first block, block count).
+    pub struct RecordingDisk {
+        pub disk : RamDisk,
+        pub reads : Vec<(u64, usize)>,
+        pub writes : Vec<(u64, usize)>,
+    }
+
+    impl RecordingDisk {
+        pub fn new(block_size : usize, block_count : u64) -> RecordingDisk {
+            RecordingDisk { disk : RamDisk::new(block_size, block_count), reads : Vec::new(), writes : Vec::new() }
+        }
+    }
+
+    impl BlockDevice for RecordingDisk {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+        fn block_count(&self) -> u64 {
+            self.disk.block_count()
+        }
+        fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+            self.reads.push((lba, buffer.len() / self.disk.block_size()));
+            self.disk.read_blocks(lba, buffer)
+        }
+        fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+            self.writes.push((lba, buffer.len() / self.disk.block_size()));
+            self.disk.write_blocks(lba, buffer)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.disk.flush()
+        }
+    }
+
+    fn pattern(len : usize, seed : u8) -> Vec<u8> {
+        (0 .. len).map(|idx| (idx as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn check_block_range_bounds() {
+        let disk = RamDisk::new(512, 8);
+        assert_eq!(check_block_range(&disk, 0, 0).unwrap(), 0);
+        assert_eq!(check_block_range(&disk, 0, 512).unwrap(), 1);
+        assert_eq!(check_block_range(&disk, 0, 8 * 512).unwrap(), 8);
+        assert_eq!(check_block_range(&disk, 7, 512).unwrap(), 1);
+        assert_eq!(check_block_range(&disk, 8, 0).unwrap(), 0);
+
+        assert_eq!(check_block_range(&disk, 0, 100).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(check_block_range(&disk, 0, 513).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(check_block_range(&disk, 7, 2 * 512).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(check_block_range(&disk, 8, 512).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(check_block_range(&disk, 0, 9 * 512).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(check_block_range(&disk, u64::max_value(), 512).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn ram_disk_round_trip() {
+        let mut disk = RamDisk::new(512, 4);
+        let data = pattern(2 * 512, 1);
+        disk.write_blocks(1, &data).unwrap();
+        let mut back = vec![0 ; 2 * 512];
+        disk.read_blocks(1, &mut back).unwrap();
+        assert_eq!(back, data);
+        assert!(disk.write_blocks(3, &data).is_err());
+
+        let bytes = disk.into_inner();
+        assert!(bytes[.. 512].iter().all(|&b| b == 0));
+        assert_eq!(&bytes[512 .. 3 * 512], &data[..]);
+        assert!(bytes[3 * 512 ..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn file_block_device_round_trip() {
+        // The trailing partial block isn't addressable.
+        let temp = TempFile::new("file-block-device", 16 * 512 + 100);
+        let first = pattern(512, 3);
+        let last = pattern(3 * 512, 7);
+        {
+            let mut disk = FileBlockDevice::open(&temp.0, 512).unwrap();
+            assert_eq!(disk.block_count(), 16);
+            assert_eq!(disk.byte_len(), 16 * 512);
+            disk.write_blocks(0, &first).unwrap();
+            disk.write_blocks(13, &last).unwrap();
+            assert_eq!(disk.write_blocks(14, &last).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+            assert_eq!(disk.write_blocks(0, &last[.. 100]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            disk.flush().unwrap();
+        }
+
+        let mut disk = FileBlockDevice::open(&temp.0, 512).unwrap();
+        let mut back = vec![0 ; 512];
+        disk.read_blocks(0, &mut back).unwrap();
+        assert_eq!(back, first);
+        let mut back = vec![0 ; 3 * 512];
+        disk.read_blocks(13, &mut back).unwrap();
+        assert_eq!(back, last);
+        let mut back = vec![0xFF ; 512];
+        disk.read_blocks(5, &mut back).unwrap();
+        assert!(back.iter().all(|&b| b == 0));
+        assert!(disk.read_blocks(16, &mut back).is_err());
+
+        let mut raw = Vec::new();
+        File::open(&temp.0).unwrap().read_to_end(&mut raw).unwrap();
+        assert_eq!(&raw[.. 512], &first[..]);
+        assert_eq!(&raw[13 * 512 .. 16 * 512], &last[..]);
+    }
+
+    #[test]
+    fn block_range_offsets_and_bounds() {
+        let mut disk = RamDisk::new(512, 8);
+        assert!(BlockRange::new(&mut disk, 4, 5).is_err());
+        assert!(BlockRange::new(&mut disk, u64::max_value(), 2).is_err());
+        let data = pattern(512, 9);
+        {
+            let mut range = BlockRange::new(&mut disk, 2, 4).unwrap();
+            assert_eq!(range.block_count(), 4);
+            range.write_blocks(3, &data).unwrap();
+            assert!(range.write_blocks(4, &data).is_err());
+        }
+        let mut back = vec![0 ; 512];
+        disk.read_blocks(5, &mut back).unwrap();
+        assert_eq!(back, data);
+    }
+}
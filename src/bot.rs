@@ -0,0 +1,721 @@
+use crate::*;
+use buffer::{SliceBuffer, SliceReader, VecBuffer};
+use scsi::{Buffer, CommunicationChannel};
+use sense::SenseData;
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const CBW_SIGNATURE : u32 = 0x4342_5355;
+pub const CSW_SIGNATURE : u32 = 0x5342_5355;
+pub const CBW_LEN : usize = 31;
+pub const CSW_LEN : usize = 13;
+
+pub const CSW_STATUS_PASSED : u8 = 0;
+pub const CSW_STATUS_FAILED : u8 = 1;
+pub const CSW_STATUS_PHASE_ERROR : u8 = 2;
+
+/// How `execute_data` retries a command whose exchange went wrong. Every retry comes
+/// after reset recovery and with a fresh tag, so the device starts from a clean state;
+/// single transfers are never re-issued, since one that failed may have moved part of its data.
+/// NAKs get no special treatment: the host controller retries those by itself, so a device
+/// that keeps NAKing only shows up as a timeout and is recovered from like any other failure.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RetryPolicy {
+    pub retries : u32,
+    /// Wait before the first retry, doubled for each one after.
+    pub backoff : Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retries : 1,
+            backoff : Duration::from_millis(100),
+        }
+    }
+}
+
+static NEXT_TAG : AtomicUsize = AtomicUsize::new(1);
+
+pub fn next_tag() -> u32 {
+    NEXT_TAG.fetch_add(1, Ordering::SeqCst) as u32
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DataDirection {
+    None,
+    In,
+    Out,
+}
+
+/// Which part of a command a transfer belongs to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TransferStage {
+    Command,
+    Data,
+    Status,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Cbw {
+    pub tag : u32,
+    pub data_length : u32,
+    pub direction : DataDirection,
+    pub lun : u8,
+    pub cdb : Vec<u8>,
+}
+
+impl Cbw {
+    pub fn new(lun : u8, cdb : &[u8], direction : DataDirection, data_length : u32) -> Cbw {
+        Cbw {
+            tag : next_tag(),
+            data_length,
+            direction,
+            lun,
+            cdb : cdb.to_vec(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8 ; CBW_LEN] {
+        let mut bytes = [0 ; CBW_LEN];
+        bytes[0..4].copy_from_slice(&le_u32_bytes(CBW_SIGNATURE));
+        bytes[4..8].copy_from_slice(&le_u32_bytes(self.tag));
+        bytes[8..12].copy_from_slice(&le_u32_bytes(self.data_length));
+        bytes[12] = if self.direction == DataDirection::In { 0x80 } else { 0x00 };
+        bytes[13] = self.lun & 0x0F;
+        let cdb_len = self.cdb.len().min(16);
+        bytes[14] = cdb_len as u8;
+        bytes[15 .. 15 + cdb_len].copy_from_slice(&self.cdb[.. cdb_len]);
+        bytes
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Option<Cbw> {
+        if bytes.len() < CBW_LEN || le_u32(&bytes[0..4]) != CBW_SIGNATURE {
+            return None;
+        }
+        let cdb_len = bytes[14] as usize;
+        if cdb_len == 0 || cdb_len > 16 {
+            return None;
+        }
+        let data_length = le_u32(&bytes[8..12]);
+        let direction = if data_length == 0 {
+            DataDirection::None
+        } else if bytes[12] & 0x80 != 0 {
+            DataDirection::In
+        } else {
+            DataDirection::Out
+        };
+        Some(Cbw {
+            tag : le_u32(&bytes[4..8]),
+            data_length,
+            direction,
+            lun : bytes[13] & 0x0F,
+            cdb : bytes[15 .. 15 + cdb_len].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Csw {
+    pub tag : u32,
+    pub data_residue : u32,
+    pub status : u8,
+}
+
+impl Csw {
+    pub fn to_bytes(&self) -> [u8 ; CSW_LEN] {
+        let mut bytes = [0 ; CSW_LEN];
+        bytes[0..4].copy_from_slice(&le_u32_bytes(CSW_SIGNATURE));
+        bytes[4..8].copy_from_slice(&le_u32_bytes(self.tag));
+        bytes[8..12].copy_from_slice(&le_u32_bytes(self.data_residue));
+        bytes[12] = self.status;
+        bytes
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Option<Csw> {
+        if bytes.len() < CSW_LEN || le_u32(&bytes[0..4]) != CSW_SIGNATURE {
+            return None;
+        }
+        Some(Csw {
+            tag : le_u32(&bytes[4..8]),
+            data_residue : le_u32(&bytes[8..12]),
+            status : bytes[12],
+        })
+    }
+}
+
+pub fn le_u32(bytes : &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+pub fn le_u32_bytes(val : u32) -> [u8 ; 4] {
+    [val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8]
+}
+
+pub fn be_u32(bytes : &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | (bytes[3] as u32)
+}
+
+pub fn be_u32_bytes(val : u32) -> [u8 ; 4] {
+    [(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]
+}
+
+/// A `CommunicationChannel` carrying Bulk-Only Transport traffic.
+pub trait BotTransport : CommunicationChannel {
+    /// Takes the underlying cause of the last failed transfer, if the channel kept one.
+    fn take_transport_error(&mut self) -> Option<StorageError> {
+        None
+    }
+
+    /// Sends the Bulk-Only Mass Storage Reset class request.
+    fn bulk_only_reset(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Clears a halt on the bulk-in (`DataDirection::In`) or bulk-out endpoint.
+    fn clear_halt(&mut self, _direction : DataDirection) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// The highest LUN the device answers on, from the GET MAX LUN class request.
+    fn max_lun(&mut self) -> Result<u8, StorageError> {
+        Ok(0)
+    }
+
+    /// Called once before a command's first attempt, so a per-command deadline can start.
+    fn begin_command(&mut self) {}
+
+    /// When the current command has to be finished by, if `begin_command` set a deadline.
+    fn command_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// How failed commands on this channel are retried.
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Called before each transfer with the stage it belongs to.
+    fn set_stage(&mut self, _stage : TransferStage) {}
+
+    /// Receives one bulk-in transfer straight into `buffer`, returning how many bytes arrived.
+    /// The default goes through `in_transfer`; channels that can do better should.
+    fn read_into(&mut self, buffer : &mut [u8]) -> Result<usize, StorageError> {
+        let mut cursor = SliceBuffer::new(buffer);
+        match self.in_transfer(&mut cursor) {
+            Ok(_) => Ok(cursor.size()),
+            Err(e) => Err(self.take_transport_error().unwrap_or_else(|| StorageError::from(e))),
+        }
+    }
+
+    /// Sends `buffer` as one bulk-out transfer, returning how many bytes were sent.
+    fn write_from(&mut self, buffer : &[u8]) -> Result<usize, StorageError> {
+        let mut cursor = SliceReader::new(buffer);
+        match self.out_transfer(&mut cursor) {
+            Ok(sent) => Ok(sent),
+            Err(e) => Err(self.take_transport_error().unwrap_or_else(|| StorageError::from(e))),
+        }
+    }
+}
+
+/// A handle to one channel that several `ScsiDisk`s, one per LUN, can hold at once.
+pub struct SharedChannel<C : BotTransport>(Rc<RefCell<C>>);
+
+impl <C : BotTransport> SharedChannel<C> {
+    pub fn new(channel : C) -> SharedChannel<C> {
+        SharedChannel(Rc::new(RefCell::new(channel)))
+    }
+}
+
+impl <C : BotTransport> Clone for SharedChannel<C> {
+    fn clone(&self) -> SharedChannel<C> {
+        SharedChannel(self.0.clone())
+    }
+}
+
+impl <C : BotTransport> CommunicationChannel for SharedChannel<C> {
+    fn in_transfer<B : scsi::Buffer>(&mut self, buffer : &mut B) -> Result<usize, scsi::ScsiError> {
+        self.0.borrow_mut().in_transfer(buffer)
+    }
+
+    fn out_transfer<B : scsi::Buffer>(&mut self, bytes : &mut B) -> Result<usize, scsi::ScsiError> {
+        self.0.borrow_mut().out_transfer(bytes)
+    }
+}
+
+impl <C : BotTransport> BotTransport for SharedChannel<C> {
+    fn take_transport_error(&mut self) -> Option<StorageError> {
+        self.0.borrow_mut().take_transport_error()
+    }
+
+    fn bulk_only_reset(&mut self) -> Result<(), StorageError> {
+        self.0.borrow_mut().bulk_only_reset()
+    }
+
+    fn clear_halt(&mut self, direction : DataDirection) -> Result<(), StorageError> {
+        self.0.borrow_mut().clear_halt(direction)
+    }
+
+    fn max_lun(&mut self) -> Result<u8, StorageError> {
+        self.0.borrow_mut().max_lun()
+    }
+
+    fn begin_command(&mut self) {
+        self.0.borrow_mut().begin_command()
+    }
+
+    fn command_deadline(&self) -> Option<Instant> {
+        self.0.borrow().command_deadline()
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        self.0.borrow().retry_policy()
+    }
+
+    fn set_stage(&mut self, stage : TransferStage) {
+        self.0.borrow_mut().set_stage(stage)
+    }
+
+    fn read_into(&mut self, buffer : &mut [u8]) -> Result<usize, StorageError> {
+        self.0.borrow_mut().read_into(buffer)
+    }
+
+    fn write_from(&mut self, buffer : &[u8]) -> Result<usize, StorageError> {
+        self.0.borrow_mut().write_from(buffer)
+    }
+}
+
+fn is_stall(err : &StorageError) -> bool {
+    match err {
+        StorageError::Transport(libusb::Error::Pipe) => true,
+        _ => false,
+    }
+}
+
+/// The reset recovery sequence from section 5.3.4 of the BOT spec: mass storage
+/// reset, then CLEAR_FEATURE(ENDPOINT_HALT) on bulk-in and bulk-out.
+pub fn reset_recovery<C : BotTransport>(channel : &mut C) -> Result<(), StorageError> {
+    channel.bulk_only_reset()?;
+    channel.clear_halt(DataDirection::In)?;
+    channel.clear_halt(DataDirection::Out)
+}
+
+fn read_csw<C : BotTransport>(channel : &mut C) -> Result<([u8 ; CSW_LEN], usize), StorageError> {
+    channel.set_stage(TransferStage::Status);
+    let mut csw_buff = [0 ; CSW_LEN];
+    let red = channel.read_into(&mut csw_buff)?;
+    Ok((csw_buff, red))
+}
+
+// Checks that a CSW is valid (section 6.3.1) and meaningful (6.3.2) for the CBW it answers.
+fn check_csw(cbw : &Cbw, bytes : &[u8]) -> Result<Csw, StorageError> {
+    if bytes.len() != CSW_LEN {
+        return Err(StorageError::Protocol(format!("Got a {} byte CSW.", bytes.len())));
+    }
+    let csw = Csw::from_bytes(bytes).ok_or_else(|| StorageError::Protocol(format!("CSW signature was {:08x}.", le_u32(&bytes[0..4]))))?;
+    if csw.tag != cbw.tag {
+        return Err(StorageError::Protocol(format!("CSW tag mismatch: sent {} but got {}.", cbw.tag, csw.tag)));
+    }
+    if csw.status > CSW_STATUS_PHASE_ERROR {
+        return Err(StorageError::Protocol(format!("CSW has reserved status {}.", csw.status)));
+    }
+    if csw.status == CSW_STATUS_PHASE_ERROR {
+        return Err(StorageError::Protocol(format!("Device reported a phase error for command 0x{:02x}.", cbw.cdb[0])));
+    }
+    if csw.data_residue > cbw.data_length {
+        return Err(StorageError::Protocol(format!("CSW residue {} exceeds the {} bytes requested.", csw.data_residue, cbw.data_length)));
+    }
+    Ok(csw)
+}
+
+/// Where a command's data stage reads from or writes to.
+pub enum DataStage<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+fn execute_once<C : BotTransport>(channel : &mut C, cbw : &Cbw, data : &mut DataStage) -> Result<(Csw, usize), StorageError> {
+    channel.set_stage(TransferStage::Command);
+    channel.write_from(&cbw.to_bytes())?;
+
+    // A stalled data stage is not fatal: clear the halt and go on to read the CSW.
+    let transferred = match data {
+        DataStage::In(buffer) => {
+            channel.set_stage(TransferStage::Data);
+            let len = buffer.len().min(cbw.data_length as usize);
+            match channel.read_into(&mut buffer[.. len]) {
+                Ok(red) => red,
+                Err(ref e) if is_stall(e) => {
+                    channel.clear_halt(DataDirection::In)?;
+                    0
+                },
+                Err(e) => return Err(e),
+            }
+        },
+        DataStage::Out(buffer) => {
+            channel.set_stage(TransferStage::Data);
+            let len = buffer.len().min(cbw.data_length as usize);
+            match channel.write_from(&buffer[.. len]) {
+                Ok(sent) => sent,
+                Err(ref e) if is_stall(e) => {
+                    channel.clear_halt(DataDirection::Out)?;
+                    0
+                },
+                Err(e) => return Err(e),
+            }
+        },
+        DataStage::None => 0,
+    };
+
+    // A stall on the status stage gets one more try after clearing it.
+    let (csw_bytes, csw_len) = match read_csw(channel) {
+        Err(ref e) if is_stall(e) => {
+            channel.clear_halt(DataDirection::In)?;
+            read_csw(channel)?
+        },
+        other => other?,
+    };
+    let csw = check_csw(cbw, &csw_bytes[.. csw_len])?;
+    // The residue says how much of the data stage the device meant; anything past that is padding.
+    let intended = (cbw.data_length - csw.data_residue) as usize;
+    if transferred > intended {
+        eprintln!("BOT: Command 0x{:02x} moved {} bytes but the residue only accounts for {}.", cbw.cdb[0], transferred, intended);
+    }
+    Ok((csw, transferred.min(intended)))
+}
+
+/// Runs a single command through the channel: CBW, optional data stage, CSW,
+/// returning the CSW and how many data bytes were actually moved, going by
+/// both the transfer itself and the CSW data residue.
+/// Data is received into or sent from the caller's buffer without copying.
+/// If the exchange goes wrong the channel is put through reset recovery and
+/// the command is retried with a fresh tag, as the channel's `RetryPolicy` allows.
+/// Once the channel's command deadline has passed, no more recovery or retries are
+/// attempted and the command fails with `io::ErrorKind::TimedOut`.
+pub fn execute_data<C : BotTransport>(channel : &mut C, cbw : &Cbw, mut data : DataStage) -> Result<(Csw, usize), StorageError> {
+    let mut cbw = cbw.clone();
+    channel.begin_command();
+    let policy = channel.retry_policy();
+    let mut backoff = policy.backoff;
+    let mut retries = 0;
+    loop {
+        if deadline_within(channel, Duration::from_secs(0)) {
+            return Err(deadline_passed(&cbw, retries, None));
+        }
+        let err = match execute_once(channel, &cbw, &mut data) {
+            Ok(res) => return Ok(res),
+            Err(e) => e,
+        };
+        if err.is_disconnect() {
+            return Err(err);
+        }
+        if deadline_within(channel, Duration::from_secs(0)) {
+            return Err(deadline_passed(&cbw, retries + 1, Some(&err)));
+        }
+        eprintln!("BOT: Command 0x{:02x} failed on attempt {} of {} ({}). Running reset recovery.", cbw.cdb[0], retries + 1, policy.retries + 1, err);
+        if let Err(reset_err) = reset_recovery(channel) {
+            eprintln!("BOT: Reset recovery after command 0x{:02x} failed too ({}).", cbw.cdb[0], reset_err);
+            return Err(if reset_err.is_disconnect() { reset_err } else { err });
+        }
+        if retries >= policy.retries {
+            return Err(err);
+        }
+        // No point waiting out a backoff that ends past the deadline.
+        if deadline_within(channel, backoff) {
+            return Err(deadline_passed(&cbw, retries + 1, Some(&err)));
+        }
+        retries += 1;
+        std::thread::sleep(backoff);
+        backoff *= 2;
+        cbw.tag = next_tag();
+    }
+}
+
+fn deadline_within<C : BotTransport>(channel : &C, wait : Duration) -> bool {
+    channel.command_deadline().map(|deadline| Instant::now() + wait >= deadline).unwrap_or(false)
+}
+
+fn deadline_passed(cbw : &Cbw, attempts : u32, last : Option<&StorageError>) -> StorageError {
+    let mut message = format!("Command 0x{:02x} ran out of time after {} attempt(s)", cbw.cdb[0], attempts);
+    if let Some(err) = last {
+        message += &format!("; the last one failed with: {}", err);
+    }
+    io::Error::new(io::ErrorKind::TimedOut, message).into()
+}
+
+/// Runs a data-in command into the free space of `buffer`, of which at most `cbw.data_length`
+/// bytes are used. Only what the device meant to send, going by the residue, adds to its size.
+pub fn execute_in<C : BotTransport>(channel : &mut C, cbw : &Cbw, buffer : &mut VecBuffer) -> Result<Csw, StorageError> {
+    let mut status = None;
+    buffer.fill_with(|space| -> Result<usize, StorageError> {
+        let len = space.len().min(cbw.data_length as usize);
+        let (csw, red) = execute_data(channel, cbw, DataStage::In(&mut space[.. len]))?;
+        status = Some(csw);
+        Ok(red)
+    })?;
+    Ok(status.unwrap())
+}
+
+/// `execute_data` for small commands. For `DataDirection::In` the received bytes
+/// replace the contents of `data`; for `DataDirection::Out` the contents of `data` are sent.
+pub fn execute<C : BotTransport>(channel : &mut C, cbw : &Cbw, data : &mut Vec<u8>) -> Result<Csw, StorageError> {
+    match cbw.direction {
+        DataDirection::In => {
+            let mut buffer = VecBuffer::with_capacity(cbw.data_length as usize);
+            let csw = execute_in(channel, cbw, &mut buffer)?;
+            *data = buffer.into_vec();
+            Ok(csw)
+        },
+        DataDirection::Out => execute_data(channel, cbw, DataStage::Out(data)).map(|(csw, _)| csw),
+        DataDirection::None => execute_data(channel, cbw, DataStage::None).map(|(csw, _)| csw),
+    }
+}
+
+pub fn request_sense_raw<C : BotTransport>(channel : &mut C, lun : u8) -> Result<Vec<u8>, StorageError> {
+    let cbw = Cbw::new(lun, &[0x03, 0, 0, 0, 18, 0], DataDirection::In, 18);
+    let mut data = Vec::new();
+    execute(channel, &cbw, &mut data)?;
+    Ok(data)
+}
+
+/// Issues REQUEST SENSE and decodes the reply, returning `None` if the
+/// device answered with something other than fixed or descriptor sense.
+pub fn request_sense<C : BotTransport>(channel : &mut C, lun : u8) -> Result<Option<SenseData>, StorageError> {
+    let data = request_sense_raw(channel, lun)?;
+    Ok(SenseData::from_bytes(&data))
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InquiryData {
+    pub peripheral_type : u8,
+    pub removable : bool,
+    pub vendor : String,
+    pub product : String,
+    pub revision : String,
+}
+
+impl InquiryData {
+    pub fn from_bytes(bytes : &[u8]) -> Option<InquiryData> {
+        if bytes.len() < 36 {
+            return None;
+        }
+        let text = |range : &[u8]| String::from_utf8_lossy(range).trim().to_owned();
+        Some(InquiryData {
+            peripheral_type : bytes[0] & 0x1F,
+            removable : bytes[1] & 0x80 != 0,
+            vendor : text(&bytes[8..16]),
+            product : text(&bytes[16..32]),
+            revision : text(&bytes[32..36]),
+        })
+    }
+}
+
+pub fn inquiry<C : BotTransport>(channel : &mut C, lun : u8) -> Result<InquiryData, StorageError> {
+    let cbw = Cbw::new(lun, &[0x12, 0, 0, 0, 36, 0], DataDirection::In, 36);
+    let mut data = Vec::new();
+    let csw = execute(channel, &cbw, &mut data)?;
+    if csw.status != CSW_STATUS_PASSED {
+        let sense = request_sense(channel, lun)?;
+        return Err(StorageError::CommandFailed { opcode : 0x12, status : csw.status, sense });
+    }
+    InquiryData::from_bytes(&data).ok_or_else(|| StorageError::Protocol(format!("INQUIRY returned only {} bytes.", data.len())))
+}
+
+/// Issues READ CAPACITY(10), returning `(block_count, block_size)`.
+/// A failed attempt is followed by REQUEST SENSE to clear any pending unit attention.
+pub fn read_capacity<C : BotTransport>(channel : &mut C, lun : u8) -> Result<(u64, u32), StorageError> {
+    let mut status = 0;
+    let mut sense = None;
+    for _ in 0..3 {
+        let cbw = Cbw::new(lun, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataDirection::In, 8);
+        let mut data = Vec::new();
+        let csw = execute(channel, &cbw, &mut data)?;
+        if csw.status == 0 && data.len() >= 8 {
+            let last_lba = be_u32(&data[0..4]);
+            let block_size = be_u32(&data[4..8]);
+            return Ok((last_lba as u64 + 1, block_size));
+        }
+        eprintln!("BOT: READ CAPACITY failed with status {}. Requesting sense and retrying.", csw.status);
+        status = csw.status;
+        sense = request_sense(channel, lun)?;
+        if let Some(sense) = sense {
+            eprintln!("BOT: Sense: {}", sense);
+        }
+    }
+    Err(StorageError::CommandFailed { opcode : 0x25, status, sense })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An emulated target whose first `failures` CBWs fail with `error`, with a settable
+    // retry policy and per-command time limit.
+    struct Flaky {
+        target : EmulatedTarget<RamDisk>,
+        failures : usize,
+        error : libusb::Error,
+        reset_fails : bool,
+        policy : RetryPolicy,
+        limit : Option<Duration>,
+        deadline : Option<Instant>,
+        stage : TransferStage,
+        attempts : usize,
+        resets : usize,
+    }
+
+    impl Flaky {
+        fn new(failures : usize, retries : u32) -> Flaky {
+            Flaky {
+                target : EmulatedTarget::new(RamDisk::new(512, 16)),
+                failures,
+                error : libusb::Error::Timeout,
+                reset_fails : false,
+                policy : RetryPolicy { retries, backoff : Duration::from_millis(1) },
+                limit : None,
+                deadline : None,
+                stage : TransferStage::Command,
+                attempts : 0,
+                resets : 0,
+            }
+        }
+    }
+
+    impl CommunicationChannel for Flaky {
+        fn in_transfer<B : scsi::Buffer>(&mut self, buffer : &mut B) -> Result<usize, scsi::ScsiError> {
+            self.target.in_transfer(buffer)
+        }
+        fn out_transfer<B : scsi::Buffer>(&mut self, bytes : &mut B) -> Result<usize, scsi::ScsiError> {
+            self.target.out_transfer(bytes)
+        }
+    }
+
+    impl BotTransport for Flaky {
+        fn bulk_only_reset(&mut self) -> Result<(), StorageError> {
+            self.resets += 1;
+            if self.reset_fails {
+                return Err(StorageError::Transport(libusb::Error::Pipe));
+            }
+            self.target.bulk_only_reset()
+        }
+        fn begin_command(&mut self) {
+            self.deadline = self.limit.map(|limit| Instant::now() + limit);
+        }
+        fn command_deadline(&self) -> Option<Instant> {
+            self.deadline
+        }
+        fn retry_policy(&self) -> RetryPolicy {
+            self.policy
+        }
+        fn set_stage(&mut self, stage : TransferStage) {
+            self.stage = stage;
+        }
+        fn read_into(&mut self, buffer : &mut [u8]) -> Result<usize, StorageError> {
+            self.target.read_into(buffer)
+        }
+        fn write_from(&mut self, buffer : &[u8]) -> Result<usize, StorageError> {
+            if self.stage == TransferStage::Command {
+                self.attempts += 1;
+                if self.attempts <= self.failures {
+                    return Err(StorageError::Transport(self.error));
+                }
+            }
+            self.target.write_from(buffer)
+        }
+    }
+
+    fn is_deadline_error(err : &StorageError) -> bool {
+        match err {
+            StorageError::Io(e) => e.kind() == io::ErrorKind::TimedOut,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn retries_then_succeeds() {
+        let mut channel = Flaky::new(2, 2);
+        let inquiry = inquiry(&mut channel, 0).unwrap();
+        assert_eq!(inquiry.vendor, "RUSTEMU");
+        assert_eq!((channel.attempts, channel.resets), (3, 2));
+    }
+
+    #[test]
+    fn gives_up_once_retries_are_used() {
+        let mut channel = Flaky::new(10, 2);
+        match inquiry(&mut channel, 0) {
+            Err(StorageError::Transport(libusb::Error::Timeout)) => {},
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert_eq!((channel.attempts, channel.resets), (3, 3));
+        // The channel was left in step for the next command.
+        channel.failures = 0;
+        assert!(inquiry(&mut channel, 0).is_ok());
+    }
+
+    #[test]
+    fn disconnect_is_not_retried() {
+        let mut channel = Flaky::new(1, 2);
+        channel.error = libusb::Error::NoDevice;
+        assert!(inquiry(&mut channel, 0).unwrap_err().is_disconnect());
+        assert_eq!((channel.attempts, channel.resets), (1, 0));
+    }
+
+    #[test]
+    fn failed_recovery_keeps_command_error() {
+        let mut channel = Flaky::new(1, 2);
+        channel.reset_fails = true;
+        match inquiry(&mut channel, 0) {
+            Err(StorageError::Transport(libusb::Error::Timeout)) => {},
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert_eq!((channel.attempts, channel.resets), (1, 1));
+    }
+
+    #[test]
+    fn stops_at_deadline() {
+        let mut channel = Flaky::new(10, 5);
+        channel.limit = Some(Duration::from_secs(0));
+        assert!(is_deadline_error(&inquiry(&mut channel, 0).unwrap_err()));
+        assert_eq!((channel.attempts, channel.resets), (0, 0));
+
+        // A backoff that would end past the deadline isn't waited out.
+        let mut channel = Flaky::new(10, 5);
+        channel.policy.backoff = Duration::from_secs(5);
+        channel.limit = Some(Duration::from_secs(1));
+        let start = Instant::now();
+        assert!(is_deadline_error(&inquiry(&mut channel, 0).unwrap_err()));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!((channel.attempts, channel.resets), (1, 1));
+    }
+
+    fn rejected(cbw : &Cbw, bytes : &[u8]) -> String {
+        match check_csw(cbw, bytes) {
+            Err(StorageError::Protocol(msg)) => msg,
+            other => panic!("expected a protocol error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn check_csw_rejects_malformed_status() {
+        let cbw = Cbw::new(0, &[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0], DataDirection::In, 512);
+        let csw = |tag, data_residue, status| Csw { tag, data_residue, status }.to_bytes();
+        assert_eq!(check_csw(&cbw, &csw(cbw.tag, 512, CSW_STATUS_FAILED)).unwrap().data_residue, 512);
+
+        let mut long = csw(cbw.tag, 0, CSW_STATUS_PASSED).to_vec();
+        long.push(0);
+        assert_eq!(rejected(&cbw, &long), "Got a 14 byte CSW.");
+        assert_eq!(rejected(&cbw, &long[.. 12]), "Got a 12 byte CSW.");
+
+        let mut bad_signature = csw(cbw.tag, 0, CSW_STATUS_PASSED);
+        bad_signature[3] = 0x42;
+        assert_eq!(rejected(&cbw, &bad_signature), "CSW signature was 42425355.");
+
+        assert_eq!(rejected(&cbw, &csw(cbw.tag + 1, 0, CSW_STATUS_PASSED)), format!("CSW tag mismatch: sent {} but got {}.", cbw.tag, cbw.tag + 1));
+        assert_eq!(rejected(&cbw, &csw(cbw.tag, 0, 3)), "CSW has reserved status 3.");
+        assert_eq!(rejected(&cbw, &csw(cbw.tag, 0, CSW_STATUS_PHASE_ERROR)), "Device reported a phase error for command 0x28.");
+        assert_eq!(rejected(&cbw, &csw(cbw.tag, 513, CSW_STATUS_PASSED)), "CSW residue 513 exceeds the 512 bytes requested.");
+    }
+}
@@ -1,174 +1,400 @@
 use crate::*;
 
-pub struct OffsetScsiDevice<'a> {
-
-    device : scsi::scsi::ScsiBlockDevice<UsbClient<'a>, VecNewtype, VecNewtype, VecNewtype>,
-    block_buffer : VecNewtype,
-    partition_start : usize, //bytes 
-    partition_idx : usize, //bytes from partition_start
-    loaded_block_number : usize, 
-    needs_flush : bool, 
+pub const DEFAULT_CACHE_BLOCKS : usize = 32;
+pub const DEFAULT_READAHEAD_MIN : usize = 4;
+pub const DEFAULT_READAHEAD_MAX : usize = 16;
+
+// Tracks whether fill_buf is walking blocks in order and how far ahead to prefetch.
+// The window doubles on every sequential miss and falls back to `min` otherwise.
+struct ReadAhead {
+    last_block : Option<u64>,
+    window : usize,
+    min : usize,
+    max : usize,
+}
+
+impl ReadAhead {
+    fn new(min : usize, max : usize) -> ReadAhead {
+        ReadAhead {
+            last_block : None,
+            window : 0,
+            min : min.min(max),
+            max,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_block = None;
+        self.window = 0;
+    }
+
+    // Seeking within the last block or onto the next one keeps a sequential streak alive.
+    fn continues(&self, block : u64) -> bool {
+        self.last_block.map(|last| block == last || block == last + 1).unwrap_or(false)
+    }
+
+    // Returns how many blocks past `block` to prefetch if it misses the cache.
+    fn on_miss(&mut self, block : u64) -> usize {
+        let sequential = self.last_block.map(|last| last + 1 == block).unwrap_or(false);
+        self.window = if !sequential || self.max == 0 {
+            0
+        } else if self.window == 0 {
+            self.min.max(1)
+        } else {
+            (self.window * 2).min(self.max)
+        };
+        self.window
+    }
+}
+
+pub struct OffsetScsiDevice<D : BlockDevice> {
+
+    device : D,
+    cache : BlockCache,
+    readahead : ReadAhead,
+    partition_start : u64, //bytes 
+    partition_idx : u64, //bytes from partition_start
+    partition_len : u64, //bytes
+    closed : bool,
+    drop_error_handler : Option<Box<dyn FnMut(io::Error)>>,
 }
 
 use std::io;
-use scsi::Buffer;
 
-impl <'a> Drop for OffsetScsiDevice<'a>{
+impl <D : BlockDevice> Drop for OffsetScsiDevice<D>{
     fn drop(&mut self) {
-        self.flush().unwrap();
+        if self.closed {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            match self.drop_error_handler.as_mut() {
+                Some(handler) => handler(e),
+                None => eprintln!("OffsetScsiDevice: Flush on drop failed: {:?}", e),
+            }
+        }
     }
 }
 
-impl <'a> OffsetScsiDevice<'a> {
-    pub fn new(device : scsi::scsi::ScsiBlockDevice<UsbClient<'a>, VecNewtype, VecNewtype, VecNewtype>, partition_start : usize) -> Self {
-        let block_size = device.block_size() as usize;
+fn offset_position(base : u64, off : i64) -> io::Result<u64> {
+    let pos = if off >= 0 {
+        base.checked_add(off as u64)
+    } else {
+        base.checked_sub(off.wrapping_neg() as u64)
+    };
+    pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid seek from {} by {}: position would be negative or overflow.", base, off)))
+}
 
+impl <D : BlockDevice> OffsetScsiDevice<D> {
+    /// Opens everything from `partition_start` to the end of the device.
+    pub fn new(device : D, partition_start : u64) -> Self {
+        let partition_len = device.byte_len().saturating_sub(partition_start);
+        OffsetScsiDevice::with_length(device, partition_start, partition_len)
+    }
+
+    /// Opens `partition_len` bytes starting at `partition_start`, clamped to the end of the device.
+    pub fn with_length(device : D, partition_start : u64, partition_len : u64) -> Self {
+        let partition_len = partition_len.min(device.byte_len().saturating_sub(partition_start));
         OffsetScsiDevice {
             device, 
-            block_buffer : VecNewtype::with_fake_capacity(block_size),
+            cache : BlockCache::new(DEFAULT_CACHE_BLOCKS),
+            readahead : ReadAhead::new(DEFAULT_READAHEAD_MIN, DEFAULT_READAHEAD_MAX),
             partition_start,
             partition_idx : 0,
-            loaded_block_number : 0,
-            needs_flush : false,
+            partition_len,
+            closed : false,
+            drop_error_handler : None,
         }
     }
 
-    #[inline]
-    fn raw_idx(&self) -> usize {
-        self.partition_start + self.partition_idx
+    /// Flushes everything and reports the result, instead of leaving it to `Drop`.
+    pub fn close(mut self) -> io::Result<()> {
+        self.closed = true;
+        self.flush()
+    }
+
+    /// Drops the device without flushing, losing any unwritten data.
+    /// For when the device has been unplugged and a flush can only fail.
+    pub fn discard(mut self) {
+        self.closed = true;
+    }
+
+    /// Called with the error if the implicit flush in `Drop` fails.
+    /// Without a handler the error is printed to stderr.
+    pub fn set_drop_error_handler<F : FnMut(io::Error) + 'static>(&mut self, handler : F) {
+        self.drop_error_handler = Some(Box::new(handler));
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn partition_len(&self) -> u64 {
+        self.partition_len
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn reset_cache_stats(&mut self) {
+        self.cache.reset_stats();
+    }
+
+    pub fn set_cache_size(&mut self, cache_blocks : usize) -> io::Result<()> {
+        self.cache.set_capacity(&mut self.device, cache_blocks)
+    }
+
+    /// Sets the read-ahead window bounds in blocks; `max` of 0 disables read-ahead.
+    /// The window is also capped by the cache size.
+    pub fn set_readahead(&mut self, min : usize, max : usize) {
+        self.readahead = ReadAhead::new(min, max);
+    }
+
+    pub fn readahead_window(&self) -> usize {
+        self.readahead.window
     }
 
     #[inline]
-    fn buffered_block_raw_idx(&self) -> usize {
-        self.device.block_size() as usize * self.loaded_block_number
+    fn raw_idx(&self) -> u64 {
+        self.partition_start + self.partition_idx
     }
 
     #[inline]
-    fn cur_block_raw_idx(&self) -> usize {
-        let rel_offset = self.raw_idx() % self.device.block_size() as usize;
-        let block_start = self.raw_idx() - rel_offset;
-        assert_eq!(block_start % self.device.block_size() as usize, 0);
-        block_start as usize
-    } 
+    fn remaining(&self) -> u64 {
+        self.partition_len.saturating_sub(self.partition_idx)
+    }
 
     #[inline]
-    fn cur_block_number(&self) -> usize {
-        self.cur_block_raw_idx()/ self.device.block_size() as usize
+    fn cur_block_number(&self) -> u64 {
+        self.raw_idx() / self.device.block_size() as u64
     }
 
     #[inline]
     fn offset_from_cur_block(&self) -> usize {
-        self.raw_idx() - self.cur_block_raw_idx()
+        (self.raw_idx() % self.device.block_size() as u64) as usize
     }
 }
 
-impl <'a> BufRead for OffsetScsiDevice<'a> {
+impl <D : BlockDevice> BufRead for OffsetScsiDevice<D> {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        if self.cur_block_number() != self.loaded_block_number {
-            println!("std::BufRead: Got block number mismatch: have {} but want {} ({} + {}). Flushing & resetting.", self.loaded_block_number, self.cur_block_number(), self.partition_start, self.partition_idx);
-            self.flush()?;
-            self.block_buffer.clear().unwrap();
+        let remaining = self.remaining();
+        if remaining == 0 {
+            return Ok(&[]);
         }
-        let block_idx = self.cur_block_raw_idx() as u32;
-        if self.block_buffer.is_empty() {
-            println!("std::BufRead: Buffer is empty. Loading block {} (raw: {}).", self.cur_block_number(), block_idx);
-            let red = self.device.read(block_idx, &mut self.block_buffer)
-                .map_err(|e| match e.cause {
-                    scsi::ErrorCause::BufferTooSmallError {expected, actual} => {
-                        io::Error::new(io::ErrorKind::UnexpectedEof, format!("Buffer too small: wanted {} but only have {}.", expected, actual))
-                    },
-                    e => io::Error::new(io::ErrorKind::Other, format!("Unmatched error : {:?}", e)),
-                }).unwrap();
-            assert_eq!(red, self.device.block_size() as usize);
-            self.loaded_block_number = self.cur_block_number();
-            println!("std::BufRead: Loaded block {}.", self.loaded_block_number);
-        }
-        Ok(&self.block_buffer.inner.as_slice()[self.offset_from_cur_block() .. ])
+        let block_number = self.cur_block_number();
+        let offset = self.offset_from_cur_block();
+        let readahead = if self.cache.contains(block_number) {
+            0
+        } else {
+            self.readahead.on_miss(block_number)
+        };
+        self.readahead.last_block = Some(block_number);
+        let block = self.cache.block_with_readahead(&mut self.device, block_number, readahead)?;
+        let end = (offset as u64 + remaining).min(block.len() as u64) as usize;
+        Ok(&block[offset .. end])
     }
 
     fn consume(&mut self, amt: usize) {
-        self.partition_idx += amt;
+        self.partition_idx += amt as u64;
     }
 }
 
-impl <'a> Read for OffsetScsiDevice<'a> {
+impl <D : BlockDevice> Read for OffsetScsiDevice<D> {
     fn read(&mut self, output_buf : &mut [u8]) -> io::Result<usize> {
-        let needed_bytes = output_buf.len();
-        println!("std::Read: Requested {} bytes.", needed_bytes);
+        let needed_bytes = (output_buf.len() as u64).min(self.remaining()) as usize;
 
+        let block_size = self.device.block_size();
         let mut output_idx = 0;
         while output_idx < needed_bytes {
-            let byte = {
+            let whole_blocks = (needed_bytes - output_idx) / block_size;
+            if self.offset_from_cur_block() == 0 && whole_blocks > 0 {
+                let block_number = self.cur_block_number();
+                let span = &mut output_buf[output_idx .. output_idx + whole_blocks * block_size];
+                self.device.read_blocks(block_number, span)?;
+                self.cache.overlay(block_number, span);
+                self.readahead.last_block = Some(block_number + whole_blocks as u64 - 1);
+                output_idx += span.len();
+                self.consume(span.len());
+                continue;
+            }
+            let copied = {
                 let buff = self.fill_buf()?;
                 if buff.is_empty() {
                     break;
                 }
-                buff[0]
+                let copied = buff.len().min(needed_bytes - output_idx);
+                output_buf[output_idx .. output_idx + copied].copy_from_slice(&buff[.. copied]);
+                copied
             };
-            output_buf[output_idx] = byte;
-            output_idx += 1;
-            self.consume(1);
+            output_idx += copied;
+            self.consume(copied);
         }
-        println!("std::Read: Finished reading {} bytes.", output_idx);
-        println!("std::Read: {:?}", output_buf);
-        println!("std::Read: self.partition_idx = {}, self.loaded_block_number = {}", self.partition_idx, self.loaded_block_number);
         return Ok(output_idx);
     }
 }
 
-impl <'a> Write for OffsetScsiDevice<'a> {
+impl <D : BlockDevice> Write for OffsetScsiDevice<D> {
     fn write(&mut self, to_write : &[u8]) -> io::Result<usize> {
-        println!("std::Write: Writing {} bytes starting at {}.", to_write.len(), self.raw_idx());
+        self.readahead.reset();
+        let to_write = &to_write[.. (to_write.len() as u64).min(self.remaining()) as usize];
+        let block_size = self.device.block_size();
         let mut written_idx = 0;
         while written_idx < to_write.len() {
-            self.fill_buf()?;
-            if self.block_buffer.is_empty() {
-                break;
-            }
-
+            let block_number = self.cur_block_number();
             let block_offset = self.offset_from_cur_block();
-            if self.block_buffer.inner[block_offset] != to_write[written_idx] {
-                self.block_buffer.inner[block_offset] = to_write[written_idx];
-                self.needs_flush = true;
+            let whole_blocks = (to_write.len() - written_idx) / block_size;
+            if block_offset == 0 && whole_blocks > 0 {
+                let span = &to_write[written_idx .. written_idx + whole_blocks * block_size];
+                self.device.write_blocks(block_number, span)?;
+                self.cache.discard_range(block_number, whole_blocks as u64);
+                written_idx += span.len();
+                self.consume(span.len());
+                continue;
             }
-            written_idx += 1;
-            self.consume(1);
+            let count = (block_size - block_offset).min(to_write.len() - written_idx);
+            self.cache.write_bytes(&mut self.device, block_number, block_offset, &to_write[written_idx .. written_idx + count])?;
+            written_idx += count;
+            self.consume(count);
         }
         return Ok(written_idx);
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        println!("std::Flush: Entered flush.");
-        if !self.needs_flush {
-            println!("std::Flush: Not doing flush.");
-            return Ok(());
-        }
-        let raw_idx = self.buffered_block_raw_idx();
-        println!("std::Flush: Doing flush.");
-        println!("std::Flush: Raw writing block: {}, offset: {}.", self.loaded_block_number, raw_idx);
-        let _ = self.device.write(raw_idx as u32, &mut self.block_buffer).unwrap();
-        self.needs_flush = false;
-        Ok(())
+        self.cache.flush(&mut self.device)?;
+        self.device.flush()
     }
 }
-impl <'a> Seek for OffsetScsiDevice<'a> {
+impl <D : BlockDevice> Seek for OffsetScsiDevice<D> {
     fn seek(&mut self, pos : SeekFrom) -> io::Result<u64> {
-        match pos {
-            SeekFrom::Start(absr) => {
-                self.partition_idx = absr as usize;
-                println!("std::Seek: Seek via abs to raw {} ({} + {}) in block {}.", self.raw_idx(), self.partition_start, self.partition_idx, self.cur_block_number());
-                Ok(absr)
-            },
-            SeekFrom::Current(off) => {
-                let absr = if off < 0 {
-                    self.partition_idx - off.abs() as usize
-                } else { self.partition_idx + off.abs() as usize};
-
-                self.partition_idx = absr;
-                println!("std::Seek: Seek via abs to raw {} ({} + {}) in block {}.", self.raw_idx(), self.partition_start, self.partition_idx, self.cur_block_number());
-                Ok(absr as u64)
-            },
-            _ => unimplemented!()
+        let absr = match pos {
+            SeekFrom::Start(absr) => absr,
+            SeekFrom::Current(off) => offset_position(self.partition_idx, off)?,
+            SeekFrom::End(off) => offset_position(self.partition_len, off)?,
+        };
+        self.partition_idx = absr;
+        let new_block = self.cur_block_number();
+        if !self.readahead.continues(new_block) {
+            self.readahead.reset();
+        }
+        Ok(absr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_dev::tests::RecordingDisk;
+
+    const FILLER : u8 = 0xAA;
+
+    fn pattern(len : usize, seed : u8) -> Vec<u8> {
+        (0 .. len).map(|idx| (idx as u8).wrapping_mul(31).wrapping_add(seed) ^ (idx >> 8) as u8).collect()
+    }
+
+    fn untouched_outside(bytes : &[u8], start : usize, len : usize) -> bool {
+        bytes[.. start].iter().chain(bytes[start + len ..].iter()).all(|&b| b == FILLER)
+    }
+
+    #[test]
+    fn unaligned_io_stays_in_partition() {
+        let mut disk = RamDisk::from_vec(vec![FILLER ; 16 * 512], 512);
+        let start = 3 * 512 + 100;
+        let len = 4 * 512;
+        let data = pattern(1000, 5);
+        {
+            let mut dev = OffsetScsiDevice::with_length(&mut disk, start as u64, len as u64);
+            assert_eq!(dev.partition_len(), len as u64);
+            dev.seek(SeekFrom::Start(200)).unwrap();
+            dev.write_all(&data).unwrap();
+
+            let mut back = vec![0 ; data.len()];
+            dev.seek(SeekFrom::Current(-(data.len() as i64))).unwrap();
+            dev.read_exact(&mut back).unwrap();
+            assert_eq!(back, data);
+
+            // Writes and reads stop at the end of the partition.
+            assert_eq!(dev.seek(SeekFrom::End(-10)).unwrap(), len as u64 - 10);
+            assert_eq!(dev.write(&[0x55 ; 20]).unwrap(), 10);
+            assert_eq!(dev.read(&mut [0 ; 20]).unwrap(), 0);
+            assert!(dev.seek(SeekFrom::Current(-(len as i64) - 1)).is_err());
+            dev.close().unwrap();
+        }
+        let bytes = disk.into_inner();
+        assert_eq!(&bytes[start + 200 .. start + 200 + data.len()], &data[..]);
+        assert!(bytes[start + len - 10 .. start + len].iter().all(|&b| b == 0x55));
+        assert!(untouched_outside(&bytes, start, len));
+    }
+
+    #[test]
+    fn fatfs_round_trip() {
+        let mut disk = RamDisk::from_vec(vec![FILLER ; 8192 * 512], 512);
+        let start = 1024 * 1024;
+        let len = 2 * 1024 * 1024;
+        let contents = pattern(100 * 1024 + 17, 11);
+        {
+            let mut dev = OffsetScsiDevice::with_length(&mut disk, start as u64, len as u64);
+            // A small cache so the filesystem pushes blocks through eviction.
+            dev.set_cache_size(4).unwrap();
+            fatfs::format_volume(&mut dev, fatfs::FormatVolumeOptions::new()).unwrap();
+            dev.seek(SeekFrom::Start(0)).unwrap();
+            {
+                let fs = fatfs::FileSystem::new(&mut dev, fatfs::FsOptions::new()).unwrap();
+                let dir = fs.root_dir().create_dir("DATA").unwrap();
+                let mut file = dir.create_file("PATTERN.BIN").unwrap();
+                file.write_all(&contents).unwrap();
+                file.flush().unwrap();
+                drop(file);
+                drop(dir);
+                fs.unmount().unwrap();
+            }
+            dev.close().unwrap();
+        }
+
+        {
+            let mut dev = OffsetScsiDevice::with_length(&mut disk, start as u64, len as u64);
+            let fs = fatfs::FileSystem::new(&mut dev, fatfs::FsOptions::new()).unwrap();
+            let mut file = fs.root_dir().open_file("DATA/PATTERN.BIN").unwrap();
+            let mut back = Vec::new();
+            file.read_to_end(&mut back).unwrap();
+            assert!(back == contents);
         }
+        assert!(untouched_outside(&disk.into_inner(), start, len));
     }
-}
\ No newline at end of file
+
+    // Reads `blocks` whole blocks through `fill_buf`, the way a `BufReader` user would.
+    fn walk<D : BlockDevice>(dev : &mut OffsetScsiDevice<D>, blocks : usize) {
+        for _ in 0 .. blocks {
+            let len = dev.fill_buf().unwrap().len();
+            dev.consume(len);
+        }
+    }
+
+    #[test]
+    fn readahead_grows_on_sequential_reads_and_resets_on_seek() {
+        let mut disk = RecordingDisk::new(512, 256);
+        {
+            let mut dev = OffsetScsiDevice::new(&mut disk, 0);
+            dev.set_cache_size(64).unwrap();
+            dev.set_readahead(4, 16);
+            walk(&mut dev, 1);
+            assert_eq!(dev.readahead_window(), 0);
+            walk(&mut dev, 5);
+            assert_eq!(dev.readahead_window(), 4);
+            walk(&mut dev, 9);
+            assert_eq!(dev.readahead_window(), 8);
+            walk(&mut dev, 34);
+            assert_eq!(dev.readahead_window(), 16);
+            assert_eq!(dev.device().reads, vec![(0, 1), (1, 5), (6, 9), (15, 17), (32, 17)]);
+
+            dev.seek(SeekFrom::Start(100 * 512)).unwrap();
+            assert_eq!(dev.readahead_window(), 0);
+            walk(&mut dev, 2);
+            assert_eq!(dev.readahead_window(), 4);
+            // Seeking onto the block after the last one read keeps the streak going.
+            dev.seek(SeekFrom::Current(0)).unwrap();
+            walk(&mut dev, 5);
+            assert_eq!(dev.readahead_window(), 8);
+        }
+        assert_eq!(&disk.reads[5 ..], &[(100, 1), (101, 5), (106, 9)]);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::*;
+
+fn too_small(expected : usize, actual : usize) -> ScsiError {
+    ScsiError::from_cause(ErrorCause::BufferTooSmallError { expected, actual })
+}
+
+/// A `scsi::Buffer` that fills a borrowed slice from the front, so a
+/// channel can receive straight into the caller's memory.
+/// `size` is how much has been written and `capacity` is the slice length.
+pub struct SliceBuffer<'a> {
+    data : &'a mut [u8],
+    start : usize,
+    end : usize,
+}
+
+impl <'a> SliceBuffer<'a> {
+    pub fn new(data : &'a mut [u8]) -> SliceBuffer<'a> {
+        SliceBuffer { data, start : 0, end : 0 }
+    }
+}
+
+impl <'a> scsi::Buffer for SliceBuffer<'a> {
+    fn size(&self) -> usize {
+        self.end - self.start
+    }
+    fn capacity(&self) -> usize {
+        self.data.len() - self.start
+    }
+    fn push_byte(&mut self, byte : u8) -> Result<usize, ScsiError> {
+        if self.end == self.data.len() {
+            return Err(too_small(self.end + 1, self.data.len()));
+        }
+        self.data[self.end] = byte;
+        self.end += 1;
+        Ok(1)
+    }
+    fn pull_byte(&mut self) -> Result<u8, ScsiError> {
+        if self.start == self.end {
+            return Err(too_small(1, 0));
+        }
+        self.start += 1;
+        Ok(self.data[self.start - 1])
+    }
+}
+
+/// A read-only cursor over a borrowed slice, for sending it through a channel without copying.
+pub struct SliceReader<'a> {
+    data : &'a [u8],
+    pos : usize,
+}
+
+impl <'a> SliceReader<'a> {
+    pub fn new(data : &'a [u8]) -> SliceReader<'a> {
+        SliceReader { data, pos : 0 }
+    }
+}
+
+impl <'a> scsi::Buffer for SliceReader<'a> {
+    fn size(&self) -> usize {
+        self.data.len() - self.pos
+    }
+    fn capacity(&self) -> usize {
+        self.data.len() - self.pos
+    }
+    fn push_byte(&mut self, _byte : u8) -> Result<usize, ScsiError> {
+        Err(too_small(self.data.len() + 1, self.data.len()))
+    }
+    fn pull_byte(&mut self) -> Result<u8, ScsiError> {
+        let byte = *self.data.get(self.pos).ok_or_else(|| too_small(1, 0))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+}
+
+/// An owned `scsi::Buffer` whose whole capacity is allocated when it is made, so
+/// `capacity` is exactly what it can hold. Pulling from the front is O(1).
+pub struct VecBuffer {
+    data : Vec<u8>,
+    start : usize,
+    end : usize,
+}
+
+impl VecBuffer {
+    /// An empty buffer that holds exactly `capacity` bytes.
+    pub fn with_capacity(capacity : usize) -> VecBuffer {
+        VecBuffer { data : vec![0 ; capacity], start : 0, end : 0 }
+    }
+
+    /// An empty buffer for at least `len` bytes, rounded up to a whole number of `block_size` blocks.
+    pub fn with_block_capacity(len : usize, block_size : usize) -> VecBuffer {
+        let block_size = block_size.max(1);
+        VecBuffer::with_capacity((len + block_size - 1) / block_size * block_size)
+    }
+
+    /// Hands the unfilled space to `receive`, which returns how many bytes it put at the
+    /// front of it; only those count towards `size`.
+    pub fn fill_with<E, F>(&mut self, receive : F) -> Result<usize, E>
+            where F : FnOnce(&mut [u8]) -> Result<usize, E> {
+        let space = self.data.len() - self.end;
+        let count = receive(&mut self.data[self.end ..])?.min(space);
+        self.end += count;
+        Ok(count)
+    }
+
+    /// The bytes received and not yet pulled.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.start .. self.end]
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.data.truncate(self.end);
+        self.data.drain(.. self.start);
+        self.data
+    }
+}
+
+impl scsi::Buffer for VecBuffer {
+    fn size(&self) -> usize {
+        self.end - self.start
+    }
+    fn capacity(&self) -> usize {
+        self.data.len() - self.start
+    }
+    fn push_byte(&mut self, byte : u8) -> Result<usize, ScsiError> {
+        if self.end == self.data.len() {
+            return Err(too_small(self.end + 1, self.data.len()));
+        }
+        self.data[self.end] = byte;
+        self.end += 1;
+        Ok(1)
+    }
+    fn pull_byte(&mut self) -> Result<u8, ScsiError> {
+        if self.start == self.end {
+            return Err(too_small(1, 0));
+        }
+        self.start += 1;
+        Ok(self.data[self.start - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scsi::Buffer;
+
+    // How the buffer before `SliceReader` gave up its bytes: one `remove(0)` per byte.
+    struct RemoveFrontBuffer(Vec<u8>);
+
+    impl scsi::Buffer for RemoveFrontBuffer {
+        fn size(&self) -> usize {
+            self.0.len()
+        }
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+        fn push_byte(&mut self, byte : u8) -> Result<usize, ScsiError> {
+            self.0.push(byte);
+            Ok(1)
+        }
+        fn pull_byte(&mut self) -> Result<u8, ScsiError> {
+            if self.0.is_empty() {
+                return Err(too_small(1, 0));
+            }
+            Ok(self.0.remove(0))
+        }
+    }
+
+    fn drain<B : scsi::Buffer>(buffer : &mut B) -> u64 {
+        let mut sum = 0;
+        while buffer.size() > 0 {
+            sum += buffer.pull_byte().unwrap() as u64;
+        }
+        sum
+    }
+
+    #[test]
+    fn vec_buffer_block_capacity() {
+        assert_eq!(VecBuffer::with_block_capacity(512, 512).capacity(), 512);
+        assert_eq!(VecBuffer::with_block_capacity(513, 512).capacity(), 1024);
+        assert_eq!(VecBuffer::with_block_capacity(512, 4096).capacity(), 4096);
+        assert_eq!(VecBuffer::with_block_capacity(512, 0).capacity(), 512);
+        assert_eq!(VecBuffer::with_block_capacity(0, 512).capacity(), 0);
+    }
+
+    #[test]
+    fn vec_buffer_size_follows_what_was_received() {
+        let mut buffer = VecBuffer::with_capacity(8);
+        assert_eq!((buffer.size(), buffer.capacity()), (0, 8));
+        let count = buffer.fill_with(|space| -> Result<usize, ()> {
+            space[.. 3].copy_from_slice(&[1, 2, 3]);
+            Ok(3)
+        }).unwrap();
+        assert_eq!((count, buffer.size(), buffer.capacity()), (3, 3, 8));
+        assert_eq!(buffer.push_byte(4).unwrap(), 1);
+        assert_eq!(buffer.pull_byte().unwrap(), 1);
+        assert_eq!((buffer.size(), buffer.capacity()), (3, 7));
+        assert_eq!(buffer.as_slice(), &[2, 3, 4]);
+
+        // A receiver that claims more than there was room for is held to the capacity.
+        assert_eq!(buffer.fill_with(|space| -> Result<usize, ()> { Ok(space.len() + 10) }).unwrap(), 4);
+        assert!(buffer.push_byte(0).is_err());
+        assert_eq!(buffer.into_vec(), vec![2, 3, 4, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn residue_short_read_sets_size() {
+        let mut target = EmulatedTarget::new(RamDisk::new(512, 16));
+        // INQUIRY has 36 bytes; the other 28 come back as residue and must not count.
+        let cbw = bot::Cbw::new(0, &[0x12, 0, 0, 0, 36, 0], bot::DataDirection::In, 64);
+        let mut buffer = VecBuffer::with_capacity(64);
+        let csw = bot::execute_in(&mut target, &cbw, &mut buffer).unwrap();
+        assert_eq!((csw.status, csw.data_residue), (bot::CSW_STATUS_PASSED, 28));
+        assert_eq!((buffer.size(), buffer.capacity()), (36, 64));
+        assert_eq!(&buffer.as_slice()[8 .. 15], b"RUSTEMU");
+
+        // A failed command pads its data stage, but the residue says none of it was data.
+        let cbw = bot::Cbw::new(0, &[0x28, 0, 0, 0, 0, 64, 0, 0, 1, 0], bot::DataDirection::In, 512);
+        let mut buffer = VecBuffer::with_block_capacity(512, 512);
+        let csw = bot::execute_in(&mut target, &cbw, &mut buffer).unwrap();
+        assert_eq!((csw.status, csw.data_residue), (bot::CSW_STATUS_FAILED, 512));
+        assert_eq!(buffer.size(), 0);
+    }
+
+    fn report(what : &str, bytes : usize, elapsed : Duration) -> f64 {
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        println!("{}: {} KiB in {:.3}s ({:.1} MiB/s)", what, bytes >> 10, secs, bytes as f64 / (1 << 20) as f64 / secs);
+        secs
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture pull_byte_throughput`.
+    #[test]
+    #[ignore]
+    fn pull_byte_throughput() {
+        // The old buffer is quadratic in the transfer size, so keep both modest.
+        const TOTAL : usize = 4 << 20;
+        const TRANSFER : usize = 16 << 10;
+        let payload : Vec<u8> = (0 .. TRANSFER).map(|idx| idx as u8).collect();
+        let expected = payload.iter().map(|&b| b as u64).sum::<u64>() * (TOTAL / TRANSFER) as u64;
+
+        let start = Instant::now();
+        let mut sum = 0;
+        for _ in 0 .. TOTAL / TRANSFER {
+            sum += drain(&mut RemoveFrontBuffer(payload.clone()));
+        }
+        let old = report("remove(0) pull_byte", TOTAL, start.elapsed());
+        assert_eq!(sum, expected);
+
+        let start = Instant::now();
+        let mut sum = 0;
+        for _ in 0 .. TOTAL / TRANSFER {
+            sum += drain(&mut SliceReader::new(&payload));
+        }
+        let new = report("SliceReader pull_byte", TOTAL, start.elapsed());
+        assert_eq!(sum, expected);
+        assert!(new < old, "the slice cursor took {:.3}s against {:.3}s", new, old);
+    }
+}
@@ -0,0 +1,630 @@
+use crate::*;
+use bot::SharedChannel;
+use image_format::ImageFormat;
+use libusb::Context;
+use select::DeviceFilter;
+use std::io;
+
+const IMAGE_BLOCK_SIZE : usize = 512;
+const PROGRESS_INTERVAL : Duration = Duration::from_millis(500);
+
+// (name, arguments, minimum argument count, maximum argument count, description)
+const COMMANDS : &'static [(&'static str, &'static str, usize, usize, &'static str)] = &[
+    ("list", "", 0, 0, "List attached mass-storage devices."),
+    ("watch", "", 0, 0, "Print mass-storage devices as they are plugged in and removed."),
+    ("info", "", 0, 0, "Show the selected device's LUNs and capacity."),
+    ("partitions", "", 0, 0, "List the partition table."),
+    ("mkpart", "<type> [size] [start]", 1, 3, "Add a primary MBR partition, 1 MiB aligned, in the first free space that fits."),
+    ("rmpart", "<n>", 1, 1, "Delete primary MBR partition n."),
+    ("resizepart", "<n> <size|max>", 2, 2, "Move the end of primary MBR partition n, or grow it into the free space after it."),
+    ("setpart", "<n> <type|boot|noboot>", 2, 2, "Change a primary MBR partition's type or bootable flag."),
+    ("format", "[label]", 0, 1, "Create an empty FAT filesystem on the partition given with -p, or the first one."),
+    ("prepare", "[label]", 0, 1, "Replace everything with a fresh MBR and one FAT32 partition filling the device."),
+    ("ls", "[path]", 0, 1, "List a directory."),
+    ("cat", "<path>", 1, 1, "Write a file to stdout."),
+    ("get", "<path> [local]", 1, 2, "Copy a file off the device."),
+    ("put", "<local> [path]", 1, 2, "Copy a file onto the device, replacing any existing one."),
+    ("mkdir", "<path>", 1, 1, "Create a directory."),
+    ("rm", "<path>", 1, 1, "Remove a file or an empty directory."),
+    ("mv", "<from> <to>", 2, 2, "Rename or move a file or directory."),
+    ("df", "", 0, 0, "Show filesystem usage."),
+    ("dump", "<file>", 1, 1, "Copy the whole device, or the partition given with -p, into an image."),
+    ("restore", "<file>", 1, 1, "Write an image of any supported format onto the whole device, or the partition given with -p."),
+    ("help", "", 0, 0, "Show this message."),
+];
+
+const OPTIONS : &'static [(&'static str, &'static str)] = &[
+    ("--vid <hex>", "Only use devices with this vendor ID."),
+    ("--pid <hex>", "Only use devices with this product ID."),
+    ("--serial <text>", "Only use the device with this serial number."),
+    ("--bus <n>", "Only use devices on this bus."),
+    ("--port <n.n...>", "Only use the device on this port chain."),
+    ("--class <n|any>", "Match this device or interface class instead of mass storage."),
+    ("--lun <n>", "Use this LUN instead of the first one with a medium."),
+    ("--max-transfer <n>", "Read and write at most this many blocks per SCSI command (default 128)."),
+    ("-p, --partition <n>", "Use this partition index instead of the first one."),
+    ("--image <path>", "Use a raw, QCOW2 or VHD disk image instead of a USB device."),
+    ("--emulate <path>", "Use a raw, QCOW2 or VHD disk image through the emulated USB target."),
+    ("--resume <bytes>", "Continue an interrupted dump or restore from this offset."),
+    ("--verify", "After a dump or restore, compare hashes of both sides."),
+    ("--format <name>", "Dump as raw, sparse, gzip, zstd or android-sparse instead of going by the file extension."),
+    ("--fat <12|16|32>", "Format as this FAT type instead of going by the partition size."),
+    ("--cluster <size>", "Format with clusters of this many bytes, e.g. 4K."),
+    ("--oem <name>", "Put this OEM name in the formatted boot sector."),
+];
+
+fn usage_error(message : String) -> StorageError {
+    io::Error::new(io::ErrorKind::InvalidInput, message).into()
+}
+
+pub fn print_usage() {
+    println!("Usage: rust-usb-experiments [options] <command> [arguments]");
+    println!();
+    println!("Commands:");
+    for (name, args, _, _, description) in COMMANDS {
+        println!("  {:<28}{}", format!("{} {}", name, args), description);
+    }
+    println!();
+    println!("Options:");
+    for (option, description) in OPTIONS {
+        println!("  {:<28}{}", option, description);
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Source {
+    Usb,
+    Image(String),
+    Emulate(String),
+}
+
+#[derive(Debug, Clone)]
+struct Options {
+    filter : Option<DeviceFilter>,
+    source : Source,
+    lun : Option<u8>,
+    max_transfer : Option<usize>,
+    partition : Option<usize>,
+    resume_from : u64,
+    verify : bool,
+    format : Option<ImageFormat>,
+    fat_type : Option<fatfs::FatType>,
+    cluster_bytes : Option<u32>,
+    oem_name : Option<String>,
+    command : String,
+    args : Vec<String>,
+}
+
+impl Options {
+    fn from_args(args : &[String]) -> Result<Options, StorageError> {
+        let (filter, rest) = DeviceFilter::from_args(args)?;
+        let mut source = Source::Usb;
+        let mut lun = None;
+        let mut max_transfer = None;
+        let mut partition = None;
+        let mut resume_from = 0;
+        let mut verify = false;
+        let mut format = None;
+        let mut fat_type = None;
+        let mut cluster_bytes = None;
+        let mut oem_name = None;
+        let mut positional = Vec::new();
+        let mut help = false;
+        let mut iter = rest.into_iter();
+        while let Some(arg) = iter.next() {
+            let flag = arg.as_str();
+            match flag {
+                "-h" | "--help" => {
+                    help = true;
+                    continue;
+                },
+                "--verify" => {
+                    verify = true;
+                    continue;
+                },
+                "--image" | "--emulate" | "--lun" | "--max-transfer" | "-p" | "--partition" | "--resume" | "--format" |
+                "--fat" | "--cluster" | "--oem" => {},
+                _ => {
+                    positional.push(arg.clone());
+                    continue;
+                },
+            }
+            let value = iter.next().ok_or_else(|| usage_error(format!("{} needs a value", flag)))?;
+            let parse_err = |_| usage_error(format!("{} expects a number, got \"{}\"", flag, value));
+            match flag {
+                "--image" => source = Source::Image(value.clone()),
+                "--emulate" => source = Source::Emulate(value.clone()),
+                "--lun" => lun = Some(value.parse().map_err(parse_err)?),
+                "--max-transfer" => max_transfer = Some(value.parse().map_err(parse_err)?),
+                "--resume" => resume_from = value.parse().map_err(parse_err)?,
+                "--format" => format = Some(ImageFormat::from_name(&value)
+                    .ok_or_else(|| usage_error(format!("Unknown image format \"{}\".", value)))?),
+                "--fat" => fat_type = Some(match value.as_str() {
+                    "12" => fatfs::FatType::Fat12,
+                    "16" => fatfs::FatType::Fat16,
+                    "32" => fatfs::FatType::Fat32,
+                    _ => return Err(usage_error(format!("--fat expects 12, 16 or 32, got \"{}\"", value))),
+                }),
+                "--cluster" => {
+                    let bytes = parse_size(&value, 1)?;
+                    if bytes > u32::max_value() as u64 {
+                        return Err(usage_error(format!("Cluster size {} is far too large.", value)));
+                    }
+                    cluster_bytes = Some(bytes as u32);
+                },
+                "--oem" => oem_name = Some(value.clone()),
+                _ => partition = Some(value.parse().map_err(parse_err)?),
+            }
+        }
+        if help {
+            positional = vec!["help".to_owned()];
+        }
+        if positional.is_empty() {
+            return Err(usage_error("No command given; try \"help\".".to_owned()));
+        }
+        let command = positional.remove(0);
+        let (_, usage, min, max, _) = COMMANDS.iter().find(|(name, ..)| *name == command)
+            .ok_or_else(|| usage_error(format!("Unknown command \"{}\"; try \"help\".", command)))?;
+        if positional.len() < *min || positional.len() > *max {
+            return Err(usage_error(format!("Usage: {} {}", command, usage)));
+        }
+        Ok(Options { filter, source, lun, max_transfer, partition, resume_from, verify, format, fat_type, cluster_bytes, oem_name, command, args : positional })
+    }
+
+    fn filter(&self) -> DeviceFilter {
+        self.filter.clone().unwrap_or_else(DeviceFilter::mass_storage)
+    }
+
+    fn apply_max_transfer<C : bot::BotTransport>(&self, disk : &mut ScsiDisk<C>) {
+        if let Some(blocks) = self.max_transfer {
+            disk.set_max_transfer_blocks(blocks);
+        }
+    }
+
+    fn arg(&self, idx : usize) -> Option<&str> {
+        self.args.get(idx).map(|arg| arg.as_str())
+    }
+}
+
+/// Parses `args` (without the program name) and runs the command they name.
+pub fn run(args : &[String]) -> Result<(), StorageError> {
+    let opts = Options::from_args(args)?;
+    match opts.command.as_str() {
+        "help" => {
+            print_usage();
+            return Ok(());
+        },
+        "list" => return cmd_list(&opts),
+        "watch" => return cmd_watch(&opts),
+        _ => {},
+    }
+
+    let context;
+    let mut disk : Box<dyn BlockDevice + '_> = match opts.source {
+        Source::Image(ref path) => open_image(path, IMAGE_BLOCK_SIZE)?,
+        Source::Emulate(ref path) => {
+            let target = EmulatedTarget::new(open_image(path, IMAGE_BLOCK_SIZE)?);
+            let mut disk = ScsiDisk::with_lun(target, opts.lun.unwrap_or(0))?;
+            opts.apply_max_transfer(&mut disk);
+            Box::new(disk)
+        },
+        Source::Usb => {
+            context = Context::new()?;
+            Box::new(open_usb(&context, &opts)?)
+        },
+    };
+    let disk = &mut *disk;
+    match opts.command.as_str() {
+        "info" => cmd_info(&opts, disk),
+        "partitions" => cmd_partitions(&opts, disk),
+        "dump" | "restore" => cmd_image(&opts, disk),
+        "mkpart" | "rmpart" | "resizepart" | "setpart" => cmd_edit_mbr(&opts, disk),
+        "format" | "prepare" => cmd_format(&opts, disk),
+        _ => {
+            let fs = mount(&opts, disk)?;
+            run_fs_command(&opts, &fs)?;
+            fs.unmount().map_err(StorageError::Filesystem)
+        },
+    }
+}
+
+fn open_usb<'a>(context : &'a Context, opts : &Options) -> Result<ScsiDisk<SharedChannel<UsbClient<'a>>>, StorageError> {
+    let (mut device, info) = select::select_device(context, &opts.filter())?;
+    let mut channel = SharedChannel::new(UsbClient::from_device(&mut device)?);
+    let luns = enumerate_luns(&mut channel)?;
+    if opts.command == "info" {
+        println!("Device: {}", info);
+        for lun in luns.iter() {
+            println!("  {}", lun);
+        }
+    }
+    let lun = match opts.lun {
+        Some(lun) => lun,
+        None => luns.iter().find(|lun| lun.capacity.is_some())
+            .ok_or_else(|| StorageError::Device(format!("No LUN of {} has a medium.", info)))?.lun,
+    };
+    let mut disk = ScsiDisk::with_lun(channel, lun)?;
+    opts.apply_max_transfer(&mut disk);
+    Ok(disk)
+}
+
+/// A partition from either kind of table, reduced to what the commands need.
+struct PartitionSpan {
+    index : usize,
+    start_lba : u64,
+    block_count : u64,
+    /// Extended MBR containers are listed but can't hold a filesystem.
+    usable : bool,
+    description : String,
+}
+
+fn read_partition_spans(mut disk : &mut dyn BlockDevice) -> Result<Vec<PartitionSpan>, StorageError> {
+    // The MBR is 512 bytes however small the blocks are.
+    let mut block = buffer::VecBuffer::with_block_capacity(512, disk.block_size());
+    block.fill_with(|space| disk.read_blocks(0, space).map(|()| space.len()))?;
+    if gpt::has_protective_mbr(block.as_slice()) {
+        let table = gpt::read_gpt(&mut disk)?;
+        return Ok(table.partitions.iter().map(|part| PartitionSpan {
+            index : part.index,
+            start_lba : part.first_lba,
+            block_count : part.block_count(),
+            usable : true,
+            description : part.to_string(),
+        }).collect());
+    }
+    Ok(mbr::read_partitions(&mut disk)?.iter().map(|part| PartitionSpan {
+        index : part.index,
+        start_lba : part.start_lba,
+        block_count : part.sector_count,
+        usable : !part.is_extended(),
+        description : part.to_string(),
+    }).collect())
+}
+
+fn choose_partition(spans : &[PartitionSpan], index : Option<usize>) -> Result<&PartitionSpan, StorageError> {
+    let mut usable = spans.iter().filter(|span| span.usable);
+    match index {
+        None => usable.next().ok_or_else(|| PartitionError::NoPartitions.into()),
+        Some(index) => usable.find(|span| span.index == index)
+            .ok_or_else(|| usage_error(format!("No usable partition {}; see \"partitions\".", index))),
+    }
+}
+
+type Fs<'d> = fatfs::FileSystem<OffsetScsiDevice<&'d mut dyn BlockDevice>>;
+type FsDir<'f, 'd> = fatfs::Dir<'f, OffsetScsiDevice<&'d mut dyn BlockDevice>>;
+
+fn mount<'d>(opts : &Options, disk : &'d mut dyn BlockDevice) -> Result<Fs<'d>, StorageError> {
+    let (start_lba, block_count) = {
+        let spans = read_partition_spans(&mut *disk)?;
+        let span = choose_partition(&spans, opts.partition)?;
+        (span.start_lba, span.block_count)
+    };
+    let block_size = disk.block_size() as u64;
+    let partition = OffsetScsiDevice::with_length(disk, start_lba * block_size, block_count * block_size);
+    fatfs::FileSystem::new(partition, fatfs::FsOptions::new()).map_err(StorageError::Filesystem)
+}
+
+// fatfs paths are relative to the directory they're opened from, so leading slashes go.
+fn fs_path(path : &str) -> &str {
+    path.trim_matches('/')
+}
+
+fn open_dir<'f, 'd>(fs : &'f Fs<'d>, path : &str) -> io::Result<FsDir<'f, 'd>> {
+    let path = fs_path(path);
+    if path.is_empty() {
+        Ok(fs.root_dir())
+    } else {
+        fs.root_dir().open_dir(path)
+    }
+}
+
+fn file_name(path : &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or(path)
+}
+
+fn run_fs_command(opts : &Options, fs : &Fs) -> Result<(), StorageError> {
+    let root = fs.root_dir();
+    let result = match opts.command.as_str() {
+        "ls" => cmd_ls(fs, opts.arg(0).unwrap_or("")),
+        "cat" => {
+            let mut file = root.open_file(fs_path(opts.arg(0).unwrap()))?;
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            io::copy(&mut file, &mut out).and_then(|_| out.flush())
+        },
+        "get" => {
+            let remote = opts.arg(0).unwrap();
+            let local = opts.arg(1).unwrap_or_else(|| file_name(remote));
+            let mut file = root.open_file(fs_path(remote))?;
+            let mut out = File::create(local)?;
+            let copied = io::copy(&mut file, &mut out)?;
+            println!("{} -> {} ({} bytes)", remote, local, copied);
+            out.sync_all()
+        },
+        "put" => {
+            let local = opts.arg(0).unwrap();
+            let remote = opts.arg(1).unwrap_or_else(|| file_name(local));
+            let mut input = File::open(local)?;
+            let mut file = root.create_file(fs_path(remote))?;
+            file.truncate()?;
+            let copied = io::copy(&mut input, &mut file)?;
+            println!("{} -> {} ({} bytes)", local, remote, copied);
+            file.flush()
+        },
+        "mkdir" => root.create_dir(fs_path(opts.arg(0).unwrap())).map(|_| ()),
+        "rm" => root.remove(fs_path(opts.arg(0).unwrap())),
+        "mv" => root.rename(fs_path(opts.arg(0).unwrap()), &root, fs_path(opts.arg(1).unwrap())),
+        "df" => cmd_df(fs),
+        other => unreachable!("{} is not a filesystem command", other),
+    };
+    result.map_err(StorageError::from)
+}
+
+fn cmd_ls(fs : &Fs, path : &str) -> io::Result<()> {
+    for entry in open_dir(fs, path)?.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let modified = entry.modified();
+        println!("{} {:>12} {:04}-{:02}-{:02} {:02}:{:02}  {}{}",
+            if entry.is_dir() { 'd' } else { '-' }, entry.len(),
+            modified.date.year, modified.date.month, modified.date.day, modified.time.hour, modified.time.min,
+            name, if entry.is_dir() { "/" } else { "" });
+    }
+    Ok(())
+}
+
+fn cmd_df(fs : &Fs) -> io::Result<()> {
+    let stats = fs.stats()?;
+    let cluster_size = stats.cluster_size() as u64;
+    let total = stats.total_clusters() as u64 * cluster_size;
+    let free = stats.free_clusters() as u64 * cluster_size;
+    println!("Volume \"{}\" ({:?}, id {:08X})", fs.volume_label().trim_end(), fs.fat_type(), fs.volume_id());
+    println!("Cluster size: {} bytes", cluster_size);
+    println!("Total: {} bytes ({} clusters)", total, stats.total_clusters());
+    println!("Used:  {} bytes", total - free);
+    println!("Free:  {} bytes ({} clusters)", free, stats.free_clusters());
+    Ok(())
+}
+
+fn cmd_list(opts : &Options) -> Result<(), StorageError> {
+    let context = Context::new()?;
+    let found = select::find_devices(&context, &opts.filter())?;
+    if found.is_empty() {
+        println!("No devices match {}.", opts.filter());
+    }
+    for (_, info) in found.iter() {
+        println!("{}", info);
+    }
+    Ok(())
+}
+
+fn cmd_watch(opts : &Options) -> Result<(), StorageError> {
+    let watcher = hotplug::HotplugWatcher::start_with(opts.filter(), Duration::from_secs(2), false)?;
+    println!("Watching for {} ({:?}).", opts.filter(), watcher.mode());
+    loop {
+        match watcher.recv_timeout(Duration::from_secs(60)) {
+            Ok(hotplug::HotplugEvent::Arrived(info)) => println!("Arrived: {}", info),
+            Ok(hotplug::HotplugEvent::Left(info)) => println!("Left: {}", info),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {},
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(StorageError::Device("Hotplug watcher stopped unexpectedly.".to_owned()));
+            },
+        }
+    }
+}
+
+fn cmd_info(opts : &Options, disk : &mut dyn BlockDevice) -> Result<(), StorageError> {
+    match opts.source {
+        Source::Usb => {},
+        Source::Image(ref path) => println!("Image: {}", path),
+        Source::Emulate(ref path) => println!("Emulated target backed by {}", path),
+    }
+    println!("Block size: {} bytes", disk.block_size());
+    println!("Block count: {}", disk.block_count());
+    println!("Capacity: {} bytes ({} MiB)", disk.byte_len(), disk.byte_len() >> 20);
+    Ok(())
+}
+
+fn cmd_partitions(opts : &Options, disk : &mut dyn BlockDevice) -> Result<(), StorageError> {
+    let spans = read_partition_spans(disk)?;
+    if spans.is_empty() {
+        println!("No partitions.");
+        return Ok(());
+    }
+    let default = choose_partition(&spans, opts.partition).ok().map(|span| span.index);
+    for span in spans.iter() {
+        println!("{} {}", if Some(span.index) == default { '*' } else { ' ' }, span.description);
+    }
+    Ok(())
+}
+
+/// Partition type names accepted wherever a type is, besides hex bytes like 0c or 0x83.
+const PARTITION_TYPES : &'static [(&'static str, u8)] = &[
+    ("fat12", 0x01),
+    ("fat16", 0x0E),
+    ("fat32", 0x0C),
+    ("ntfs", 0x07),
+    ("exfat", 0x07),
+    ("extended", 0x0F),
+    ("swap", 0x82),
+    ("linux", 0x83),
+    ("efi", 0xEF),
+];
+
+fn parse_partition_type(text : &str) -> Result<u8, StorageError> {
+    let lower = text.to_lowercase();
+    if let Some((_, partition_type)) = PARTITION_TYPES.iter().find(|(name, _)| *name == lower) {
+        return Ok(*partition_type);
+    }
+    let hex = lower.trim_start_matches("0x");
+    u8::from_str_radix(hex, 16).map_err(|_| usage_error(format!("Unknown partition type \"{}\"; use a hex byte or one of fat12, fat16, fat32, ntfs, exfat, extended, swap, linux, efi.", text)))
+}
+
+/// Parses a byte count with an optional binary K, M, G or T suffix into whole blocks.
+fn parse_size(text : &str, block_size : usize) -> Result<u64, StorageError> {
+    let upper = text.to_uppercase();
+    let (number, suffix) = upper.split_at(upper.find(|c : char| !c.is_ascii_digit()).unwrap_or(upper.len()));
+    let shift = match suffix.trim_end_matches("IB").trim_end_matches('B') {
+        "" => Some(0),
+        "K" => Some(10),
+        "M" => Some(20),
+        "G" => Some(30),
+        "T" => Some(40),
+        _ => None,
+    };
+    let bytes = shift.and_then(|shift| number.parse::<u64>().ok().and_then(|number| number.checked_mul(1 << shift)))
+        .ok_or_else(|| usage_error(format!("Bad size \"{}\"; use a byte count like 4096, 512M or 2G.", text)))?;
+    Ok(bytes / block_size as u64)
+}
+
+fn parse_entry_index(text : &str) -> Result<usize, StorageError> {
+    text.parse().map_err(|_| usage_error(format!("Expected a partition number from 0 to 3, got \"{}\".", text)))
+}
+
+fn cmd_edit_mbr(opts : &Options, disk : &mut dyn BlockDevice) -> Result<(), StorageError> {
+    let block_size = disk.block_size();
+    let mut table = match mbr::MbrTable::read(disk) {
+        Err(StorageError::Partition(PartitionError::MissingSignature)) if opts.command == "mkpart" => {
+            eprintln!("No MBR found; starting a new one.");
+            mbr::MbrTable::empty(&*disk)
+        },
+        other => other?,
+    };
+    match opts.command.as_str() {
+        "mkpart" => {
+            let partition_type = parse_partition_type(opts.arg(0).unwrap())?;
+            let blocks = opts.arg(1).map(|size| parse_size(size, block_size)).transpose()?;
+            let start = opts.arg(2).map(|start| parse_size(start, block_size)).transpose()?;
+            let index = table.create(partition_type, start, blocks)?;
+            println!("Created partition {}.", index);
+        },
+        "rmpart" => {
+            let index = parse_entry_index(opts.arg(0).unwrap())?;
+            table.delete(index)?;
+            println!("Deleted partition {}.", index);
+        },
+        "resizepart" => {
+            let index = parse_entry_index(opts.arg(0).unwrap())?;
+            let blocks = match opts.arg(1).unwrap() {
+                "max" => None,
+                size => Some(parse_size(size, block_size)?),
+            };
+            table.resize(index, blocks)?;
+            println!("Resized partition {} to {} blocks.", index, table.entries()[index].sector_count);
+        },
+        "setpart" => {
+            let index = parse_entry_index(opts.arg(0).unwrap())?;
+            match opts.arg(1).unwrap() {
+                "boot" => table.set_bootable(index, true)?,
+                "noboot" => table.set_bootable(index, false)?,
+                other => table.set_type(index, parse_partition_type(other)?)?,
+            }
+        },
+        other => unreachable!("{} is not a partition editing command", other),
+    }
+    table.write(disk)?;
+    cmd_partitions(opts, disk)
+}
+
+fn cmd_format(opts : &Options, disk : &mut dyn BlockDevice) -> Result<(), StorageError> {
+    let options = fat_format::FormatOptions {
+        label : opts.arg(0).map(|label| label.to_owned()),
+        cluster_bytes : opts.cluster_bytes,
+        fat_type : opts.fat_type,
+        oem_name : opts.oem_name.clone(),
+    };
+    let summary = if opts.command == "prepare" {
+        fat_format::prepare_stick(disk, &options)?
+    } else {
+        let (index, start_lba, block_count) = {
+            let spans = read_partition_spans(&mut *disk)?;
+            let span = choose_partition(&spans, opts.partition)?;
+            (span.index, span.start_lba, span.block_count)
+        };
+        let block_size = disk.block_size() as u64;
+        let summary = {
+            let mut volume = OffsetScsiDevice::with_length(&mut *disk, start_lba * block_size, block_count * block_size);
+            let summary = fat_format::format_volume(&mut volume, &options)?;
+            volume.close()?;
+            summary
+        };
+        // Keep a primary MBR entry's type in line with what it now holds.
+        if let Ok(mut table) = mbr::MbrTable::read(&mut *disk) {
+            if index < table.entries().len() && table.entries()[index].start_lba as u64 == start_lba {
+                table.set_type(index, fat_format::mbr_partition_type(summary.fat_type))?;
+                table.write(&mut *disk)?;
+            }
+        }
+        summary
+    };
+    println!("Formatted as {:?}: {} clusters of {} bytes, volume id {:08X}.",
+        summary.fat_type, summary.total_clusters, summary.cluster_bytes, summary.volume_id);
+    Ok(())
+}
+
+fn cmd_image(opts : &Options, disk : &mut dyn BlockDevice) -> Result<(), StorageError> {
+    let (start_lba, block_count) = match opts.partition {
+        Some(index) => {
+            let spans = read_partition_spans(&mut *disk)?;
+            let span = choose_partition(&spans, Some(index))?;
+            (span.start_lba, span.block_count)
+        },
+        None => (0, disk.block_count()),
+    };
+    let mut target = BlockRange::new(disk, start_lba, block_count)?;
+    let path = Path::new(opts.arg(0).unwrap());
+    let options = image::ImageOptions {
+        resume_from : opts.resume_from,
+        verify : opts.verify,
+        ..image::ImageOptions::default()
+    };
+    let format = if opts.command == "dump" {
+        opts.format.unwrap_or_else(|| ImageFormat::from_path(path))
+    } else {
+        ImageFormat::detect(path)?
+    };
+
+    let mut copied = opts.resume_from;
+    let mut total = None;
+    let mut last_print : Option<Instant> = None;
+    let result = {
+        let report = |progress : &image::Progress| {
+            if progress.phase == image::ImagePhase::Copy {
+                copied = progress.done;
+                total = Some(progress.total);
+            }
+            if last_print.map(|last| last.elapsed() >= PROGRESS_INTERVAL).unwrap_or(true) || progress.done == progress.total {
+                eprint!("\r{}    ", progress);
+                last_print = Some(Instant::now());
+            }
+        };
+        match (opts.command == "dump", format) {
+            (true, ImageFormat::Raw) => image::dump_to_file(&mut target, path, &options, report),
+            (true, format) => image_format::export(&mut target, path, format, &options, report),
+            (false, ImageFormat::Raw) => image::restore_from_file(path, &mut target, &options, report),
+            (false, _) => image_format::import(path, &mut target, &options, report),
+        }
+    };
+    if last_print.is_some() {
+        eprintln!();
+    }
+    match result {
+        Ok(summary) => {
+            println!("Copied {} bytes in {:.1}s.", summary.copied, summary.elapsed.as_secs() as f64 + summary.elapsed.subsec_nanos() as f64 / 1e9);
+            if let Some(hash) = summary.hash {
+                println!("Verified: both sides hash to SHA-256 {}.", hash);
+            }
+            Ok(())
+        },
+        Err(e) => {
+            // Only raw copies report progress in device bytes and can pick up where they stopped.
+            if format == ImageFormat::Raw && copied > opts.resume_from && Some(copied) != total {
+                eprintln!("Stopped after {} bytes; rerun with --resume {} to continue.", copied, copied);
+            }
+            Err(e)
+        },
+    }
+}
@@ -0,0 +1,332 @@
+use crate::*;
+use bot::{Cbw, Csw, DataDirection, CSW_LEN};
+
+const STATUS_GOOD : u8 = 0;
+const STATUS_FAILED : u8 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct SenseCode {
+    key : u8,
+    asc : u8,
+    ascq : u8,
+}
+
+const NO_SENSE : SenseCode = SenseCode { key : 0x00, asc : 0x00, ascq : 0x00 };
+const UNRECOVERED_READ_ERROR : SenseCode = SenseCode { key : 0x03, asc : 0x11, ascq : 0x00 };
+const WRITE_ERROR : SenseCode = SenseCode { key : 0x03, asc : 0x0C, ascq : 0x00 };
+const INVALID_OPCODE : SenseCode = SenseCode { key : 0x05, asc : 0x20, ascq : 0x00 };
+const LBA_OUT_OF_RANGE : SenseCode = SenseCode { key : 0x05, asc : 0x21, ascq : 0x00 };
+const INVALID_FIELD_IN_CDB : SenseCode = SenseCode { key : 0x05, asc : 0x24, ascq : 0x00 };
+const LUN_NOT_SUPPORTED : SenseCode = SenseCode { key : 0x05, asc : 0x25, ascq : 0x00 };
+
+enum Phase {
+    Command,
+    DataIn { data : Vec<u8>, pos : usize },
+    DataOut { cbw : Cbw, data : Vec<u8>, reject : Option<SenseCode> },
+    Status,
+}
+
+/// A software Bulk-Only Transport mass-storage target backed by any `BlockDevice`.
+/// It plays the device side of the CBW/data/CSW exchange so `ScsiDisk` and
+/// everything above it can run without hardware.
+pub struct EmulatedTarget<D : BlockDevice> {
+    disk : D,
+    vendor : [u8 ; 8],
+    product : [u8 ; 16],
+    revision : [u8 ; 4],
+    phase : Phase,
+    csw : Csw,
+    sense : SenseCode,
+}
+
+fn padded(text : &str, out : &mut [u8]) {
+    for (idx, byte) in out.iter_mut().enumerate() {
+        *byte = *text.as_bytes().get(idx).unwrap_or(&b' ');
+    }
+}
+
+impl <D : BlockDevice> EmulatedTarget<D> {
+    pub fn new(disk : D) -> EmulatedTarget<D> {
+        let mut vendor = [0 ; 8];
+        let mut product = [0 ; 16];
+        let mut revision = [0 ; 4];
+        padded("RUSTEMU", &mut vendor);
+        padded("Emulated Disk", &mut product);
+        padded("0001", &mut revision);
+        EmulatedTarget {
+            disk,
+            vendor,
+            product,
+            revision,
+            phase : Phase::Command,
+            csw : Csw { tag : 0, data_residue : 0, status : STATUS_GOOD },
+            sense : NO_SENSE,
+        }
+    }
+
+    pub fn disk(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    fn finish(&mut self, cbw : &Cbw, transferred : usize, sense : SenseCode) {
+        let status = if sense == NO_SENSE { STATUS_GOOD } else { STATUS_FAILED };
+        if status != STATUS_GOOD {
+            eprintln!("EMU: Command 0x{:02x} failed with sense {:?}.", cbw.cdb[0], sense);
+        }
+        self.sense = sense;
+        self.csw = Csw {
+            tag : cbw.tag,
+            data_residue : (cbw.data_length as usize).saturating_sub(transferred) as u32,
+            status,
+        };
+    }
+
+    // Sends `data` (truncated to what the host asked for) and then the CSW.
+    fn respond_in(&mut self, cbw : &Cbw, mut data : Vec<u8>, sense : SenseCode) {
+        data.truncate(cbw.data_length as usize);
+        self.finish(cbw, data.len(), sense);
+        if cbw.direction == DataDirection::In {
+            self.phase = Phase::DataIn { data, pos : 0 };
+        } else {
+            self.phase = Phase::Status;
+        }
+    }
+
+    fn fail(&mut self, cbw : &Cbw, sense : SenseCode) {
+        match cbw.direction {
+            // Pad the data stage so the host's transfer sequence stays in step.
+            DataDirection::In => {
+                let padding = vec![0 ; cbw.data_length as usize];
+                self.finish(cbw, 0, sense);
+                self.phase = Phase::DataIn { data : padding, pos : 0 };
+            },
+            DataDirection::Out => {
+                self.phase = Phase::DataOut { cbw : cbw.clone(), data : Vec::new(), reject : Some(sense) };
+            },
+            DataDirection::None => {
+                self.finish(cbw, 0, sense);
+                self.phase = Phase::Status;
+            },
+        }
+    }
+
+    fn inquiry(&self, cbw : &Cbw) -> Vec<u8> {
+        let mut data = vec![0 ; 36];
+        data[0] = 0x00; // direct access block device
+        data[1] = 0x80; // removable
+        data[2] = 0x04; // SPC-2
+        data[3] = 0x02;
+        data[4] = 31;
+        data[8..16].copy_from_slice(&self.vendor);
+        data[16..32].copy_from_slice(&self.product);
+        data[32..36].copy_from_slice(&self.revision);
+        let alloc_len = cbw.cdb.get(4).cloned().unwrap_or(36) as usize;
+        data.truncate(alloc_len);
+        data
+    }
+
+    fn request_sense(&mut self) -> Vec<u8> {
+        let mut data = vec![0 ; 18];
+        data[0] = 0x70;
+        data[2] = self.sense.key;
+        data[7] = 10;
+        data[12] = self.sense.asc;
+        data[13] = self.sense.ascq;
+        self.sense = NO_SENSE;
+        data
+    }
+
+    fn read_capacity(&self) -> Vec<u8> {
+        let last_lba = self.disk.block_count().saturating_sub(1).min(u32::max_value() as u64) as u32;
+        let mut data = Vec::with_capacity(8);
+        data.extend_from_slice(&bot::be_u32_bytes(last_lba));
+        data.extend_from_slice(&bot::be_u32_bytes(self.disk.block_size() as u32));
+        data
+    }
+
+    fn rw10_range(&self, cbw : &Cbw) -> Result<(u64, usize), SenseCode> {
+        if cbw.cdb.len() < 10 {
+            return Err(INVALID_FIELD_IN_CDB);
+        }
+        let lba = bot::be_u32(&cbw.cdb[2..6]) as u64;
+        let blocks = ((cbw.cdb[7] as usize) << 8) | cbw.cdb[8] as usize;
+        if lba + blocks as u64 > self.disk.block_count() {
+            return Err(LBA_OUT_OF_RANGE);
+        }
+        if blocks * self.disk.block_size() != cbw.data_length as usize {
+            return Err(INVALID_FIELD_IN_CDB);
+        }
+        Ok((lba, blocks))
+    }
+
+    fn execute(&mut self, cbw : Cbw) {
+        // Only LUN 0 exists; REQUEST SENSE still has to work so the host can find out why.
+        if cbw.lun != 0 && cbw.cdb[0] != 0x03 {
+            return self.fail(&cbw, LUN_NOT_SUPPORTED);
+        }
+        match cbw.cdb[0] {
+            // TEST UNIT READY, PREVENT ALLOW MEDIUM REMOVAL
+            0x00 | 0x1E => self.respond_in(&cbw, Vec::new(), NO_SENSE),
+            // REQUEST SENSE
+            0x03 => {
+                let data = self.request_sense();
+                self.respond_in(&cbw, data, NO_SENSE);
+            },
+            // INQUIRY
+            0x12 => {
+                if cbw.cdb.get(1).cloned().unwrap_or(0) & 0x01 != 0 {
+                    self.fail(&cbw, INVALID_FIELD_IN_CDB);
+                    return;
+                }
+                let data = self.inquiry(&cbw);
+                self.respond_in(&cbw, data, NO_SENSE);
+            },
+            // MODE SENSE(6): header only, not write protected
+            0x1A => self.respond_in(&cbw, vec![3, 0, 0, 0], NO_SENSE),
+            // READ CAPACITY(10)
+            0x25 => {
+                let data = self.read_capacity();
+                self.respond_in(&cbw, data, NO_SENSE);
+            },
+            // READ(10)
+            0x28 => {
+                let (lba, blocks) = match self.rw10_range(&cbw) {
+                    Ok(range) => range,
+                    Err(sense) => return self.fail(&cbw, sense),
+                };
+                let mut data = vec![0 ; blocks * self.disk.block_size()];
+                match self.disk.read_blocks(lba, &mut data) {
+                    Ok(()) => self.respond_in(&cbw, data, NO_SENSE),
+                    Err(e) => {
+                        eprintln!("EMU: Backing read error: {:?}", e);
+                        self.fail(&cbw, UNRECOVERED_READ_ERROR);
+                    },
+                }
+            },
+            // WRITE(10)
+            0x2A => {
+                let reject = self.rw10_range(&cbw).err();
+                if cbw.data_length == 0 {
+                    self.finish_write(cbw, Vec::new(), reject);
+                } else {
+                    self.phase = Phase::DataOut { cbw, data : Vec::new(), reject };
+                }
+            },
+            // SYNCHRONIZE CACHE(10)
+            0x35 => {
+                let sense = if self.disk.flush().is_ok() { NO_SENSE } else { WRITE_ERROR };
+                self.respond_in(&cbw, Vec::new(), sense);
+            },
+            _ => self.fail(&cbw, INVALID_OPCODE),
+        }
+    }
+
+    fn finish_write(&mut self, cbw : Cbw, data : Vec<u8>, reject : Option<SenseCode>) {
+        if let Some(sense) = reject {
+            self.finish(&cbw, 0, sense);
+        } else {
+            let lba = bot::be_u32(&cbw.cdb[2..6]) as u64;
+            match self.disk.write_blocks(lba, &data) {
+                Ok(()) => self.finish(&cbw, data.len(), NO_SENSE),
+                Err(e) => {
+                    eprintln!("EMU: Backing write error: {:?}", e);
+                    self.finish(&cbw, 0, WRITE_ERROR);
+                },
+            }
+        }
+        self.phase = Phase::Status;
+    }
+}
+
+fn transfer_error(direction : scsi::UsbTransferDirection) -> scsi::ScsiError {
+    scsi::ScsiError::from_cause(scsi::ErrorCause::UsbTransferError { direction })
+}
+
+// A real device stalls the endpoint when the host gets out of step.
+fn stall() -> StorageError {
+    StorageError::Transport(libusb::Error::Pipe)
+}
+
+impl <D : BlockDevice> bot::BotTransport for EmulatedTarget<D> {
+    fn bulk_only_reset(&mut self) -> Result<(), StorageError> {
+        eprintln!("EMU: Bulk-only reset.");
+        self.phase = Phase::Command;
+        Ok(())
+    }
+
+    fn read_into(&mut self, buffer : &mut [u8]) -> Result<usize, StorageError> {
+        match std::mem::replace(&mut self.phase, Phase::Command) {
+            Phase::DataIn { data, mut pos } => {
+                let count = buffer.len().min(data.len() - pos);
+                buffer[.. count].copy_from_slice(&data[pos .. pos + count]);
+                pos += count;
+                self.phase = if pos < data.len() { Phase::DataIn { data, pos } } else { Phase::Status };
+                Ok(count)
+            },
+            Phase::Status if buffer.len() >= CSW_LEN => {
+                buffer[.. CSW_LEN].copy_from_slice(&self.csw.to_bytes());
+                Ok(CSW_LEN)
+            },
+            other => {
+                eprintln!("EMU: Host tried to read outside of a data-in or status phase.");
+                self.phase = other;
+                Err(stall())
+            },
+        }
+    }
+
+    fn write_from(&mut self, received : &[u8]) -> Result<usize, StorageError> {
+        let count = received.len();
+        match std::mem::replace(&mut self.phase, Phase::Command) {
+            Phase::Command => {
+                let cbw = match Cbw::from_bytes(received) {
+                    Some(cbw) => cbw,
+                    None => {
+                        eprintln!("EMU: Got invalid CBW ({} bytes).", count);
+                        return Err(stall());
+                    },
+                };
+                self.execute(cbw);
+            },
+            Phase::DataOut { cbw, mut data, reject } => {
+                data.extend_from_slice(received);
+                if data.len() >= cbw.data_length as usize {
+                    data.truncate(cbw.data_length as usize);
+                    self.finish_write(cbw, data, reject);
+                } else {
+                    self.phase = Phase::DataOut { cbw, data, reject };
+                }
+            },
+            other => {
+                eprintln!("EMU: Host tried to write outside of a command or data-out phase.");
+                self.phase = other;
+                return Err(stall());
+            },
+        }
+        Ok(count)
+    }
+}
+
+// The byte-at-a-time `scsi::Buffer` path, for callers that don't go through `BotTransport`.
+impl <D : BlockDevice> scsi::CommunicationChannel for EmulatedTarget<D> {
+    fn in_transfer<B : scsi::Buffer> (&mut self, buffer: &mut B) -> Result<usize, scsi::ScsiError> {
+        let mut chunk = vec![0 ; buffer.capacity() - buffer.size()];
+        let count = bot::BotTransport::read_into(self, &mut chunk).map_err(|_| transfer_error(scsi::UsbTransferDirection::In))?;
+        for byte in &chunk[.. count] {
+            buffer.push_byte(*byte)?;
+        }
+        Ok(count)
+    }
+
+    fn out_transfer<B : scsi::Buffer>(&mut self, bytes: &mut B) -> Result<usize, scsi::ScsiError> {
+        let mut received = Vec::with_capacity(bytes.size());
+        while bytes.size() > 0 {
+            received.push(bytes.pull_byte()?);
+        }
+        bot::BotTransport::write_from(self, &received).map_err(|_| transfer_error(scsi::UsbTransferDirection::Out))
+    }
+}
@@ -1,51 +1,233 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use sense::{self, SenseData};
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct RawStringErr {
-    pub err : String, 
+#[derive(Debug)]
+pub enum PartitionError {
+    MissingSignature,
+    Mbr(mbr_nostd::MbrError),
+    EbrLoop { lba : u64 },
+    EbrOutOfRange { lba : u64, extended_start : u64, extended_end : u64 },
+    EbrChainTooLong { limit : usize },
+    InvalidGpt(String),
+    GptCrcMismatch { what : &'static str, stored : u32, computed : u32 },
+    NoValidGpt { primary : Box<PartitionError>, backup : Box<PartitionError> },
+    NoPartitions,
+    /// The rest are layouts an edit would have produced, refused before anything was written.
+    NoSuchEntry { index : usize },
+    EntryUnused { index : usize },
+    NoFreeEntry,
+    NoFreeSpace { blocks : Option<u64> },
+    Overlap { first : usize, second : usize },
+    PastEnd { index : usize, end : u64, block_count : u64 },
+    InvalidLayout(String),
 }
-impl From<scsi::ScsiError> for RawStringErr {
-    fn from(obj : scsi::ScsiError) -> RawStringErr {
-        RawStringErr {
-            err : format!("scsi::ScsiError ({:?})", obj)
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionError::MissingSignature => write!(f, "missing 0x55AA boot sector signature"),
+            PartitionError::Mbr(e) => write!(f, "invalid MBR: {:?}", e),
+            PartitionError::EbrLoop { lba } => write!(f, "EBR chain loops back to LBA {}", lba),
+            PartitionError::EbrOutOfRange { lba, extended_start, extended_end } => write!(f, "EBR at LBA {} lies outside extended partition {}..{}", lba, extended_start, extended_end),
+            PartitionError::EbrChainTooLong { limit } => write!(f, "EBR chain is longer than {} entries", limit),
+            PartitionError::InvalidGpt(msg) => write!(f, "invalid GPT: {}", msg),
+            PartitionError::GptCrcMismatch { what, stored, computed } => write!(f, "GPT {} CRC mismatch: stored {:08x} but computed {:08x}", what, stored, computed),
+            PartitionError::NoValidGpt { primary, backup } => write!(f, "no valid GPT found (primary: {}; backup: {})", primary, backup),
+            PartitionError::NoPartitions => write!(f, "partition table has no usable partitions"),
+            PartitionError::NoSuchEntry { index } => write!(f, "there is no primary entry {}; they are numbered 0-3", index),
+            PartitionError::EntryUnused { index } => write!(f, "primary entry {} is unused", index),
+            PartitionError::NoFreeEntry => write!(f, "all four primary entries are in use"),
+            PartitionError::NoFreeSpace { blocks : Some(blocks) } => write!(f, "no free aligned space holds {} blocks", blocks),
+            PartitionError::NoFreeSpace { blocks : None } => write!(f, "no free aligned space is left"),
+            PartitionError::Overlap { first, second } => write!(f, "partitions {} and {} would overlap", first, second),
+            PartitionError::PastEnd { index, end, block_count } => write!(f, "partition {} would end at block {}, past the end of the device ({} blocks)", index, end, block_count),
+            PartitionError::InvalidLayout(msg) => write!(f, "invalid layout: {}", msg),
         }
     }
 }
 
-impl From<mbr_nostd::MbrError> for RawStringErr {
-    fn from(obj : mbr_nostd::MbrError) -> RawStringErr {
-        RawStringErr {
-            err : format!("mbr_nostd::MbrError ({:?})", obj)
+impl Error for PartitionError {}
+
+impl From<mbr_nostd::MbrError> for PartitionError {
+    fn from(obj : mbr_nostd::MbrError) -> PartitionError {
+        PartitionError::Mbr(obj)
+    }
+}
+
+#[derive(Debug)]
+pub enum SelectError {
+    InvalidFilter(String),
+    NoMatch { filter : String },
+    MultipleMatches { filter : String, matches : Vec<String> },
+}
+
+impl fmt::Display for SelectError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectError::InvalidFilter(msg) => write!(f, "invalid device filter: {}", msg),
+            SelectError::NoMatch { filter } => write!(f, "no device matches {}", filter),
+            SelectError::MultipleMatches { filter, matches } => write!(f, "{} devices match {}: {}", matches.len(), filter, matches.join("; ")),
         }
     }
 }
-impl From<libusb::Error> for RawStringErr {
-    fn from(obj : libusb::Error) -> RawStringErr {
-        RawStringErr {
-            err : format!("libusb::Error ({:?})", obj)
+
+impl Error for SelectError {}
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// libusb failed; `libusb::Error::NoDevice` means the device was unplugged.
+    Transport(libusb::Error),
+    /// The device is missing or is not a usable Bulk-Only mass-storage device.
+    Device(String),
+    /// The Bulk-Only Transport exchange itself was malformed.
+    Protocol(String),
+    /// The scsi crate rejected a buffer or transfer.
+    Scsi(scsi::ScsiError),
+    /// A SCSI command completed with a failed status, with the sense data fetched
+    /// afterwards if the device gave any.
+    CommandFailed { opcode : u8, status : u8, sense : Option<SenseData> },
+    Partition(PartitionError),
+    Select(SelectError),
+    Filesystem(io::Error),
+    Io(io::Error),
+    /// A copy was read back and its hash did not match the original's.
+    VerifyFailed { source_hash : String, destination_hash : String },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Transport(e) => write!(f, "USB transport error: {}", e),
+            StorageError::Device(msg) => write!(f, "unusable device: {}", msg),
+            StorageError::Protocol(msg) => write!(f, "bulk-only transport protocol error: {}", msg),
+            StorageError::Scsi(e) => write!(f, "SCSI error: {:?}", e),
+            StorageError::CommandFailed { opcode, status, sense : Some(sense) } => write!(f, "SCSI command 0x{:02x} failed with status {}: {}", opcode, status, sense),
+            StorageError::CommandFailed { opcode, status, sense : None } => write!(f, "SCSI command 0x{:02x} failed with status {}", opcode, status),
+            StorageError::Partition(e) => write!(f, "partition table error: {}", e),
+            StorageError::Select(e) => write!(f, "device selection failed: {}", e),
+            StorageError::Filesystem(e) => write!(f, "filesystem error: {}", e),
+            StorageError::Io(e) => write!(f, "I/O error: {}", e),
+            StorageError::VerifyFailed { source_hash, destination_hash } => write!(f, "verification failed: source hashes to {} but destination to {}", source_hash, destination_hash),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::Transport(e) => Some(e),
+            StorageError::Partition(e) => Some(e),
+            StorageError::Select(e) => Some(e),
+            StorageError::Filesystem(e) => Some(e),
+            StorageError::Io(e) => Some(e),
+            _ => None,
         }
     }
 }
 
-impl From<String> for RawStringErr {
-    fn from(err : String) -> RawStringErr {
-        RawStringErr{
-            err
+impl StorageError {
+    /// True if the device has gone away and the handle should be torn down.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            StorageError::Transport(libusb::Error::NoDevice) => true,
+            _ => false,
+        }
+    }
+
+    pub fn sense(&self) -> Option<&SenseData> {
+        match self {
+            StorageError::CommandFailed { sense : Some(sense), .. } => Some(sense),
+            _ => None,
+        }
+    }
+
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            StorageError::Transport(e) => match e {
+                libusb::Error::NoDevice => io::ErrorKind::NotConnected,
+                libusb::Error::Timeout => io::ErrorKind::TimedOut,
+                libusb::Error::Access => io::ErrorKind::PermissionDenied,
+                libusb::Error::NotFound => io::ErrorKind::NotFound,
+                libusb::Error::Interrupted => io::ErrorKind::Interrupted,
+                libusb::Error::InvalidParam => io::ErrorKind::InvalidInput,
+                libusb::Error::Pipe => io::ErrorKind::BrokenPipe,
+                _ => io::ErrorKind::Other,
+            },
+            StorageError::Device(_) => io::ErrorKind::NotFound,
+            StorageError::Protocol(_) | StorageError::VerifyFailed { .. } => io::ErrorKind::InvalidData,
+            StorageError::Partition(PartitionError::NoSuchEntry { .. }) | StorageError::Partition(PartitionError::EntryUnused { .. }) |
+            StorageError::Partition(PartitionError::NoFreeEntry) | StorageError::Partition(PartitionError::NoFreeSpace { .. }) |
+            StorageError::Partition(PartitionError::Overlap { .. }) | StorageError::Partition(PartitionError::PastEnd { .. }) |
+            StorageError::Partition(PartitionError::InvalidLayout(_)) => io::ErrorKind::InvalidInput,
+            StorageError::Partition(_) => io::ErrorKind::InvalidData,
+            StorageError::Select(SelectError::InvalidFilter(_)) => io::ErrorKind::InvalidInput,
+            StorageError::Select(_) => io::ErrorKind::NotFound,
+            StorageError::Filesystem(e) | StorageError::Io(e) => e.kind(),
+            StorageError::CommandFailed { sense : Some(sense), .. } => match sense.key {
+                sense::KEY_NOT_READY => io::ErrorKind::NotConnected,
+                sense::KEY_ILLEGAL_REQUEST => io::ErrorKind::InvalidInput,
+                sense::KEY_DATA_PROTECT => io::ErrorKind::PermissionDenied,
+                sense::KEY_MEDIUM_ERROR => io::ErrorKind::InvalidData,
+                sense::KEY_UNIT_ATTENTION | sense::KEY_ABORTED_COMMAND => io::ErrorKind::Interrupted,
+                _ => io::ErrorKind::Other,
+            },
+            StorageError::Scsi(_) | StorageError::CommandFailed { .. } => io::ErrorKind::Other,
         }
     }
 }
 
-impl <'a> From<&'a str> for RawStringErr {
-    fn from(err : &str) -> RawStringErr {
-        RawStringErr {
-            err : err.to_owned()
+impl From<libusb::Error> for StorageError {
+    fn from(obj : libusb::Error) -> StorageError {
+        StorageError::Transport(obj)
+    }
+}
+
+impl From<scsi::ScsiError> for StorageError {
+    fn from(obj : scsi::ScsiError) -> StorageError {
+        StorageError::Scsi(obj)
+    }
+}
+
+impl From<PartitionError> for StorageError {
+    fn from(obj : PartitionError) -> StorageError {
+        StorageError::Partition(obj)
+    }
+}
+
+impl From<SelectError> for StorageError {
+    fn from(obj : SelectError) -> StorageError {
+        StorageError::Select(obj)
+    }
+}
+
+impl From<mbr_nostd::MbrError> for StorageError {
+    fn from(obj : mbr_nostd::MbrError) -> StorageError {
+        StorageError::Partition(PartitionError::Mbr(obj))
+    }
+}
+
+// Block devices speak io::Error, so a StorageError that went through one
+// comes back out as itself rather than as a nested Io.
+impl From<io::Error> for StorageError {
+    fn from(err : io::Error) -> StorageError {
+        if !err.get_ref().map(|inner| inner.is::<StorageError>()).unwrap_or(false) {
+            return StorageError::Io(err);
+        }
+        let kind = err.kind();
+        match err.into_inner().map(|inner| inner.downcast::<StorageError>()) {
+            Some(Ok(storage)) => *storage,
+            _ => StorageError::Io(io::Error::from(kind)),
         }
     }
 }
 
-impl From<std::io::Error> for RawStringErr {
-    fn from(err : std::io::Error) -> RawStringErr {
-        RawStringErr {
-            err : format!("std::io::Error ({:?})", err)
+impl From<StorageError> for io::Error {
+    fn from(err : StorageError) -> io::Error {
+        match err {
+            StorageError::Io(e) => e,
+            other => io::Error::new(other.io_kind(), other),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,215 @@
+use crate::*;
+use fatfs::FatType;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LABEL_LEN : usize = 11;
+const OEM_NAME_OFFSET : u64 = 3;
+const OEM_NAME_LEN : usize = 8;
+// BPB fields needed to find the FAT32 backup boot sector.
+const BYTES_PER_SECTOR_OFFSET : usize = 11;
+const SECTORS_PER_FAT_16_OFFSET : usize = 22;
+const BACKUP_BOOT_SECTOR_OFFSET : usize = 50;
+const MAX_CLUSTER_BYTES : u32 = 64 * 1024;
+// Characters FAT doesn't allow in short names, which labels are.
+const LABEL_FORBIDDEN : &str = "\"*+,./:;<=>?[\\]|";
+
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Up to 11 characters; stored upper-cased.
+    pub label : Option<String>,
+    /// A power of two from the sector size up to 64 KiB; picked from the volume size when unset.
+    pub cluster_bytes : Option<u32>,
+    /// Picked from the volume size when unset: FAT12 under 4 MiB, FAT16 under 512 MiB, FAT32 from there on.
+    pub fat_type : Option<FatType>,
+    /// Up to 8 characters for the boot sector's OEM name field; fatfs writes "MSWIN4.1".
+    pub oem_name : Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FormatSummary {
+    pub fat_type : FatType,
+    pub cluster_bytes : u32,
+    pub total_clusters : u32,
+    pub volume_id : u32,
+}
+
+/// The MBR partition type that announces a filesystem of this FAT type, addressed by LBA.
+pub fn mbr_partition_type(fat_type : FatType) -> u8 {
+    match fat_type {
+        FatType::Fat12 => 0x01,
+        FatType::Fat16 => 0x0E,
+        FatType::Fat32 => 0x0C,
+    }
+}
+
+fn le_u16(bytes : &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+fn invalid_input(message : String) -> StorageError {
+    io::Error::new(io::ErrorKind::InvalidInput, message).into()
+}
+
+// Pads `text` with spaces to `len` bytes, refusing anything that isn't plain printable ASCII.
+fn padded_field(text : &str, len : usize, what : &str, forbidden : &str) -> Result<Vec<u8>, StorageError> {
+    if text.len() > len || text.chars().any(|c| !c.is_ascii() || c.is_ascii_control() || forbidden.contains(c)) {
+        let excluding = if forbidden.is_empty() { String::new() } else { format!(" other than {}", forbidden) };
+        return Err(invalid_input(format!("The {} \"{}\" must be at most {} printable ASCII characters{}.", what, text, len, excluding)));
+    }
+    let mut field = text.as_bytes().to_vec();
+    field.resize(len, b' ');
+    Ok(field)
+}
+
+impl FormatOptions {
+    fn to_fatfs(&self, bytes_per_sector : usize) -> Result<fatfs::FormatVolumeOptions, StorageError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let mut options = fatfs::FormatVolumeOptions::new()
+            .bytes_per_sector(bytes_per_sector as u16)
+            .volume_id(now.as_secs() as u32 ^ now.subsec_nanos());
+        if let Some(ref label) = self.label {
+            let mut bytes = [0 ; LABEL_LEN];
+            bytes.copy_from_slice(&padded_field(&label.to_uppercase(), LABEL_LEN, "volume label", LABEL_FORBIDDEN)?);
+            options = options.volume_label(bytes);
+        }
+        if let Some(cluster_bytes) = self.cluster_bytes {
+            if !cluster_bytes.is_power_of_two() || (cluster_bytes as usize) < bytes_per_sector || cluster_bytes > MAX_CLUSTER_BYTES {
+                return Err(invalid_input(format!("A cluster size of {} bytes isn't a power of two from {} to {}.", cluster_bytes, bytes_per_sector, MAX_CLUSTER_BYTES)));
+            }
+            options = options.bytes_per_cluster(cluster_bytes);
+        }
+        if let Some(fat_type) = self.fat_type {
+            options = options.fat_type(fat_type);
+        }
+        Ok(options)
+    }
+}
+
+/// Creates an empty FAT filesystem filling `volume`, usually a partition, and mounts it
+/// once to report what was made. Everything already on the volume is lost.
+pub fn format_volume<D : BlockDevice>(volume : &mut OffsetScsiDevice<D>, options : &FormatOptions) -> Result<FormatSummary, StorageError> {
+    let bytes_per_sector = volume.device().block_size();
+    if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 512 || bytes_per_sector > 4096 {
+        return Err(invalid_input(format!("FAT can't use {}-byte sectors.", bytes_per_sector)));
+    }
+    let fatfs_options = options.to_fatfs(bytes_per_sector)?;
+    let oem_name = match options.oem_name {
+        Some(ref name) => Some(padded_field(name, OEM_NAME_LEN, "OEM name", "")?),
+        None => None,
+    };
+    volume.seek(SeekFrom::Start(0))?;
+    fatfs::format_volume(&mut *volume, fatfs_options).map_err(StorageError::Filesystem)?;
+    if let Some(oem_name) = oem_name {
+        write_oem_name(volume, &oem_name)?;
+    }
+    volume.flush()?;
+
+    volume.seek(SeekFrom::Start(0))?;
+    let fs = fatfs::FileSystem::new(&mut *volume, fatfs::FsOptions::new()).map_err(StorageError::Filesystem)?;
+    let stats = fs.stats().map_err(StorageError::Filesystem)?;
+    let summary = FormatSummary {
+        fat_type : fs.fat_type(),
+        cluster_bytes : stats.cluster_size(),
+        total_clusters : stats.total_clusters(),
+        volume_id : fs.volume_id(),
+    };
+    fs.unmount().map_err(StorageError::Filesystem)?;
+    Ok(summary)
+}
+
+// fatfs has no option for the OEM name, so it's patched into the boot sector afterwards,
+// and into FAT32's backup boot sector too so the two stay identical.
+fn write_oem_name<D : BlockDevice>(volume : &mut OffsetScsiDevice<D>, oem_name : &[u8]) -> io::Result<()> {
+    let mut boot = [0 ; 512];
+    volume.seek(SeekFrom::Start(0))?;
+    volume.read_exact(&mut boot)?;
+    let mut boot_sectors = vec![0];
+    // Only FAT32 leaves the 16-bit FAT size at zero.
+    if le_u16(&boot[SECTORS_PER_FAT_16_OFFSET ..]) == 0 {
+        let bytes_per_sector = le_u16(&boot[BYTES_PER_SECTOR_OFFSET ..]) as u64;
+        let backup = le_u16(&boot[BACKUP_BOOT_SECTOR_OFFSET ..]) as u64;
+        if backup != 0 {
+            boot_sectors.push(backup * bytes_per_sector);
+        }
+    }
+    for offset in boot_sectors {
+        volume.seek(SeekFrom::Start(offset + OEM_NAME_OFFSET))?;
+        volume.write_all(oem_name)?;
+    }
+    Ok(())
+}
+
+/// Replaces the partition table of `disk` with a fresh MBR holding one FAT32 partition
+/// that fills the device from the first 1 MiB boundary, then formats that partition.
+/// Any GPT header at LBA 1 is wiped so the device isn't mistaken for a GPT disk afterwards.
+pub fn prepare_stick<D : BlockDevice + ?Sized>(disk : &mut D, options : &FormatOptions) -> Result<FormatSummary, StorageError> {
+    let mut options = options.clone();
+    let fat_type = *options.fat_type.get_or_insert(FatType::Fat32);
+    let mut table = mbr::MbrTable::empty(&*disk);
+    let index = table.create(mbr_partition_type(fat_type), None, None)?;
+    let entry = table.entries()[index];
+    if disk.block_count() > 1 {
+        disk.write_blocks(1, &vec![0 ; disk.block_size()])?;
+    }
+    table.write(&mut *disk)?;
+
+    let block_size = disk.block_size() as u64;
+    let mut volume = OffsetScsiDevice::with_length(&mut *disk, entry.start_lba as u64 * block_size, entry.sector_count as u64 * block_size);
+    let summary = format_volume(&mut volume, &options)?;
+    volume.close()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_field_rejects_unusable_text() {
+        assert_eq!(padded_field("USB", OEM_NAME_LEN, "OEM name", "").unwrap(), b"USB     ".to_vec());
+        assert_eq!(padded_field("MY STICK 01", LABEL_LEN, "volume label", LABEL_FORBIDDEN).unwrap(), b"MY STICK 01".to_vec());
+
+        assert!(padded_field("A/B", LABEL_LEN, "volume label", LABEL_FORBIDDEN).is_err());
+        assert!(padded_field("TWELVE CHARS", LABEL_LEN, "volume label", LABEL_FORBIDDEN).is_err());
+        assert!(padded_field("ÉTÉ", OEM_NAME_LEN, "OEM name", "").is_err());
+        assert!(padded_field("TAB\t", OEM_NAME_LEN, "OEM name", "").is_err());
+    }
+
+    #[test]
+    fn invalid_cluster_sizes_are_rejected() {
+        let with_clusters = |cluster_bytes| FormatOptions { cluster_bytes : Some(cluster_bytes), ..FormatOptions::default() };
+        assert!(with_clusters(4096).to_fatfs(512).is_ok());
+        assert!(with_clusters(MAX_CLUSTER_BYTES).to_fatfs(4096).is_ok());
+        for &cluster_bytes in &[0, 1536, 256, 2 * MAX_CLUSTER_BYTES] {
+            assert!(with_clusters(cluster_bytes).to_fatfs(512).is_err(), "{} byte clusters", cluster_bytes);
+        }
+        assert!(with_clusters(2048).to_fatfs(4096).is_err());
+    }
+
+    #[test]
+    fn oem_name_is_written_to_both_fat32_boot_sectors() {
+        // Enough 512-byte clusters that the volume has to be FAT32.
+        let mut disk = RamDisk::new(512, 80 * 1024);
+        let options = FormatOptions {
+            cluster_bytes : Some(512),
+            fat_type : Some(FatType::Fat32),
+            oem_name : Some("USBTOOL".to_string()),
+            ..FormatOptions::default()
+        };
+        {
+            let len = disk.byte_len();
+            let mut volume = OffsetScsiDevice::with_length(&mut disk, 0, len);
+            let summary = format_volume(&mut volume, &options).unwrap();
+            assert_eq!(summary.fat_type, FatType::Fat32);
+            volume.close().unwrap();
+        }
+        let bytes = disk.into_inner();
+        let backup = le_u16(&bytes[BACKUP_BOOT_SECTOR_OFFSET ..]) as usize;
+        assert_ne!(backup, 0);
+        for &sector in &[0, backup] {
+            let start = sector * 512 + OEM_NAME_OFFSET as usize;
+            assert_eq!(&bytes[start .. start + OEM_NAME_LEN], b"USBTOOL ");
+        }
+    }
+}
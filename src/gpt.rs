@@ -0,0 +1,375 @@
+use crate::*;
+use std::fmt;
+
+const GPT_SIGNATURE : &'static [u8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE : usize = 92;
+const GPT_MAX_ENTRIES_BYTES : usize = 1 << 20;
+const PROTECTIVE_MBR_TYPE : u8 = 0xEE;
+
+pub fn crc32(data : &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn le_u64(bytes : &[u8]) -> u64 {
+    bot::le_u32(&bytes[0..4]) as u64 | (bot::le_u32(&bytes[4..8]) as u64) << 32
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Default)]
+pub struct Guid(pub [u8 ; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15])
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+// Partition type GUIDs worth naming when listing partitions.
+const KNOWN_TYPES : &'static [(&'static str, &'static str)] = &[
+    ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "EFI System"),
+    ("024DEE41-33E7-11D3-9D69-0008C781F39F", "MBR partition scheme"),
+    ("21686148-6449-6E6F-744E-656564454649", "BIOS boot"),
+    ("E3C9E316-0B5C-4DB8-817D-F92DF00215AE", "Microsoft reserved"),
+    ("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", "Microsoft basic data"),
+    ("DE94BBA4-06D1-4D40-A16A-BFD50179D6AC", "Windows recovery"),
+    ("0FC63DAF-8483-4772-8E79-3D69D8477DE4", "Linux filesystem"),
+    ("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F", "Linux swap"),
+    ("E6D6D379-F507-44C2-A23C-238F2A3DF928", "Linux LVM"),
+    ("48465300-0000-11AA-AA11-00306543ECAC", "Apple HFS+"),
+    ("7C3457EF-0000-11AA-AA11-00306543ECAC", "Apple APFS"),
+];
+
+#[derive(Debug, Clone)]
+pub struct GptHeader {
+    pub revision : u32,
+    pub header_size : u32,
+    pub current_lba : u64,
+    pub backup_lba : u64,
+    pub first_usable_lba : u64,
+    pub last_usable_lba : u64,
+    pub disk_guid : Guid,
+    pub entries_lba : u64,
+    pub num_entries : u32,
+    pub entry_size : u32,
+    pub entries_crc32 : u32,
+}
+
+impl GptHeader {
+    pub fn from_bytes(block : &[u8]) -> Result<GptHeader, PartitionError> {
+        if block.len() < GPT_MIN_HEADER_SIZE || &block[0..8] != GPT_SIGNATURE {
+            return Err(PartitionError::InvalidGpt("header signature not found".to_owned()));
+        }
+        let header_size = bot::le_u32(&block[12..16]);
+        if (header_size as usize) < GPT_MIN_HEADER_SIZE || header_size as usize > block.len() {
+            return Err(PartitionError::InvalidGpt(format!("header size {}", header_size)));
+        }
+        let stored_crc = bot::le_u32(&block[16..20]);
+        let mut header_bytes = block[.. header_size as usize].to_vec();
+        for byte in &mut header_bytes[16..20] {
+            *byte = 0;
+        }
+        let actual_crc = crc32(&header_bytes);
+        if stored_crc != actual_crc {
+            return Err(PartitionError::GptCrcMismatch { what : "header", stored : stored_crc, computed : actual_crc });
+        }
+        let mut disk_guid = Guid::default();
+        disk_guid.0.copy_from_slice(&block[56..72]);
+        let header = GptHeader {
+            revision : bot::le_u32(&block[8..12]),
+            header_size,
+            current_lba : le_u64(&block[24..32]),
+            backup_lba : le_u64(&block[32..40]),
+            first_usable_lba : le_u64(&block[40..48]),
+            last_usable_lba : le_u64(&block[48..56]),
+            disk_guid,
+            entries_lba : le_u64(&block[72..80]),
+            num_entries : bot::le_u32(&block[80..84]),
+            entry_size : bot::le_u32(&block[84..88]),
+            entries_crc32 : bot::le_u32(&block[88..92]),
+        };
+        if header.first_usable_lba > header.last_usable_lba {
+            return Err(PartitionError::InvalidGpt(format!("usable LBA range {}..={} is empty", header.first_usable_lba, header.last_usable_lba)));
+        }
+        if header.entry_size < 128 || header.entry_size % 8 != 0 {
+            return Err(PartitionError::InvalidGpt(format!("entry size {}", header.entry_size)));
+        }
+        if header.entries_bytes() > GPT_MAX_ENTRIES_BYTES {
+            return Err(PartitionError::InvalidGpt(format!("entry array of {} bytes is unreasonably large", header.entries_bytes())));
+        }
+        Ok(header)
+    }
+
+    pub fn entries_bytes(&self) -> usize {
+        self.num_entries as usize * self.entry_size as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GptPartition {
+    pub index : usize,
+    pub type_guid : Guid,
+    pub unique_guid : Guid,
+    pub first_lba : u64,
+    pub last_lba : u64,
+    pub attributes : u64,
+    pub name : String,
+}
+
+impl GptPartition {
+    fn from_bytes(index : usize, entry : &[u8]) -> GptPartition {
+        let mut type_guid = Guid::default();
+        type_guid.0.copy_from_slice(&entry[0..16]);
+        let mut unique_guid = Guid::default();
+        unique_guid.0.copy_from_slice(&entry[16..32]);
+        let name_units : Vec<u16> = entry[56..128].chunks(2)
+            .map(|pair| pair[0] as u16 | (pair[1] as u16) << 8)
+            .take_while(|unit| *unit != 0)
+            .collect();
+        GptPartition {
+            index,
+            type_guid,
+            unique_guid,
+            first_lba : le_u64(&entry[32..40]),
+            last_lba : le_u64(&entry[40..48]),
+            attributes : le_u64(&entry[48..56]),
+            name : String::from_utf16_lossy(&name_units),
+        }
+    }
+
+    pub fn type_name(&self) -> Option<&'static str> {
+        let type_str = self.type_guid.to_string();
+        KNOWN_TYPES.iter().find(|(guid, _)| *guid == type_str).map(|(_, name)| *name)
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.last_lba + 1 - self.first_lba
+    }
+
+    pub fn byte_offset(&self, block_size : usize) -> u64 {
+        self.first_lba * block_size as u64
+    }
+
+    pub fn open<D : BlockDevice>(&self, device : D) -> OffsetScsiDevice<D> {
+        let offset = self.byte_offset(device.block_size());
+        let len = self.block_count() * device.block_size() as u64;
+        OffsetScsiDevice::with_length(device, offset, len)
+    }
+}
+
+impl fmt::Display for GptPartition {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GPT #{}: \"{}\" type {} ({}), guid {}, LBA {}..={} ({} blocks), attributes {:#x}",
+            self.index, self.name, self.type_guid, self.type_name().unwrap_or("unknown"), self.unique_guid,
+            self.first_lba, self.last_lba, self.block_count(), self.attributes)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gpt {
+    pub header : GptHeader,
+    pub partitions : Vec<GptPartition>,
+    pub used_backup : bool,
+}
+
+pub fn has_protective_mbr(mbr : &[u8]) -> bool {
+    mbr.len() >= 512 && (0..4).any(|idx| mbr[446 + 16 * idx + 4] == PROTECTIVE_MBR_TYPE)
+}
+
+fn read_header<D : BlockDevice>(device : &mut D, header_lba : u64) -> Result<GptHeader, StorageError> {
+    let mut block = vec![0 ; device.block_size()];
+    device.read_blocks(header_lba, &mut block)?;
+    let header = GptHeader::from_bytes(&block)?;
+    if header.current_lba != header_lba {
+        return Err(PartitionError::InvalidGpt(format!("header at LBA {} claims to be at LBA {}", header_lba, header.current_lba)).into());
+    }
+    Ok(header)
+}
+
+fn read_entries<D : BlockDevice>(device : &mut D, header : &GptHeader) -> Result<Vec<GptPartition>, StorageError> {
+    let block_size = device.block_size();
+    let entries_blocks = (header.entries_bytes() + block_size - 1) / block_size;
+    let mut entries = vec![0 ; entries_blocks * block_size];
+    device.read_blocks(header.entries_lba, &mut entries)?;
+    let entries = &entries[.. header.entries_bytes()];
+    let actual_crc = crc32(entries);
+    if actual_crc != header.entries_crc32 {
+        return Err(PartitionError::GptCrcMismatch { what : "entry array", stored : header.entries_crc32, computed : actual_crc }.into());
+    }
+
+    // Every used entry must lie within the usable range, so `block_count` and friends can trust it.
+    let mut partitions = Vec::new();
+    for (idx, entry) in entries.chunks(header.entry_size as usize).enumerate() {
+        let part = GptPartition::from_bytes(idx, entry);
+        if part.type_guid.is_zero() {
+            continue;
+        }
+        if part.first_lba > part.last_lba {
+            return Err(PartitionError::InvalidGpt(format!("entry {} ends at LBA {} before it starts at LBA {}", idx, part.last_lba, part.first_lba)).into());
+        }
+        if part.first_lba < header.first_usable_lba || part.last_lba > header.last_usable_lba {
+            return Err(PartitionError::InvalidGpt(format!("entry {} covers LBA {}..={}, outside the usable LBA {}..={}",
+                idx, part.first_lba, part.last_lba, header.first_usable_lba, header.last_usable_lba)).into());
+        }
+        partitions.push(part);
+    }
+    Ok(partitions)
+}
+
+fn read_table<D : BlockDevice>(device : &mut D, header_lba : u64) -> Result<(GptHeader, Vec<GptPartition>), StorageError> {
+    let header = read_header(device, header_lba)?;
+    let partitions = read_entries(device, &header)?;
+    Ok((header, partitions))
+}
+
+/// Reads the primary GPT, falling back to the backup if the primary header or entry array
+/// fails validation. The backup is where a valid primary header says it is, and at the end
+/// of the disk otherwise.
+pub fn read_gpt<D : BlockDevice>(device : &mut D) -> Result<Gpt, StorageError> {
+    let (primary_err, backup_hint) = match read_header(device, 1) {
+        Ok(header) => match read_entries(device, &header) {
+            Ok(partitions) => return Ok(Gpt { header, partitions, used_backup : false }),
+            Err(e) => (e, Some(header.backup_lba).filter(|lba| *lba > 1 && *lba < device.block_count())),
+        },
+        Err(e) => (e, None),
+    };
+    eprintln!("GPT: Primary table invalid ({}). Trying backup.", primary_err);
+    let backup_lba = match backup_hint.or_else(|| device.block_count().checked_sub(1)) {
+        Some(lba) => lba,
+        None => return Err(primary_err),
+    };
+    match (primary_err, read_table(device, backup_lba)) {
+        (_, Ok((header, partitions))) => Ok(Gpt { header, partitions, used_backup : true }),
+        (StorageError::Partition(primary), Err(StorageError::Partition(backup))) => {
+            Err(PartitionError::NoValidGpt { primary : Box::new(primary), backup : Box::new(backup) }.into())
+        },
+        // Anything other than a bad table (e.g. the device failing) is reported as-is.
+        (StorageError::Partition(_), Err(other)) | (other, Err(_)) => Err(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKS : u64 = 128;
+    const ENTRIES : usize = 4;
+    const FIRST_USABLE : u64 = 34;
+    const LAST_USABLE : u64 = BLOCKS - 34;
+
+    fn entry(first_lba : u64, last_lba : u64) -> [u8 ; 128] {
+        let mut entry = [0 ; 128];
+        entry[0] = 0xAF; // any non-zero type
+        entry[32..40].copy_from_slice(&first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+        entry
+    }
+
+    // Writes a GPT header at `header_lba` pointing at `backup_lba`, with `entries` at `entries_lba`.
+    fn write_gpt(disk : &mut RamDisk, header_lba : u64, backup_lba : u64, entries_lba : u64, entries : &[[u8 ; 128]]) {
+        let mut array = vec![0 ; ENTRIES * 128];
+        for (idx, entry) in entries.iter().enumerate() {
+            array[idx * 128 .. (idx + 1) * 128].copy_from_slice(entry);
+        }
+        let mut header = vec![0 ; 512];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&bot::le_u32_bytes(0x0001_0000));
+        header[12..16].copy_from_slice(&bot::le_u32_bytes(GPT_MIN_HEADER_SIZE as u32));
+        header[24..32].copy_from_slice(&header_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&FIRST_USABLE.to_le_bytes());
+        header[48..56].copy_from_slice(&LAST_USABLE.to_le_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&bot::le_u32_bytes(ENTRIES as u32));
+        header[84..88].copy_from_slice(&bot::le_u32_bytes(128));
+        header[88..92].copy_from_slice(&bot::le_u32_bytes(crc32(&array)));
+        let crc = crc32(&header[.. GPT_MIN_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&bot::le_u32_bytes(crc));
+
+        disk.write_blocks(header_lba, &header).unwrap();
+        disk.write_blocks(entries_lba, &array).unwrap();
+    }
+
+    // A primary GPT holding `entries` at LBA 1, with the entry array at LBA 2.
+    fn disk_with(entries : &[[u8 ; 128]]) -> RamDisk {
+        let mut disk = RamDisk::new(512, BLOCKS);
+        write_gpt(&mut disk, 1, BLOCKS - 1, 2, entries);
+        disk
+    }
+
+    fn primary_error(disk : &mut RamDisk) -> String {
+        match read_gpt(disk) {
+            Err(StorageError::Partition(PartitionError::NoValidGpt { primary, .. })) => primary.to_string(),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(gpt) => panic!("accepted {:?}", gpt.partitions),
+        }
+    }
+
+    #[test]
+    fn reads_valid_entries() {
+        let mut disk = disk_with(&[entry(FIRST_USABLE, 63), [0 ; 128], entry(64, LAST_USABLE)]);
+        let gpt = read_gpt(&mut disk).unwrap();
+        assert!(!gpt.used_backup);
+        let spans : Vec<_> = gpt.partitions.iter().map(|part| (part.index, part.first_lba, part.block_count())).collect();
+        assert_eq!(spans, vec![(0, FIRST_USABLE, 30), (2, 64, LAST_USABLE - 63)]);
+    }
+
+    #[test]
+    fn rejects_reversed_entry() {
+        let mut disk = disk_with(&[entry(40, 50), entry(60, 59)]);
+        assert!(primary_error(&mut disk).contains("entry 1 ends at LBA 59"));
+        let mut disk = disk_with(&[entry(u64::max_value(), 0)]);
+        assert!(primary_error(&mut disk).contains("entry 0 ends at LBA 0"));
+    }
+
+    #[test]
+    fn rejects_entry_outside_usable_range() {
+        let mut disk = disk_with(&[entry(FIRST_USABLE - 1, 50)]);
+        assert!(primary_error(&mut disk).contains("outside the usable"));
+        let mut disk = disk_with(&[entry(40, LAST_USABLE + 1)]);
+        assert!(primary_error(&mut disk).contains("outside the usable"));
+    }
+
+    #[test]
+    fn backup_is_found_through_the_primary_header() {
+        // A disk imaged onto a bigger one: the backup isn't in the last block.
+        let mut disk = RamDisk::new(512, 2 * BLOCKS);
+        write_gpt(&mut disk, 1, BLOCKS - 1, 2, &[entry(40, 50)]);
+        write_gpt(&mut disk, BLOCKS - 1, 1, LAST_USABLE + 1, &[entry(40, 60)]);
+        // Break the primary entry array's CRC but not the header's.
+        let mut array = vec![0 ; 512];
+        disk.read_blocks(2, &mut array).unwrap();
+        array[40] ^= 1;
+        disk.write_blocks(2, &array).unwrap();
+        let gpt = read_gpt(&mut disk).unwrap();
+        assert!(gpt.used_backup);
+        assert_eq!(gpt.header.current_lba, BLOCKS - 1);
+        assert_eq!(gpt.partitions[0].last_lba, 60);
+    }
+
+    #[test]
+    fn empty_device_has_no_gpt() {
+        let mut disk = RamDisk::new(512, 0);
+        assert!(read_gpt(&mut disk).is_err());
+    }
+}
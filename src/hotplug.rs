@@ -0,0 +1,385 @@
+use crate::*;
+use libusb::Context;
+use select::{DeviceFilter, DeviceInfo};
+use std::os::raw::{c_int, c_long, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+// How often the watcher thread checks whether it has been asked to stop.
+const STOP_CHECK_INTERVAL : Duration = Duration::from_millis(100);
+
+// libusb 0.3 has no hotplug wrapper, so these come straight from libusb.h.
+// The library itself is already linked through libusb-sys.
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    pub enum libusb_context {}
+    pub enum libusb_device {}
+
+    pub type libusb_hotplug_callback_handle = c_int;
+    pub type libusb_hotplug_callback_fn = extern "C" fn(*mut libusb_context, *mut libusb_device, c_int, *mut c_void) -> c_int;
+
+    pub const LIBUSB_CAP_HAS_HOTPLUG : u32 = 0x0001;
+    pub const LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED : c_int = 0x01;
+    pub const LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT : c_int = 0x02;
+    pub const LIBUSB_HOTPLUG_MATCH_ANY : c_int = -1;
+
+    #[repr(C)]
+    pub struct timeval {
+        pub tv_sec : c_long,
+        pub tv_usec : c_long,
+    }
+
+    extern "C" {
+        pub fn libusb_init(ctx : *mut *mut libusb_context) -> c_int;
+        pub fn libusb_exit(ctx : *mut libusb_context);
+        pub fn libusb_has_capability(capability : u32) -> c_int;
+        pub fn libusb_hotplug_register_callback(
+            ctx : *mut libusb_context,
+            events : c_int,
+            flags : c_int,
+            vendor_id : c_int,
+            product_id : c_int,
+            dev_class : c_int,
+            cb_fn : libusb_hotplug_callback_fn,
+            user_data : *mut c_void,
+            handle : *mut libusb_hotplug_callback_handle,
+        ) -> c_int;
+        pub fn libusb_hotplug_deregister_callback(ctx : *mut libusb_context, handle : libusb_hotplug_callback_handle);
+        pub fn libusb_handle_events_timeout_completed(ctx : *mut libusb_context, tv : *mut timeval, completed : *mut c_int) -> c_int;
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HotplugEvent {
+    Arrived(DeviceInfo),
+    Left(DeviceInfo),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HotplugMode {
+    /// libusb hotplug callbacks trigger a rescan as soon as something changes.
+    Native,
+    /// The device list is rescanned every poll interval.
+    Polling,
+}
+
+// The callback only flags that something changed; the watcher thread works out what.
+extern "C" fn on_hotplug(_ctx : *mut ffi::libusb_context, _device : *mut ffi::libusb_device, _event : c_int, user_data : *mut c_void) -> c_int {
+    let changed = unsafe { &*(user_data as *const AtomicBool) };
+    changed.store(true, Ordering::SeqCst);
+    0
+}
+
+// A raw libusb context with our callback registered on it.
+struct NativeHotplug {
+    context : *mut ffi::libusb_context,
+    handle : ffi::libusb_hotplug_callback_handle,
+    changed : Arc<AtomicBool>,
+}
+
+impl NativeHotplug {
+    fn register() -> Option<NativeHotplug> {
+        if unsafe { ffi::libusb_has_capability(ffi::LIBUSB_CAP_HAS_HOTPLUG) } == 0 {
+            return None;
+        }
+        let mut context = ptr::null_mut();
+        if unsafe { ffi::libusb_init(&mut context) } != 0 {
+            return None;
+        }
+        let changed = Arc::new(AtomicBool::new(false));
+        let mut handle = 0;
+        let res = unsafe {
+            ffi::libusb_hotplug_register_callback(
+                context,
+                ffi::LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED | ffi::LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+                0,
+                ffi::LIBUSB_HOTPLUG_MATCH_ANY,
+                ffi::LIBUSB_HOTPLUG_MATCH_ANY,
+                // Mass storage is usually an interface class, so filtering happens on rescan.
+                ffi::LIBUSB_HOTPLUG_MATCH_ANY,
+                on_hotplug,
+                &*changed as *const AtomicBool as *mut c_void,
+                &mut handle,
+            )
+        };
+        if res != 0 {
+            unsafe { ffi::libusb_exit(context) };
+            return None;
+        }
+        Some(NativeHotplug { context, handle, changed })
+    }
+
+    // Runs libusb's event loop for up to `timeout`, returning true if a callback fired.
+    fn wait(&mut self, timeout : Duration) -> bool {
+        let mut tv = ffi::timeval {
+            tv_sec : timeout.as_secs() as c_long,
+            tv_usec : timeout.subsec_micros() as c_long,
+        };
+        unsafe { ffi::libusb_handle_events_timeout_completed(self.context, &mut tv, ptr::null_mut()) };
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Drop for NativeHotplug {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::libusb_hotplug_deregister_callback(self.context, self.handle);
+            ffi::libusb_exit(self.context);
+        }
+    }
+}
+
+fn same_device(a : &DeviceInfo, b : &DeviceInfo) -> bool {
+    a.bus == b.bus && a.address == b.address && a.vendor_id == b.vendor_id && a.product_id == b.product_id
+}
+
+// Diffs the current device list against `known`, sending an event for each change.
+// Returns false once nobody is listening anymore.
+fn rescan(context : &Context, filter : &DeviceFilter, known : &mut Vec<DeviceInfo>, sender : &mpsc::Sender<HotplugEvent>) -> bool {
+    let current : Vec<DeviceInfo> = match select::find_devices(context, filter) {
+        Ok(found) => found.into_iter().map(|(_, info)| info).collect(),
+        Err(e) => {
+            eprintln!("HOTPLUG: Rescan failed: {}", e);
+            return true;
+        },
+    };
+    for gone in known.iter().filter(|old| !current.iter().any(|new| same_device(old, new))) {
+        if sender.send(HotplugEvent::Left(gone.clone())).is_err() {
+            return false;
+        }
+    }
+    for arrived in current.iter().filter(|new| !known.iter().any(|old| same_device(old, new))) {
+        if sender.send(HotplugEvent::Arrived(arrived.clone())).is_err() {
+            return false;
+        }
+    }
+    *known = current;
+    true
+}
+
+fn watch(filter : DeviceFilter, poll_interval : Duration, force_polling : bool, stop : Arc<AtomicBool>,
+        sender : mpsc::Sender<HotplugEvent>, started : mpsc::Sender<Result<HotplugMode, StorageError>>) {
+    let context = match Context::new() {
+        Ok(context) => context,
+        Err(e) => {
+            let _ = started.send(Err(e.into()));
+            return;
+        },
+    };
+    let mut native = if force_polling { None } else { NativeHotplug::register() };
+    let _ = started.send(Ok(if native.is_some() { HotplugMode::Native } else { HotplugMode::Polling }));
+
+    let mut known = Vec::new();
+    let mut last_scan = Instant::now();
+    if !rescan(&context, &filter, &mut known, &sender) {
+        return;
+    }
+    while !stop.load(Ordering::SeqCst) {
+        let changed = match native.as_mut() {
+            Some(native) => native.wait(STOP_CHECK_INTERVAL),
+            None => {
+                thread::sleep(STOP_CHECK_INTERVAL);
+                false
+            },
+        };
+        // Native mode still rescans on the interval in case a callback was missed.
+        if changed || last_scan.elapsed() >= poll_interval {
+            last_scan = Instant::now();
+            if !rescan(&context, &filter, &mut known, &sender) {
+                return;
+            }
+        }
+    }
+}
+
+/// Watches for mass-storage devices coming and going on a background thread.
+/// Devices already plugged in when the watcher starts are reported as arrivals.
+/// On `Left`, drop any `UsbClient` for that device and tear down its
+/// `OffsetScsiDevice`s with `discard`, since they can no longer be flushed;
+/// a `DeviceRegistry` does the latter.
+pub struct HotplugWatcher {
+    events : mpsc::Receiver<HotplugEvent>,
+    mode : HotplugMode,
+    stop : Arc<AtomicBool>,
+    thread : Option<thread::JoinHandle<()>>,
+}
+
+impl HotplugWatcher {
+    /// Uses libusb hotplug callbacks if the platform supports them, polling otherwise.
+    pub fn start(poll_interval : Duration) -> Result<HotplugWatcher, StorageError> {
+        HotplugWatcher::start_with(DeviceFilter::mass_storage(), poll_interval, false)
+    }
+
+    pub fn start_with(filter : DeviceFilter, poll_interval : Duration, force_polling : bool) -> Result<HotplugWatcher, StorageError> {
+        let (sender, events) = mpsc::channel();
+        let (started_sender, started) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("hotplug".to_owned())
+            .spawn(move || watch(filter, poll_interval, force_polling, thread_stop, sender, started_sender))?;
+        let mode = match started.recv() {
+            Ok(started) => started?,
+            Err(_) => return Err(StorageError::Device("Hotplug watcher thread exited during startup.".to_owned())),
+        };
+        Ok(HotplugWatcher { events, mode, stop, thread : Some(thread) })
+    }
+
+    pub fn mode(&self) -> HotplugMode {
+        self.mode
+    }
+
+    /// Waits up to `timeout` for the next event. `Disconnected` means the watcher thread has exited.
+    pub fn recv_timeout(&self, timeout : Duration) -> Result<HotplugEvent, mpsc::RecvTimeoutError> {
+        self.events.recv_timeout(timeout)
+    }
+
+    pub fn try_recv(&self) -> Option<HotplugEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Stops the watcher thread and waits for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("HotplugWatcher: Watcher thread panicked.");
+            }
+        }
+    }
+}
+
+impl Drop for HotplugWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The `OffsetScsiDevice`s open on each hotplugged device. Feeding it every event
+/// discards a device's partitions when it leaves, rather than letting `Drop` try to flush them.
+pub struct DeviceRegistry<D : BlockDevice> {
+    devices : Vec<(DeviceInfo, OffsetScsiDevice<D>)>,
+}
+
+impl <D : BlockDevice> DeviceRegistry<D> {
+    pub fn new() -> DeviceRegistry<D> {
+        DeviceRegistry::default()
+    }
+
+    pub fn track(&mut self, info : DeviceInfo, device : OffsetScsiDevice<D>) {
+        self.devices.push((info, device));
+    }
+
+    /// The partitions tracked for `info`, in the order they were added.
+    pub fn devices_of<'a>(&'a mut self, info : &'a DeviceInfo) -> impl Iterator<Item = &'a mut OffsetScsiDevice<D>> + 'a {
+        self.devices.iter_mut().filter(move |(tracked, _)| same_device(tracked, info)).map(|(_, device)| device)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Discards everything tracked for `info`, returning how many partitions that was.
+    pub fn remove(&mut self, info : &DeviceInfo) -> usize {
+        let mut removed = 0;
+        let mut idx = 0;
+        while idx < self.devices.len() {
+            if same_device(&self.devices[idx].0, info) {
+                self.devices.swap_remove(idx).1.discard();
+                removed += 1;
+            } else {
+                idx += 1;
+            }
+        }
+        removed
+    }
+
+    /// Tears down the device a `Left` event is about; arrivals need nothing.
+    pub fn handle(&mut self, event : &HotplugEvent) -> usize {
+        match event {
+            HotplugEvent::Left(info) => self.remove(info),
+            HotplugEvent::Arrived(_) => 0,
+        }
+    }
+}
+
+impl <D : BlockDevice> Default for DeviceRegistry<D> {
+    fn default() -> DeviceRegistry<D> {
+        DeviceRegistry { devices : Vec::new() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+
+    // A disk that has been pulled out: every write and flush fails.
+    struct Unplugged(RamDisk);
+
+    impl BlockDevice for Unplugged {
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+        fn block_count(&self) -> u64 {
+            self.0.block_count()
+        }
+        fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+            self.0.read_blocks(lba, buffer)
+        }
+        fn write_blocks(&mut self, _lba : u64, _buffer : &[u8]) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "unplugged"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "unplugged"))
+        }
+    }
+
+    fn info(address : u8) -> DeviceInfo {
+        DeviceInfo { bus : 1, address, ports : None, vendor_id : 0x1234, product_id : 0x5678, serial : None, classes : vec![0, 8] }
+    }
+
+    // A partition with unwritten data, counting failed flushes on drop.
+    fn dirty_partition(flush_errors : &Rc<Cell<u32>>) -> OffsetScsiDevice<Unplugged> {
+        let mut device = OffsetScsiDevice::new(Unplugged(RamDisk::new(512, 16)), 512);
+        let errors = flush_errors.clone();
+        device.set_drop_error_handler(move |_| errors.set(errors.get() + 1));
+        device.write_all(b"unwritten").unwrap();
+        device
+    }
+
+    #[test]
+    fn left_event_discards_that_devices_partitions() {
+        let flush_errors = Rc::new(Cell::new(0));
+        let mut registry = DeviceRegistry::new();
+        registry.track(info(3), dirty_partition(&flush_errors));
+        registry.track(info(4), dirty_partition(&flush_errors));
+        registry.track(info(3), dirty_partition(&flush_errors));
+        assert_eq!(registry.devices_of(&info(3)).count(), 2);
+
+        assert_eq!(registry.handle(&HotplugEvent::Arrived(info(5))), 0);
+        assert_eq!(registry.handle(&HotplugEvent::Left(info(3))), 2);
+        assert_eq!(registry.len(), 1);
+        assert_eq!(flush_errors.get(), 0);
+        assert_eq!(registry.handle(&HotplugEvent::Left(info(3))), 0);
+
+        // Without the event the partition is flushed on drop, which fails.
+        drop(registry);
+        assert_eq!(flush_errors.get(), 1);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::*;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io;
+
+/// How much is read or written per `BlockDevice` call. `ScsiDisk` splits this
+/// into as many READ(10)/WRITE(10) commands as its transfer limit needs.
+pub const DEFAULT_CHUNK_BYTES : usize = 1 << 20;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ImagePhase {
+    Copy,
+    Verify,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Progress {
+    pub phase : ImagePhase,
+    pub done : u64,
+    pub total : u64,
+    /// Bytes this run didn't have to move, because an earlier run it resumed from did or
+    /// the image left them out; they don't count towards the rate.
+    pub skipped : u64,
+    pub elapsed : Duration,
+}
+
+fn duration_secs(duration : Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+impl Progress {
+    /// Bytes per second moved by this run.
+    pub fn rate(&self) -> f64 {
+        let secs = duration_secs(self.elapsed);
+        if secs <= 0.0 {
+            return 0.0;
+        }
+        (self.done - self.skipped) as f64 / secs
+    }
+
+    /// How long the rest will take at the current rate, once there is a rate.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        if rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_millis(((self.total - self.done) as f64 / rate * 1000.0) as u64))
+    }
+}
+
+fn fmt_mib(bytes : f64) -> String {
+    format!("{:.1} MiB", bytes / (1 << 20) as f64)
+}
+
+fn fmt_hms(duration : Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let percent = if self.total == 0 { 100.0 } else { self.done as f64 * 100.0 / self.total as f64 };
+        write!(f, "{}: {} of {} ({:.1}%), {}/s, ETA {}",
+            if self.phase == ImagePhase::Copy { "Copying" } else { "Verifying" },
+            fmt_mib(self.done as f64), fmt_mib(self.total as f64), percent, fmt_mib(self.rate()),
+            self.eta().map(fmt_hms).unwrap_or_else(|| "unknown".to_owned()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageOptions {
+    /// Rounded down to a whole number of blocks, and at least one block.
+    pub chunk_bytes : usize,
+    /// Where to pick up an interrupted copy, in bytes; must be a multiple of the block size.
+    pub resume_from : u64,
+    /// Read both sides back afterwards and compare their SHA-256 hashes.
+    pub verify : bool,
+}
+
+impl Default for ImageOptions {
+    fn default() -> ImageOptions {
+        ImageOptions {
+            chunk_bytes : DEFAULT_CHUNK_BYTES,
+            resume_from : 0,
+            verify : false,
+        }
+    }
+}
+
+impl ImageOptions {
+    pub fn chunk_buffer(&self, block_size : usize) -> Vec<u8> {
+        vec![0 ; (self.chunk_bytes / block_size).max(1) * block_size]
+    }
+
+    fn check_resume(&self, block_size : usize, total : u64) -> io::Result<()> {
+        if self.resume_from % block_size as u64 != 0 || self.resume_from > total {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't resume at byte {}: it must be a multiple of {} no larger than {}.", self.resume_from, block_size, total)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageSummary {
+    /// Bytes copied by this run, not counting any that were resumed past.
+    pub copied : u64,
+    pub total : u64,
+    pub elapsed : Duration,
+    /// The SHA-256 both sides hashed to, if they were verified.
+    pub hash : Option<String>,
+}
+
+/// SHA-256 hashes of the same span on a device and in an image, as hex.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Hashes {
+    pub device : String,
+    pub image : String,
+}
+
+impl Hashes {
+    pub fn matches(&self) -> bool {
+        self.device == self.image
+    }
+}
+
+pub fn to_hex(bytes : &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Hashes the first `len` bytes of `device` and of `image`.
+pub fn hash_device_and_image<D, R, P>(device : &mut D, image : &mut R, len : u64, mut progress : P) -> Result<Hashes, StorageError>
+        where D : BlockDevice + ?Sized, R : Read + Seek, P : FnMut(&Progress) {
+    let block_size = device.block_size();
+    let mut device_buffer = ImageOptions::default().chunk_buffer(block_size);
+    let mut image_buffer = vec![0 ; device_buffer.len()];
+    let mut device_hash = Sha256::new();
+    let mut image_hash = Sha256::new();
+    let start = Instant::now();
+    let mut done = 0;
+    image.seek(SeekFrom::Start(0))?;
+    while done < len {
+        let count = (device_buffer.len() as u64).min(len - done) as usize;
+        // The last read from the device has to be whole blocks even if the image isn't.
+        let blocks_len = (count + block_size - 1) / block_size * block_size;
+        device.read_blocks(done / block_size as u64, &mut device_buffer[.. blocks_len])?;
+        image.read_exact(&mut image_buffer[.. count])?;
+        device_hash.input(&device_buffer[.. count]);
+        image_hash.input(&image_buffer[.. count]);
+        done += count as u64;
+        progress(&Progress { phase : ImagePhase::Verify, done, total : len, skipped : 0, elapsed : start.elapsed() });
+    }
+    Ok(Hashes {
+        device : to_hex(&device_hash.result()),
+        image : to_hex(&image_hash.result()),
+    })
+}
+
+/// Writes the first `count` bytes of `buffer` at `lba`. If that isn't a whole number of
+/// blocks, the rest of the last block is read from the device first so it keeps its contents;
+/// `buffer` needs room for the rounded-up length.
+pub fn write_padded<D : BlockDevice + ?Sized>(device : &mut D, lba : u64, buffer : &mut [u8], count : usize) -> io::Result<()> {
+    let block_size = device.block_size();
+    let blocks_len = (count + block_size - 1) / block_size * block_size;
+    if blocks_len > count {
+        let mut tail = vec![0 ; block_size];
+        device.read_blocks(lba + (blocks_len / block_size) as u64 - 1, &mut tail)?;
+        buffer[count .. blocks_len].copy_from_slice(&tail[count % block_size ..]);
+    }
+    device.write_blocks(lba, &buffer[.. blocks_len])
+}
+
+/// Copies all of `device` into `image`, starting `options.resume_from` bytes in.
+pub fn dump<D, W, P>(device : &mut D, image : &mut W, options : &ImageOptions, mut progress : P) -> Result<ImageSummary, StorageError>
+        where D : BlockDevice + ?Sized, W : Read + Write + Seek, P : FnMut(&Progress) {
+    let block_size = device.block_size();
+    let total = device.byte_len();
+    options.check_resume(block_size, total)?;
+    let mut buffer = options.chunk_buffer(block_size);
+    let start = Instant::now();
+    let mut done = options.resume_from;
+    image.seek(SeekFrom::Start(done))?;
+    while done < total {
+        let count = (buffer.len() as u64).min(total - done) as usize;
+        device.read_blocks(done / block_size as u64, &mut buffer[.. count])?;
+        image.write_all(&buffer[.. count])?;
+        done += count as u64;
+        progress(&Progress { phase : ImagePhase::Copy, done, total, skipped : options.resume_from, elapsed : start.elapsed() });
+    }
+    image.flush()?;
+    let mut summary = ImageSummary { copied : total - options.resume_from, total, elapsed : start.elapsed(), hash : None };
+    if options.verify {
+        let hashes = hash_device_and_image(device, image, total, progress)?;
+        if !hashes.matches() {
+            return Err(StorageError::VerifyFailed { source_hash : hashes.device, destination_hash : hashes.image });
+        }
+        summary.hash = Some(hashes.device);
+        summary.elapsed = start.elapsed();
+    }
+    Ok(summary)
+}
+
+/// Copies all of `image` onto the start of `device`, starting `options.resume_from` bytes in.
+/// If the image ends partway through a block, the rest of that block is left as it was.
+pub fn restore<R, D, P>(image : &mut R, device : &mut D, options : &ImageOptions, mut progress : P) -> Result<ImageSummary, StorageError>
+        where R : Read + Seek, D : BlockDevice + ?Sized, P : FnMut(&Progress) {
+    let block_size = device.block_size();
+    let total = image.seek(SeekFrom::End(0))?;
+    if total > device.byte_len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("An image of {} bytes doesn't fit on a device of {} bytes.", total, device.byte_len())).into());
+    }
+    options.check_resume(block_size, total)?;
+    let mut buffer = options.chunk_buffer(block_size);
+    let start = Instant::now();
+    let mut done = options.resume_from;
+    image.seek(SeekFrom::Start(done))?;
+    while done < total {
+        let count = (buffer.len() as u64).min(total - done) as usize;
+        image.read_exact(&mut buffer[.. count])?;
+        write_padded(device, done / block_size as u64, &mut buffer, count)?;
+        done += count as u64;
+        progress(&Progress { phase : ImagePhase::Copy, done, total, skipped : options.resume_from, elapsed : start.elapsed() });
+    }
+    device.flush()?;
+    let mut summary = ImageSummary { copied : total - options.resume_from, total, elapsed : start.elapsed(), hash : None };
+    if options.verify {
+        let hashes = hash_device_and_image(device, image, total, progress)?;
+        if !hashes.matches() {
+            return Err(StorageError::VerifyFailed { source_hash : hashes.image, destination_hash : hashes.device });
+        }
+        summary.hash = Some(hashes.image);
+        summary.elapsed = start.elapsed();
+    }
+    Ok(summary)
+}
+
+/// `dump` into the file at `path`. A fresh dump replaces the file; a resumed one
+/// needs it to already hold at least `options.resume_from` bytes.
+pub fn dump_to_file<D, P>(device : &mut D, path : &Path, options : &ImageOptions, progress : P) -> Result<ImageSummary, StorageError>
+        where D : BlockDevice + ?Sized, P : FnMut(&Progress) {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+    if options.resume_from == 0 {
+        file.set_len(0)?;
+    } else {
+        let existing = file.metadata()?.len();
+        if existing < options.resume_from {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} only holds {} bytes, so it can't be resumed at byte {}.", path.display(), existing, options.resume_from)).into());
+        }
+        // Anything past the device's length is left over from some other dump.
+        file.set_len(device.byte_len())?;
+    }
+    let summary = dump(device, &mut file, options, progress)?;
+    file.sync_all()?;
+    Ok(summary)
+}
+
+pub fn restore_from_file<D, P>(path : &Path, device : &mut D, options : &ImageOptions, progress : P) -> Result<ImageSummary, StorageError>
+        where D : BlockDevice + ?Sized, P : FnMut(&Progress) {
+    let mut file = File::open(path)?;
+    restore(&mut file, device, options, progress)
+}
@@ -0,0 +1,562 @@
+use crate::*;
+use android_sparse::{SparseReader, SparseWriter};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use image::{Hashes, ImageOptions, ImagePhase, ImageSummary, Progress};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+const GZIP_MAGIC : [u8 ; 2] = [0x1F, 0x8B];
+const ZSTD_MAGIC : [u8 ; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const ZSTD_LEVEL : i32 = 3;
+// Zero runs are detected, and Android sparse blocks sized, in units of this when the device allows it.
+const PREFERRED_GRAIN : usize = 4096;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ImageFormat {
+    Raw,
+    /// Raw, but with zero runs left as holes in the file.
+    SparseRaw,
+    Gzip,
+    Zstd,
+    AndroidSparse,
+}
+
+impl ImageFormat {
+    pub fn from_name(name : &str) -> Option<ImageFormat> {
+        match name {
+            "raw" => Some(ImageFormat::Raw),
+            "sparse" => Some(ImageFormat::SparseRaw),
+            "gzip" | "gz" => Some(ImageFormat::Gzip),
+            "zstd" | "zst" => Some(ImageFormat::Zstd),
+            "android-sparse" | "simg" => Some(ImageFormat::AndroidSparse),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format to write from a file name; anything unrecognised is raw.
+    pub fn from_path(path : &Path) -> ImageFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => ImageFormat::Gzip,
+            Some("zst") => ImageFormat::Zstd,
+            Some("simg") => ImageFormat::AndroidSparse,
+            _ => ImageFormat::Raw,
+        }
+    }
+
+    /// Works out the format of an existing image from its first bytes.
+    /// Sparse raw files can't be told apart from raw ones and read the same anyway.
+    pub fn detect(path : &Path) -> io::Result<ImageFormat> {
+        let mut magic = Vec::with_capacity(4);
+        File::open(path)?.take(4).read_to_end(&mut magic)?;
+        Ok(if magic.starts_with(&GZIP_MAGIC) {
+            ImageFormat::Gzip
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            ImageFormat::Zstd
+        } else if android_sparse::has_magic(&magic) {
+            ImageFormat::AndroidSparse
+        } else {
+            ImageFormat::Raw
+        })
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::SparseRaw => "sparse",
+            ImageFormat::Gzip => "gzip",
+            ImageFormat::Zstd => "zstd",
+            ImageFormat::AndroidSparse => "android-sparse",
+        })
+    }
+}
+
+// The unit zero runs are found in: PREFERRED_GRAIN if it is made of whole blocks
+// and divides the device, so every run is a multiple of it, and a block otherwise.
+fn zero_grain(block_size : usize, byte_len : u64) -> usize {
+    if PREFERRED_GRAIN % block_size == 0 && byte_len % PREFERRED_GRAIN as u64 == 0 {
+        PREFERRED_GRAIN
+    } else {
+        block_size
+    }
+}
+
+fn is_zero(data : &[u8]) -> bool {
+    data.iter().all(|byte| *byte == 0)
+}
+
+/// Where an export's data goes. `write_zeros` lets formats store zero runs cheaply.
+trait ImageWriter {
+    fn write_data(&mut self, data : &[u8]) -> io::Result<()>;
+    fn write_zeros(&mut self, len : u64) -> io::Result<()>;
+    fn finish(self : Box<Self>) -> io::Result<()>;
+}
+
+// A stream that needs finishing to produce valid output, e.g. to write a compression trailer.
+trait FinishStream : Write + Sized {
+    fn finish_stream(self) -> io::Result<()>;
+}
+
+impl FinishStream for File {
+    fn finish_stream(self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+// Flushes the buffer before finishing what's underneath, so a `File` is synced with everything in it.
+impl <W : FinishStream> FinishStream for io::BufWriter<W> {
+    fn finish_stream(self) -> io::Result<()> {
+        self.into_inner()?.finish_stream()
+    }
+}
+
+impl <W : FinishStream> FinishStream for GzEncoder<W> {
+    fn finish_stream(self) -> io::Result<()> {
+        self.finish()?.finish_stream()
+    }
+}
+
+impl <W : FinishStream> FinishStream for zstd::stream::write::Encoder<W> {
+    fn finish_stream(self) -> io::Result<()> {
+        self.finish()?.finish_stream()
+    }
+}
+
+struct StreamWriter<S : FinishStream>(S);
+
+impl <S : FinishStream> ImageWriter for StreamWriter<S> {
+    fn write_data(&mut self, data : &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+    fn write_zeros(&mut self, mut len : u64) -> io::Result<()> {
+        let zeros = [0 ; PREFERRED_GRAIN];
+        while len > 0 {
+            let count = (zeros.len() as u64).min(len) as usize;
+            self.0.write_all(&zeros[.. count])?;
+            len -= count as u64;
+        }
+        Ok(())
+    }
+    fn finish(self : Box<Self>) -> io::Result<()> {
+        self.0.finish_stream()
+    }
+}
+
+// Seeks over zero runs so the filesystem leaves holes, then sets the final length
+// in case the image ends with one.
+struct HoleWriter {
+    file : File,
+    pos : u64,
+}
+
+impl ImageWriter for HoleWriter {
+    fn write_data(&mut self, data : &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.pos))?;
+        self.file.write_all(data)?;
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+    fn write_zeros(&mut self, len : u64) -> io::Result<()> {
+        self.pos += len;
+        Ok(())
+    }
+    fn finish(self : Box<Self>) -> io::Result<()> {
+        self.file.set_len(self.pos)?;
+        self.file.sync_all()
+    }
+}
+
+impl ImageWriter for SparseWriter<File> {
+    fn write_data(&mut self, data : &[u8]) -> io::Result<()> {
+        SparseWriter::write_data(self, data)
+    }
+    fn write_zeros(&mut self, len : u64) -> io::Result<()> {
+        SparseWriter::write_zeros(self, len)
+    }
+    fn finish(self : Box<Self>) -> io::Result<()> {
+        SparseWriter::finish(*self)?.sync_all()
+    }
+}
+
+fn create_writer(path : &Path, format : ImageFormat, grain : usize, total : u64) -> io::Result<Box<dyn ImageWriter>> {
+    let file = File::create(path)?;
+    Ok(match format {
+        ImageFormat::Raw => Box::new(StreamWriter(io::BufWriter::new(file))),
+        ImageFormat::SparseRaw => Box::new(HoleWriter { file, pos : 0 }),
+        ImageFormat::Gzip => Box::new(StreamWriter(GzEncoder::new(file, flate2::Compression::default()))),
+        ImageFormat::Zstd => Box::new(StreamWriter(zstd::stream::write::Encoder::new(file, ZSTD_LEVEL)?)),
+        ImageFormat::AndroidSparse => {
+            let blocks = total / grain as u64;
+            if blocks > u32::max_value() as u64 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} blocks is too many for an Android sparse image.", blocks)));
+            }
+            Box::new(SparseWriter::new(file, grain as u32, blocks as u32)?)
+        },
+    })
+}
+
+/// A decoded image. Formats that can mark parts of the image as not mattering
+/// let those parts be skipped rather than read as zeros.
+pub trait ImageReader : Read {
+    /// Consumes the run of don't-care bytes at the current position, returning its length.
+    fn skip_dont_care(&mut self) -> io::Result<u64> {
+        Ok(0)
+    }
+}
+
+struct StreamReader<R : Read>(R);
+
+impl <R : Read> Read for StreamReader<R> {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl <R : Read> ImageReader for StreamReader<R> {}
+
+impl <R : Read> ImageReader for SparseReader<R> {
+    fn skip_dont_care(&mut self) -> io::Result<u64> {
+        SparseReader::skip_dont_care(self)
+    }
+}
+
+/// Counts the bytes read through it, so progress can be reported against a
+/// compressed file's length while a decoder owns the reader.
+struct CountingReader<R : Read> {
+    inner : R,
+    count : Rc<Cell<u64>>,
+}
+
+impl <R : Read> Read for CountingReader<R> {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let red = self.inner.read(buf)?;
+        self.count.set(self.count.get() + red as u64);
+        Ok(red)
+    }
+}
+
+/// An image file opened as a stream of the raw bytes it holds.
+pub struct DecodedImage {
+    pub format : ImageFormat,
+    pub stream : Box<dyn ImageReader>,
+    /// The raw length, when the format records it up front.
+    pub raw_len : Option<u64>,
+    pub file_len : u64,
+    file_read : Rc<Cell<u64>>,
+}
+
+impl DecodedImage {
+    pub fn open(path : &Path) -> io::Result<DecodedImage> {
+        let format = ImageFormat::detect(path)?;
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let file_read = Rc::new(Cell::new(0));
+        let counted = CountingReader { inner : io::BufReader::new(file), count : file_read.clone() };
+        let (stream, raw_len) : (Box<dyn ImageReader>, Option<u64>) = match format {
+            ImageFormat::Raw | ImageFormat::SparseRaw => (Box::new(StreamReader(counted)), Some(file_len)),
+            ImageFormat::Gzip => (Box::new(StreamReader(MultiGzDecoder::new(counted))), None),
+            ImageFormat::Zstd => (Box::new(StreamReader(zstd::stream::read::Decoder::new(counted)?)), None),
+            ImageFormat::AndroidSparse => {
+                let reader = SparseReader::new(counted)?;
+                let raw_len = reader.raw_len();
+                (Box::new(reader), Some(raw_len))
+            },
+        };
+        Ok(DecodedImage { format, stream, raw_len, file_len, file_read })
+    }
+
+    // Raw bytes against the raw length when it's known, file bytes against the file length when not.
+    fn progress_at(&self, phase : ImagePhase, raw_done : u64, skipped : u64, start : Instant) -> Progress {
+        let (done, total) = match self.raw_len {
+            Some(raw_len) => (raw_done, raw_len),
+            None => (self.file_read.get(), self.file_len),
+        };
+        Progress { phase, done, total, skipped, elapsed : start.elapsed() }
+    }
+}
+
+// Fills as much of `buf` as the stream has left, stopping short at a run of don't-care bytes
+// and skipping it. Returns how much was read and then how much was skipped after it.
+fn read_until_dont_care(stream : &mut dyn ImageReader, buf : &mut [u8]) -> io::Result<(usize, u64)> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let skipped = stream.skip_dont_care()?;
+        if skipped > 0 {
+            return Ok((filled, skipped));
+        }
+        match stream.read(&mut buf[filled ..]) {
+            Ok(0) => break,
+            Ok(red) => filled += red,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((filled, 0))
+}
+
+// Skipping leaves the device's own contents in place, which only works a whole block at a time.
+fn check_skip_aligned(done : u64, skipped : u64, block_size : usize) -> io::Result<()> {
+    if done % block_size as u64 != 0 || skipped % block_size as u64 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The image skips {} bytes at byte {}, which isn't on a {}-byte block boundary.", skipped, done, block_size)));
+    }
+    Ok(())
+}
+
+fn length_mismatch(len : u64, expected_len : u64) -> StorageError {
+    io::Error::new(io::ErrorKind::UnexpectedEof, format!("The image decodes to {} bytes, not the {} expected.", len, expected_len)).into()
+}
+
+fn too_big(len : u64, device_len : u64) -> StorageError {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("The image holds more than the device's {} bytes (at least {}).", device_len, len)).into()
+}
+
+/// Hashes everything `image` decodes to and the same span at the start of `device`,
+/// leaving out whatever the image skips on both sides. An image that doesn't decode
+/// to exactly `expected_len` bytes is an error, so a truncated one can't pass.
+pub fn hash_device_and_decoded<D, P>(device : &mut D, image : &mut DecodedImage, expected_len : u64, mut progress : P) -> Result<Hashes, StorageError>
+        where D : BlockDevice + ?Sized, P : FnMut(&Progress) {
+    let block_size = device.block_size();
+    let mut image_buffer = ImageOptions::default().chunk_buffer(block_size);
+    let mut device_buffer = vec![0 ; image_buffer.len()];
+    let mut device_hash = Sha256::new();
+    let mut image_hash = Sha256::new();
+    let start = Instant::now();
+    let mut done = 0;
+    let mut skipped = 0;
+    loop {
+        let (count, skip) = read_until_dont_care(&mut *image.stream, &mut image_buffer)?;
+        if count == 0 && skip == 0 {
+            break;
+        }
+        if done + count as u64 + skip > device.byte_len() {
+            return Err(too_big(done + count as u64 + skip, device.byte_len()));
+        }
+        if count > 0 {
+            let blocks_len = (count + block_size - 1) / block_size * block_size;
+            device.read_blocks(done / block_size as u64, &mut device_buffer[.. blocks_len])?;
+            device_hash.input(&device_buffer[.. count]);
+            image_hash.input(&image_buffer[.. count]);
+            done += count as u64;
+        }
+        if skip > 0 {
+            check_skip_aligned(done, skip, block_size)?;
+            done += skip;
+            skipped += skip;
+        }
+        progress(&image.progress_at(ImagePhase::Verify, done, skipped, start));
+    }
+    if done != expected_len {
+        return Err(length_mismatch(done, expected_len));
+    }
+    Ok(Hashes {
+        device : image::to_hex(&device_hash.result()),
+        image : image::to_hex(&image_hash.result()),
+    })
+}
+
+/// Writes all of `device` to `path` as `format`, one chunk at a time.
+/// Only `options.verify` and `options.chunk_bytes` apply; resuming needs a raw `image::dump`.
+pub fn export<D, P>(device : &mut D, path : &Path, format : ImageFormat, options : &ImageOptions, mut progress : P) -> Result<ImageSummary, StorageError>
+        where D : BlockDevice + ?Sized, P : FnMut(&Progress) {
+    if options.resume_from != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Only raw images can be resumed, not {}.", format)).into());
+    }
+    let block_size = device.block_size();
+    let total = device.byte_len();
+    let grain = zero_grain(block_size, total);
+    let mut writer = create_writer(path, format, grain, total)?;
+    let mut buffer = options.chunk_buffer(grain);
+    let start = Instant::now();
+    let mut done = 0;
+    while done < total {
+        let count = (buffer.len() as u64).min(total - done) as usize;
+        device.read_blocks(done / block_size as u64, &mut buffer[.. count])?;
+        let mut run_start = 0;
+        while run_start < count {
+            let grain_at = |pos : usize| &buffer[pos .. (pos + grain).min(count)];
+            let zero = is_zero(grain_at(run_start));
+            let mut run_end = run_start;
+            while run_end < count && is_zero(grain_at(run_end)) == zero {
+                run_end = (run_end + grain).min(count);
+            }
+            if zero {
+                writer.write_zeros((run_end - run_start) as u64)?;
+            } else {
+                writer.write_data(&buffer[run_start .. run_end])?;
+            }
+            run_start = run_end;
+        }
+        done += count as u64;
+        progress(&Progress { phase : ImagePhase::Copy, done, total, skipped : 0, elapsed : start.elapsed() });
+    }
+    writer.finish()?;
+    let mut summary = ImageSummary { copied : total, total, elapsed : start.elapsed(), hash : None };
+    if options.verify {
+        let hashes = hash_device_and_decoded(device, &mut DecodedImage::open(path)?, total, progress)?;
+        if !hashes.matches() {
+            return Err(StorageError::VerifyFailed { source_hash : hashes.device, destination_hash : hashes.image });
+        }
+        summary.hash = Some(hashes.device);
+        summary.elapsed = start.elapsed();
+    }
+    Ok(summary)
+}
+
+/// Writes the image at `path`, in whatever format it turns out to be, onto the start of `device`.
+/// If the image ends partway through a block, the rest of that block is left as it was,
+/// and so are the blocks under an Android sparse image's DONT_CARE chunks.
+pub fn import<D, P>(path : &Path, device : &mut D, options : &ImageOptions, mut progress : P) -> Result<ImageSummary, StorageError>
+        where D : BlockDevice + ?Sized, P : FnMut(&Progress) {
+    let mut image = DecodedImage::open(path)?;
+    if options.resume_from != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Only raw images can be resumed, not {}.", image.format)).into());
+    }
+    let device_len = device.byte_len();
+    if let Some(raw_len) = image.raw_len {
+        if raw_len > device_len {
+            return Err(too_big(raw_len, device_len));
+        }
+    }
+    let block_size = device.block_size();
+    let mut buffer = options.chunk_buffer(block_size);
+    let start = Instant::now();
+    let mut done = 0;
+    let mut skipped = 0;
+    loop {
+        let (count, skip) = read_until_dont_care(&mut *image.stream, &mut buffer)?;
+        if count == 0 && skip == 0 {
+            break;
+        }
+        if done + count as u64 + skip > device_len {
+            return Err(too_big(done + count as u64 + skip, device_len));
+        }
+        if count > 0 {
+            image::write_padded(device, done / block_size as u64, &mut buffer, count)?;
+            done += count as u64;
+        }
+        if skip > 0 {
+            check_skip_aligned(done, skip, block_size)?;
+            done += skip;
+            skipped += skip;
+        }
+        progress(&image.progress_at(ImagePhase::Copy, done, skipped, start));
+    }
+    device.flush()?;
+    if let Some(raw_len) = image.raw_len {
+        if done != raw_len {
+            return Err(length_mismatch(done, raw_len));
+        }
+    }
+    let mut summary = ImageSummary { copied : done - skipped, total : done, elapsed : start.elapsed(), hash : None };
+    if options.verify {
+        let hashes = hash_device_and_decoded(device, &mut DecodedImage::open(path)?, done, progress)?;
+        if !hashes.matches() {
+            return Err(StorageError::VerifyFailed { source_hash : hashes.image, destination_hash : hashes.device });
+        }
+        summary.hash = Some(hashes.image);
+        summary.elapsed = start.elapsed();
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_dev::tests::TempFile;
+
+    const BLOCKS : u64 = 256;
+
+    // Data at the start and in the middle, with zero runs between and at the end.
+    fn sample_disk() -> RamDisk {
+        let mut disk = RamDisk::new(512, BLOCKS);
+        let data : Vec<u8> = (0 .. 8 * 512).map(|idx| (idx % 251) as u8 + 1).collect();
+        disk.write_blocks(0, &data).unwrap();
+        disk.write_blocks(100, &data[.. 3 * 512]).unwrap();
+        disk
+    }
+
+    fn contents(disk : &mut RamDisk) -> Vec<u8> {
+        let mut bytes = vec![0 ; disk.byte_len() as usize];
+        disk.read_blocks(0, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn export_and_import_round_trip() {
+        let options = ImageOptions { chunk_bytes : 16 << 10, resume_from : 0, verify : true };
+        for &format in &[ImageFormat::Raw, ImageFormat::SparseRaw, ImageFormat::Gzip, ImageFormat::Zstd, ImageFormat::AndroidSparse] {
+            let mut source = sample_disk();
+            let expected = contents(&mut source);
+            let temp = TempFile::new(&format!("export-{}", format), 0);
+            let summary = export(&mut source, &temp.0, format, &options, |_| {}).unwrap();
+            assert_eq!(summary.copied, expected.len() as u64);
+            assert!(summary.hash.is_some());
+
+            // The trailing zeros have to reach the file too.
+            if format == ImageFormat::Raw || format == ImageFormat::SparseRaw {
+                let mut file_bytes = Vec::new();
+                File::open(&temp.0).unwrap().read_to_end(&mut file_bytes).unwrap();
+                assert!(file_bytes == expected, "{} export differs", format);
+            }
+
+            let mut target = RamDisk::from_vec(vec![0xEE ; expected.len()], 512);
+            let summary = import(&temp.0, &mut target, &options, |_| {}).unwrap();
+            assert_eq!(summary.copied, expected.len() as u64);
+            assert!(contents(&mut target) == expected, "{} import differs", format);
+        }
+    }
+
+    fn sparse_chunk(image : &mut Vec<u8>, chunk_type : u16, blocks : u32, data : &[u8]) {
+        image.extend_from_slice(&[chunk_type as u8, (chunk_type >> 8) as u8, 0, 0]);
+        image.extend_from_slice(&bot::le_u32_bytes(blocks));
+        image.extend_from_slice(&bot::le_u32_bytes(12 + data.len() as u32));
+        image.extend_from_slice(data);
+    }
+
+    #[test]
+    fn import_leaves_dont_care_blocks_alone() {
+        let data : Vec<u8> = (0 .. 4096).map(|idx| (idx % 13) as u8 + 1).collect();
+        let mut image = Vec::new();
+        image.extend_from_slice(&bot::le_u32_bytes(android_sparse::MAGIC));
+        image.extend_from_slice(&[1, 0, 0, 0, 28, 0, 12, 0]);
+        image.extend_from_slice(&bot::le_u32_bytes(4096));
+        image.extend_from_slice(&bot::le_u32_bytes(4));
+        image.extend_from_slice(&bot::le_u32_bytes(3));
+        image.extend_from_slice(&[0 ; 4]);
+        sparse_chunk(&mut image, 0xCAC1, 1, &data);
+        sparse_chunk(&mut image, 0xCAC3, 2, &[]);
+        sparse_chunk(&mut image, 0xCAC1, 1, &data);
+        let temp = TempFile::new("import-dont-care", 0);
+        File::create(&temp.0).unwrap().write_all(&image).unwrap();
+
+        let mut target = RamDisk::from_vec(vec![0xEE ; 5 * 4096], 512);
+        let options = ImageOptions { chunk_bytes : 16 << 10, resume_from : 0, verify : true };
+        let summary = import(&temp.0, &mut target, &options, |_| {}).unwrap();
+        assert_eq!((summary.copied, summary.total), (2 * 4096, 4 * 4096));
+        let mut expected = data.clone();
+        expected.extend_from_slice(&[0xEE ; 2 * 4096]);
+        expected.extend_from_slice(&data);
+        expected.extend_from_slice(&[0xEE ; 4096]);
+        assert!(contents(&mut target) == expected);
+    }
+
+    #[test]
+    fn truncated_image_fails_verification() {
+        let mut source = sample_disk();
+        let len = source.byte_len();
+        let temp = TempFile::new("verify-truncated", 0);
+        let options = ImageOptions { chunk_bytes : 16 << 10, resume_from : 0, verify : false };
+        export(&mut source, &temp.0, ImageFormat::Raw, &options, |_| {}).unwrap();
+        OpenOptions::new().write(true).open(&temp.0).unwrap().set_len(len - 512).unwrap();
+
+        let err = hash_device_and_decoded(&mut source, &mut DecodedImage::open(&temp.0).unwrap(), len, |_| {}).unwrap_err();
+        assert!(err.to_string().contains("decodes to"), "{}", err);
+        let hashes = hash_device_and_decoded(&mut source, &mut DecodedImage::open(&temp.0).unwrap(), len - 512, |_| {}).unwrap();
+        assert!(hashes.matches());
+    }
+}
@@ -0,0 +1,604 @@
+use crate::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MBR_SIGNATURE : [u8 ; 2] = [0x55, 0xAA];
+pub const PARTITION_TABLE_OFFSET : usize = 446;
+pub const PARTITION_ENTRY_SIZE : usize = 16;
+const MAX_LOGICAL_PARTITIONS : usize = 256;
+
+pub fn is_extended_type(partition_type : u8) -> bool {
+    partition_type == 0x05 || partition_type == 0x0F || partition_type == 0x85
+}
+
+/// One 16-byte partition table entry, with the LBA relative to whatever
+/// sector it was read from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RawMbrEntry {
+    pub bootable : bool,
+    pub partition_type : u8,
+    pub start_lba : u32,
+    pub sector_count : u32,
+}
+
+impl RawMbrEntry {
+    pub fn from_bytes(bytes : &[u8]) -> RawMbrEntry {
+        RawMbrEntry {
+            bootable : bytes[0] & 0x80 != 0,
+            partition_type : bytes[4],
+            start_lba : bot::le_u32(&bytes[8..12]),
+            sector_count : bot::le_u32(&bytes[12..16]),
+        }
+    }
+
+    pub fn is_unused(&self) -> bool {
+        self.partition_type == 0 || self.sector_count == 0
+    }
+
+    /// Encodes the entry, filling in CHS addresses from its LBA fields.
+    /// Unused entries encode as all zeros.
+    pub fn to_bytes(&self) -> [u8 ; PARTITION_ENTRY_SIZE] {
+        let mut bytes = [0 ; PARTITION_ENTRY_SIZE];
+        if self.is_unused() {
+            return bytes;
+        }
+        bytes[0] = if self.bootable { 0x80 } else { 0 };
+        bytes[1..4].copy_from_slice(&lba_to_chs(self.start_lba as u64));
+        bytes[4] = self.partition_type;
+        bytes[5..8].copy_from_slice(&lba_to_chs(self.start_lba as u64 + self.sector_count as u64 - 1));
+        bytes[8..12].copy_from_slice(&bot::le_u32_bytes(self.start_lba));
+        bytes[12..16].copy_from_slice(&bot::le_u32_bytes(self.sector_count));
+        bytes
+    }
+
+    fn end_lba(&self) -> u64 {
+        self.start_lba as u64 + self.sector_count as u64
+    }
+}
+
+// The geometry BIOSes have assumed for LBA-assisted translation since the 1990s.
+const CHS_HEADS : u64 = 255;
+const CHS_SECTORS : u64 = 63;
+const CHS_MAX_CYLINDER : u64 = 1023;
+
+/// The CHS address of `lba` as stored in a partition entry: head, then sector with the top
+/// two cylinder bits, then the low cylinder byte. Past cylinder 1023 it saturates to 1023/254/63.
+pub fn lba_to_chs(lba : u64) -> [u8 ; 3] {
+    let cylinder = lba / (CHS_HEADS * CHS_SECTORS);
+    if cylinder > CHS_MAX_CYLINDER {
+        return [0xFE, 0xFF, 0xFF];
+    }
+    let head = lba / CHS_SECTORS % CHS_HEADS;
+    let sector = lba % CHS_SECTORS + 1;
+    [head as u8, sector as u8 | (cylinder >> 2 & 0xC0) as u8, cylinder as u8]
+}
+
+pub fn parse_sector(sector : &[u8]) -> Result<[RawMbrEntry ; 4], PartitionError> {
+    if sector.len() < 512 || sector[510..512] != MBR_SIGNATURE {
+        return Err(PartitionError::MissingSignature);
+    }
+    let mut entries = [RawMbrEntry { bootable : false, partition_type : 0, start_lba : 0, sector_count : 0 } ; 4];
+    for (idx, entry) in entries.iter_mut().enumerate() {
+        let start = PARTITION_TABLE_OFFSET + idx * PARTITION_ENTRY_SIZE;
+        *entry = RawMbrEntry::from_bytes(&sector[start .. start + PARTITION_ENTRY_SIZE]);
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MbrPartition {
+    pub index : usize, // 0-3 are primary, 4 and up are logical in chain order
+    pub bootable : bool,
+    pub partition_type : u8,
+    pub start_lba : u64, // absolute
+    pub sector_count : u64,
+    pub logical : bool,
+}
+
+impl MbrPartition {
+    pub fn is_extended(&self) -> bool {
+        is_extended_type(self.partition_type)
+    }
+
+    pub fn byte_offset(&self, block_size : usize) -> u64 {
+        self.start_lba * block_size as u64
+    }
+
+    pub fn open<D : BlockDevice>(&self, device : D) -> OffsetScsiDevice<D> {
+        let offset = self.byte_offset(device.block_size());
+        let len = self.sector_count * device.block_size() as u64;
+        OffsetScsiDevice::with_length(device, offset, len)
+    }
+}
+
+impl fmt::Display for MbrPartition {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MBR #{} ({}): type 0x{:02x}{}{}, LBA {} + {} blocks",
+            self.index, if self.logical { "logical" } else { "primary" }, self.partition_type,
+            if self.is_extended() { " (extended)" } else { "" }, if self.bootable { ", bootable" } else { "" },
+            self.start_lba, self.sector_count)
+    }
+}
+
+/// Reads the primary table at LBA 0 and walks any extended partition's EBR chain.
+/// Extended container entries are included alongside the partitions they hold.
+pub fn read_partitions<D : BlockDevice>(device : &mut D) -> Result<Vec<MbrPartition>, StorageError> {
+    let mut sector = vec![0 ; device.block_size()];
+    device.read_blocks(0, &mut sector)?;
+    let primaries = parse_sector(&sector)?;
+
+    let mut partitions = Vec::new();
+    for (index, entry) in primaries.iter().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        partitions.push(MbrPartition {
+            index,
+            bootable : entry.bootable,
+            partition_type : entry.partition_type,
+            start_lba : entry.start_lba as u64,
+            sector_count : entry.sector_count as u64,
+            logical : false,
+        });
+    }
+
+    let extended : Vec<MbrPartition> = partitions.iter().filter(|part| part.is_extended()).cloned().collect();
+    for ext in extended {
+        read_logical_partitions(device, &ext, &mut partitions)?;
+    }
+    Ok(partitions)
+}
+
+fn read_logical_partitions<D : BlockDevice>(device : &mut D, extended : &MbrPartition, partitions : &mut Vec<MbrPartition>) -> Result<(), StorageError> {
+    let ext_start = extended.start_lba;
+    let ext_end = extended.start_lba + extended.sector_count;
+    let mut sector = vec![0 ; device.block_size()];
+    let mut visited = HashSet::new();
+    let mut ebr_lba = ext_start;
+
+    loop {
+        if !visited.insert(ebr_lba) {
+            return Err(PartitionError::EbrLoop { lba : ebr_lba }.into());
+        }
+        if visited.len() > MAX_LOGICAL_PARTITIONS {
+            return Err(PartitionError::EbrChainTooLong { limit : MAX_LOGICAL_PARTITIONS }.into());
+        }
+        if ebr_lba < ext_start || ebr_lba >= ext_end || ebr_lba >= device.block_count() {
+            return Err(PartitionError::EbrOutOfRange { lba : ebr_lba, extended_start : ext_start, extended_end : ext_end }.into());
+        }
+        device.read_blocks(ebr_lba, &mut sector)?;
+        let entries = parse_sector(&sector)?;
+
+        let logical = entries[0];
+        if !logical.is_unused() {
+            // Logical partitions are relative to their own EBR.
+            partitions.push(MbrPartition {
+                index : partitions.iter().filter(|part| part.logical).count() + 4,
+                bootable : logical.bootable,
+                partition_type : logical.partition_type,
+                start_lba : ebr_lba + logical.start_lba as u64,
+                sector_count : logical.sector_count as u64,
+                logical : true,
+            });
+        }
+
+        // Links to the next EBR are relative to the start of the extended partition.
+        let next = entries[1];
+        if next.is_unused() || !is_extended_type(next.partition_type) {
+            return Ok(());
+        }
+        ebr_lba = ext_start + next.start_lba as u64;
+    }
+}
+
+/// Partitions created or resized by `MbrTable` start and end on 1 MiB boundaries.
+pub const ALIGNMENT_BYTES : u64 = 1 << 20;
+const DISK_SIGNATURE_OFFSET : usize = 440;
+const PRIMARY_ENTRIES : usize = 4;
+
+/// An editable copy of the primary partition table in LBA 0. Boot code and the disk
+/// signature are kept; nothing reaches the device until `write`, which refuses
+/// overlapping partitions and partitions past the end of the device.
+/// Logical partitions inside an extended one aren't edited.
+pub struct MbrTable {
+    sector : Vec<u8>,
+    entries : [RawMbrEntry ; PRIMARY_ENTRIES],
+    block_count : u64,
+    alignment : u64,
+}
+
+impl MbrTable {
+    pub fn read<D : BlockDevice + ?Sized>(device : &mut D) -> Result<MbrTable, StorageError> {
+        let mut sector = vec![0 ; device.block_size()];
+        device.read_blocks(0, &mut sector)?;
+        if gpt::has_protective_mbr(&sector) {
+            return Err(PartitionError::InvalidLayout("the device has a GPT; its protective MBR can't be edited".to_owned()).into());
+        }
+        let entries = parse_sector(&sector)?;
+        Ok(MbrTable::with_sector(device, sector, entries))
+    }
+
+    /// A table with no partitions, no boot code and a fresh disk signature.
+    pub fn empty<D : BlockDevice + ?Sized>(device : &D) -> MbrTable {
+        let mut sector = vec![0 ; device.block_size()];
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let signature = now.as_secs() as u32 ^ now.subsec_nanos();
+        sector[DISK_SIGNATURE_OFFSET .. DISK_SIGNATURE_OFFSET + 4].copy_from_slice(&bot::le_u32_bytes(signature));
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+        let unused = RawMbrEntry { bootable : false, partition_type : 0, start_lba : 0, sector_count : 0 };
+        MbrTable::with_sector(device, sector, [unused ; PRIMARY_ENTRIES])
+    }
+
+    fn with_sector<D : BlockDevice + ?Sized>(device : &D, sector : Vec<u8>, entries : [RawMbrEntry ; PRIMARY_ENTRIES]) -> MbrTable {
+        MbrTable {
+            sector,
+            entries,
+            block_count : device.block_count(),
+            alignment : (ALIGNMENT_BYTES / device.block_size() as u64).max(1),
+        }
+    }
+
+    pub fn entries(&self) -> &[RawMbrEntry ; PRIMARY_ENTRIES] {
+        &self.entries
+    }
+
+    /// Partition entries can't address anything past 2^32 blocks.
+    fn addressable_end(&self) -> u64 {
+        self.block_count.min(1 << 32)
+    }
+
+    fn align_up(&self, lba : u64) -> u64 {
+        (lba + self.alignment - 1) / self.alignment * self.alignment
+    }
+
+    fn used_entry(&self, index : usize) -> Result<&RawMbrEntry, PartitionError> {
+        let entry = self.entries.get(index).ok_or(PartitionError::NoSuchEntry { index })?;
+        if entry.is_unused() {
+            return Err(PartitionError::EntryUnused { index });
+        }
+        Ok(entry)
+    }
+
+    // Where the free space after `lba` ends: the start of the next partition other than
+    // `except`, or the end of what the table can address.
+    fn free_end(&self, lba : u64, except : Option<usize>) -> u64 {
+        self.entries.iter().enumerate()
+            .filter(|(idx, entry)| Some(*idx) != except && !entry.is_unused() && entry.start_lba as u64 >= lba)
+            .map(|(_, entry)| entry.start_lba as u64)
+            .fold(self.addressable_end(), u64::min)
+    }
+
+    // Aligned starts of the gaps between partitions, in order, each with the gap's length.
+    fn free_gaps(&self) -> Vec<(u64, u64)> {
+        let mut used : Vec<(u64, u64)> = self.entries.iter().filter(|entry| !entry.is_unused())
+            .map(|entry| (entry.start_lba as u64, entry.end_lba())).collect();
+        used.sort();
+        let mut gaps = Vec::new();
+        let mut pos = self.alignment;
+        for (start, end) in used.into_iter().chain(Some((self.addressable_end(), self.addressable_end()))) {
+            let gap_start = self.align_up(pos);
+            if start > gap_start {
+                gaps.push((gap_start, start - gap_start));
+            }
+            pos = pos.max(end);
+        }
+        gaps
+    }
+
+    // Trims a requested length so the partition ends on an alignment boundary.
+    fn aligned_length(&self, start : u64, blocks : u64) -> Result<u64, PartitionError> {
+        let end = (start + blocks) / self.alignment * self.alignment;
+        if end <= start {
+            return Err(PartitionError::InvalidLayout(format!("{} blocks is less than the {}-block alignment", blocks, self.alignment)));
+        }
+        Ok(end - start)
+    }
+
+    // Applies `edit` to entry `index`, putting it back if the result doesn't validate.
+    fn try_edit<F : FnOnce(&mut RawMbrEntry)>(&mut self, index : usize, edit : F) -> Result<(), PartitionError> {
+        let old = self.entries[index];
+        edit(&mut self.entries[index]);
+        if let Err(e) = self.validate() {
+            self.entries[index] = old;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Adds a partition in the first unused entry and returns that entry's index.
+    /// `start` is rounded up to the alignment; without it the partition goes in the first
+    /// gap big enough. `blocks` is trimmed so the partition ends on a boundary; without it
+    /// the partition fills the space up to the last boundary before the next one or the end of the device.
+    pub fn create(&mut self, partition_type : u8, start : Option<u64>, blocks : Option<u64>) -> Result<usize, PartitionError> {
+        if partition_type == 0 {
+            return Err(PartitionError::InvalidLayout("type 0x00 marks an entry unused".to_owned()));
+        }
+        let index = self.entries.iter().position(|entry| entry.is_unused()).ok_or(PartitionError::NoFreeEntry)?;
+        let start = match start {
+            Some(start) => self.align_up(start.max(1)),
+            None => {
+                let fits = |&&(gap_start, len) : &&(u64, u64)| match blocks {
+                    Some(blocks) => self.aligned_length(gap_start, blocks).map(|needed| needed <= len).unwrap_or(false),
+                    None => true,
+                };
+                self.free_gaps().iter().find(fits).map(|&(gap_start, _)| gap_start)
+                    .ok_or(PartitionError::NoFreeSpace { blocks })?
+            },
+        };
+        let blocks = match blocks {
+            Some(blocks) => self.aligned_length(start, blocks)?,
+            None => self.aligned_length(start, self.free_end(start, None).saturating_sub(start))
+                .map_err(|_| PartitionError::NoFreeSpace { blocks : None })?,
+        };
+        let (start_lba, sector_count) = to_entry_fields(start, blocks)?;
+        self.try_edit(index, |entry| *entry = RawMbrEntry { bootable : false, partition_type, start_lba, sector_count })?;
+        Ok(index)
+    }
+
+    pub fn delete(&mut self, index : usize) -> Result<RawMbrEntry, PartitionError> {
+        let old = *self.used_entry(index)?;
+        self.entries[index] = RawMbrEntry { bootable : false, partition_type : 0, start_lba : 0, sector_count : 0 };
+        Ok(old)
+    }
+
+    /// Changes where partition `index` ends, keeping its start. `blocks` is trimmed as in
+    /// `create`; without it the partition grows up to the last boundary before the next one
+    /// or the end of the device.
+    pub fn resize(&mut self, index : usize, blocks : Option<u64>) -> Result<(), PartitionError> {
+        let start = self.used_entry(index)?.start_lba as u64;
+        let blocks = match blocks {
+            Some(blocks) => self.aligned_length(start, blocks)?,
+            None => self.aligned_length(start, self.free_end(start + 1, Some(index)) - start)?,
+        };
+        let (_, sector_count) = to_entry_fields(start, blocks)?;
+        self.try_edit(index, |entry| entry.sector_count = sector_count)
+    }
+
+    pub fn set_type(&mut self, index : usize, partition_type : u8) -> Result<(), PartitionError> {
+        self.used_entry(index)?;
+        if partition_type == 0 {
+            return Err(PartitionError::InvalidLayout("type 0x00 marks an entry unused; delete the partition instead".to_owned()));
+        }
+        self.entries[index].partition_type = partition_type;
+        Ok(())
+    }
+
+    /// Marking a partition bootable clears the flag on the others, since BIOSes boot the only active one.
+    pub fn set_bootable(&mut self, index : usize, bootable : bool) -> Result<(), PartitionError> {
+        self.used_entry(index)?;
+        for (idx, entry) in self.entries.iter_mut().enumerate() {
+            if idx == index {
+                entry.bootable = bootable;
+            } else if bootable {
+                entry.bootable = false;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), PartitionError> {
+        let used : Vec<(usize, &RawMbrEntry)> = self.entries.iter().enumerate().filter(|(_, entry)| !entry.is_unused()).collect();
+        for &(index, entry) in used.iter() {
+            if entry.start_lba == 0 {
+                return Err(PartitionError::InvalidLayout(format!("partition {} would cover the MBR itself", index)));
+            }
+            if entry.end_lba() > self.block_count {
+                return Err(PartitionError::PastEnd { index, end : entry.end_lba(), block_count : self.block_count });
+            }
+        }
+        for (pos, &(first, a)) in used.iter().enumerate() {
+            for &(second, b) in used[pos + 1 ..].iter() {
+                if (a.start_lba as u64) < b.end_lba() && (b.start_lba as u64) < a.end_lba() {
+                    return Err(PartitionError::Overlap { first, second });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates the table and writes it to LBA 0 of `device`.
+    pub fn write<D : BlockDevice + ?Sized>(&mut self, device : &mut D) -> Result<(), StorageError> {
+        self.validate()?;
+        if device.block_size() != self.sector.len() || device.block_count() != self.block_count {
+            return Err(PartitionError::InvalidLayout("the table was built for a different device".to_owned()).into());
+        }
+        for (idx, entry) in self.entries.iter().enumerate() {
+            let start = PARTITION_TABLE_OFFSET + idx * PARTITION_ENTRY_SIZE;
+            self.sector[start .. start + PARTITION_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+        }
+        self.sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+        device.write_blocks(0, &self.sector)?;
+        device.flush()?;
+        Ok(())
+    }
+}
+
+fn to_entry_fields(start : u64, blocks : u64) -> Result<(u32, u32), PartitionError> {
+    if start > u32::max_value() as u64 || blocks > u32::max_value() as u64 {
+        return Err(PartitionError::InvalidLayout(format!("LBA {} + {} blocks doesn't fit in an MBR entry", start, blocks)));
+    }
+    Ok((start as u32, blocks as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mbr_nostd::MasterBootRecord;
+
+    // 10 MiB and a bit, so the end of the device isn't aligned.
+    const BLOCKS : u64 = 10 * 2048 + 100;
+
+    fn table() -> (RamDisk, MbrTable) {
+        let disk = RamDisk::new(512, BLOCKS);
+        let table = MbrTable::empty(&disk);
+        (disk, table)
+    }
+
+    fn span(table : &MbrTable, index : usize) -> (u32, u32) {
+        let entry = table.entries()[index];
+        (entry.start_lba, entry.sector_count)
+    }
+
+    #[test]
+    fn create_aligns_start_and_length() {
+        let (_, mut table) = table();
+        assert_eq!(table.create(0x83, Some(3000), Some(5000)).unwrap(), 0);
+        assert_eq!(span(&table, 0), (4096, 4096));
+        // The first gap is too small for this, so it goes after the first partition.
+        assert_eq!(table.create(0x0C, None, Some(5000)).unwrap(), 1);
+        assert_eq!(span(&table, 1), (8192, 4096));
+        assert_eq!(table.create(0x0C, None, None).unwrap(), 2);
+        assert_eq!(span(&table, 2), (2048, 2048));
+        // Filling the rest stops at the last boundary, not the end of the device.
+        assert_eq!(table.create(0x07, None, None).unwrap(), 3);
+        assert_eq!(span(&table, 3), (12288, 8192));
+        match table.create(0x07, None, None) {
+            Err(PartitionError::NoFreeEntry) => (),
+            other => panic!("{:?}", other),
+        }
+        match table.create(0x83, Some(0), Some(100)) {
+            Err(PartitionError::NoFreeEntry) => (),
+            other => panic!("{:?}", other),
+        }
+        table.delete(3).unwrap();
+        match table.create(0x83, Some(12288), Some(100)) {
+            Err(PartitionError::InvalidLayout(_)) => (),
+            other => panic!("{:?}", other),
+        }
+        table.resize(1, None).unwrap();
+        assert_eq!(span(&table, 1), (8192, 12288));
+    }
+
+    #[test]
+    fn create_rejects_overlap_and_overcapacity() {
+        let (_, mut table) = table();
+        table.create(0x83, Some(2048), Some(4096)).unwrap();
+        match table.create(0x83, Some(4096), Some(4096)) {
+            Err(PartitionError::Overlap { first : 0, second : 1 }) => (),
+            other => panic!("{:?}", other),
+        }
+        match table.create(0x83, Some(18432), Some(4096)) {
+            Err(PartitionError::PastEnd { index : 1, end : 22528, block_count : BLOCKS }) => (),
+            other => panic!("{:?}", other),
+        }
+        match table.resize(0, Some(BLOCKS)) {
+            Err(PartitionError::PastEnd { index : 0, .. }) => (),
+            other => panic!("{:?}", other),
+        }
+        // Refused edits leave the table as it was.
+        assert_eq!(span(&table, 0), (2048, 4096));
+        assert!(table.entries()[1].is_unused());
+        table.validate().unwrap();
+    }
+
+    #[test]
+    fn chs_saturates_past_cylinder_1023() {
+        assert_eq!(lba_to_chs(0), [0, 1, 0]);
+        assert_eq!(lba_to_chs(2048), [32, 33, 0]);
+        // Cylinder 1022, head 254, sector 63: the cylinder's top bits go in the sector byte.
+        assert_eq!(lba_to_chs(1023 * 255 * 63 - 1), [0xFE, 0xFF, 0xFE]);
+        assert_eq!(lba_to_chs(1023 * 255 * 63), [0, 0xC1, 0xFF]);
+        assert_eq!(lba_to_chs(1024 * 255 * 63), [0xFE, 0xFF, 0xFF]);
+        assert_eq!(lba_to_chs(u32::max_value() as u64), [0xFE, 0xFF, 0xFF]);
+
+        let entry = RawMbrEntry { bootable : true, partition_type : 0x83, start_lba : 2048, sector_count : 1024 * 255 * 63 };
+        let bytes = entry.to_bytes();
+        assert_eq!(bytes[0], 0x80);
+        assert_eq!(&bytes[1..4], &[32, 33, 0]);
+        assert_eq!(&bytes[5..8], &[0xFE, 0xFF, 0xFF]);
+        assert_eq!(RawMbrEntry::from_bytes(&bytes), entry);
+    }
+
+    #[test]
+    fn written_table_reads_back() {
+        let (mut disk, mut table) = table();
+        table.create(0x0C, None, Some(4096)).unwrap();
+        table.create(0x83, None, None).unwrap();
+        table.set_bootable(1, true).unwrap();
+        table.write(&mut disk).unwrap();
+
+        let mut sector = vec![0 ; 512];
+        disk.read_blocks(0, &mut sector).unwrap();
+        let mbr = MasterBootRecord::from_bytes(&sector).unwrap();
+        let entries = mbr.partition_table_entries();
+        assert_eq!(entries[0].partition_type.to_mbr_tag_byte(), 0x0C);
+        assert_eq!((entries[0].logical_block_address, entries[0].sector_count), (2048, 4096));
+        assert_eq!(entries[1].partition_type.to_mbr_tag_byte(), 0x83);
+        assert_eq!((entries[1].logical_block_address, entries[1].sector_count), (6144, 14336));
+        assert_eq!(entries[2].sector_count, 0);
+
+        let reread = MbrTable::read(&mut disk).unwrap();
+        assert_eq!(reread.entries(), table.entries());
+        let partitions = read_partitions(&mut disk).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert!(partitions[1].bootable && !partitions[0].bootable);
+    }
+
+    fn raw(partition_type : u8, start_lba : u32, sector_count : u32) -> RawMbrEntry {
+        RawMbrEntry { bootable : false, partition_type, start_lba, sector_count }
+    }
+
+    fn write_table(disk : &mut RamDisk, lba : u64, entries : &[RawMbrEntry]) {
+        let mut sector = vec![0 ; 512];
+        for (idx, entry) in entries.iter().enumerate() {
+            let start = PARTITION_TABLE_OFFSET + idx * PARTITION_ENTRY_SIZE;
+            sector[start .. start + PARTITION_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+        }
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+        disk.write_blocks(lba, &sector).unwrap();
+    }
+
+    // A primary partition, then an extended one from LBA 4096 holding the rest of the disk.
+    fn extended_disk() -> RamDisk {
+        let mut disk = RamDisk::new(512, BLOCKS);
+        write_table(&mut disk, 0, &[raw(0x83, 2048, 2048), raw(0x05, 4096, (BLOCKS - 4096) as u32)]);
+        disk
+    }
+
+    #[test]
+    fn ebr_chain_uses_absolute_lbas() {
+        let mut disk = extended_disk();
+        // Each logical partition is relative to its EBR, each link to the extended partition.
+        write_table(&mut disk, 4096, &[raw(0x83, 63, 100), raw(0x05, 1000, 200)]);
+        write_table(&mut disk, 5096, &[raw(0x0C, 10, 50)]);
+        let partitions = read_partitions(&mut disk).unwrap();
+        let spans : Vec<(usize, u8, u64, u64, bool)> = partitions.iter()
+            .map(|part| (part.index, part.partition_type, part.start_lba, part.sector_count, part.logical)).collect();
+        assert_eq!(spans, vec![
+            (0, 0x83, 2048, 2048, false),
+            (1, 0x05, 4096, BLOCKS - 4096, false),
+            (4, 0x83, 4159, 100, true),
+            (5, 0x0C, 5106, 50, true),
+        ]);
+    }
+
+    #[test]
+    fn ebr_pointing_at_itself_is_a_loop() {
+        let mut disk = extended_disk();
+        write_table(&mut disk, 4096, &[raw(0x83, 63, 100), raw(0x05, 0, 200)]);
+        match read_partitions(&mut disk) {
+            Err(StorageError::Partition(PartitionError::EbrLoop { lba : 4096 })) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn ebr_chain_is_capped() {
+        for &(chain, ok) in &[(MAX_LOGICAL_PARTITIONS, true), (MAX_LOGICAL_PARTITIONS + 1, false)] {
+            let mut disk = extended_disk();
+            for link in 0 .. chain as u32 {
+                let next = if link + 1 < chain as u32 { raw(0x05, 2 * (link + 1), 2) } else { raw(0, 0, 0) };
+                write_table(&mut disk, 4096 + 2 * link as u64, &[raw(0x83, 1, 1), next]);
+            }
+            match read_partitions(&mut disk) {
+                Ok(ref partitions) if ok => {
+                    assert_eq!(partitions.len(), 2 + chain);
+                    assert_eq!(partitions.last().unwrap().start_lba, 4096 + 2 * (chain as u64 - 1) + 1);
+                },
+                Err(StorageError::Partition(PartitionError::EbrChainTooLong { limit : MAX_LOGICAL_PARTITIONS })) if !ok => (),
+                other => panic!("{} EBRs: {:?}", chain, other),
+            }
+        }
+    }
+}
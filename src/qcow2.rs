@@ -0,0 +1,583 @@
+use crate::*;
+use flate2::read::DeflateDecoder;
+use std::io;
+
+// See docs/interop/qcow2.txt in the QEMU tree for the layout.
+const MAGIC : u32 = 0x5146_49FB;
+const HEADER_LEN : usize = 104;
+const SECTOR_SIZE : usize = 512;
+const MIN_CLUSTER_BITS : u32 = 9;
+const MAX_CLUSTER_BITS : u32 = 21;
+
+const OFFSET_MASK : u64 = 0x00FF_FFFF_FFFF_FE00;
+const COPIED_FLAG : u64 = 1 << 63;
+const COMPRESSED_FLAG : u64 = 1 << 62;
+// Only meaningful in version 3 images.
+const ZERO_FLAG : u64 = 1;
+const INCOMPAT_DIRTY : u64 = 1;
+
+const L2_CACHE_TABLES : usize = 16;
+
+fn be_u64(bytes : &[u8]) -> u64 {
+    (bot::be_u32(&bytes[0..4]) as u64) << 32 | bot::be_u32(&bytes[4..8]) as u64
+}
+
+fn be_u64_bytes(val : u64) -> [u8 ; 8] {
+    let mut bytes = [0 ; 8];
+    bytes[0..4].copy_from_slice(&bot::be_u32_bytes((val >> 32) as u32));
+    bytes[4..8].copy_from_slice(&bot::be_u32_bytes(val as u32));
+    bytes
+}
+
+fn invalid(message : String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("QCOW2: {}", message))
+}
+
+fn unsupported(message : String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("QCOW2: {}", message))
+}
+
+pub fn has_magic(bytes : &[u8]) -> bool {
+    bytes.len() >= 4 && bot::be_u32(&bytes[0..4]) == MAGIC
+}
+
+struct CachedL2 {
+    offset : u64,
+    entries : Vec<u64>,
+    last_used : u64,
+}
+
+// Where a guest cluster's data lives.
+#[derive(Debug, Copy, Clone)]
+enum Mapping {
+    Unallocated,
+    /// Reads as zeros, possibly with a preallocated host cluster.
+    Zero(Option<u64>),
+    Data(u64),
+    Compressed { offset : u64, len : u64 },
+}
+
+/// A QCOW2 (version 2 or 3) image as a block device with 512-byte blocks.
+/// Encrypted images, backing files and non-zlib compression aren't supported.
+/// Writes allocate clusters at the end of the file and keep refcounts up to date;
+/// clusters freed by overwriting a compressed one are leaked, which `qemu-img check` tolerates.
+/// Images with snapshots are read-only.
+pub struct Qcow2Disk {
+    file : File,
+    version : u32,
+    cluster_bits : u32,
+    size : u64,
+    l1_table_offset : u64,
+    l1 : Vec<u64>,
+    // L2 tables are written through, so evicting one never loses anything.
+    l2_cache : Vec<CachedL2>,
+    l2_clock : u64,
+    refcount_table_offset : u64,
+    refcount_table : Vec<u64>,
+    refcount_order : u32,
+    // Where the next allocated cluster goes: the end of the file, rounded up to a cluster.
+    next_free : u64,
+    read_only : Option<String>,
+    // The most recently decompressed guest cluster.
+    compressed_cache : Option<(u64, Vec<u8>)>,
+}
+
+impl Qcow2Disk {
+    pub fn open<P : AsRef<Path>>(path : P) -> io::Result<Qcow2Disk> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Qcow2Disk::from_file(file)
+    }
+
+    pub fn from_file(mut file : File) -> io::Result<Qcow2Disk> {
+        let mut header = [0 ; HEADER_LEN];
+        file.seek(SeekFrom::Start(0))?;
+        let red = read_up_to(&mut file, &mut header)?;
+        if !has_magic(&header[.. red]) {
+            return Err(invalid("bad magic".to_owned()));
+        }
+        let version = bot::be_u32(&header[4..8]);
+        if version != 2 && version != 3 {
+            return Err(unsupported(format!("version {}", version)));
+        }
+        if red < if version == 2 { 72 } else { HEADER_LEN } {
+            return Err(invalid("header is truncated".to_owned()));
+        }
+        if be_u64(&header[8..16]) != 0 {
+            return Err(unsupported("images with a backing file".to_owned()));
+        }
+        let cluster_bits = bot::be_u32(&header[20..24]);
+        if cluster_bits < MIN_CLUSTER_BITS || cluster_bits > MAX_CLUSTER_BITS {
+            return Err(invalid(format!("cluster bits {}", cluster_bits)));
+        }
+        if bot::be_u32(&header[32..36]) != 0 {
+            return Err(unsupported("encrypted images".to_owned()));
+        }
+        let (incompatible, refcount_order) = if version == 3 {
+            (be_u64(&header[72..80]), bot::be_u32(&header[96..100]))
+        } else {
+            (0, 4)
+        };
+        if incompatible & INCOMPAT_DIRTY != 0 {
+            return Err(unsupported("the image is marked dirty; run `qemu-img check -r all` on it first".to_owned()));
+        }
+        if incompatible != 0 {
+            return Err(unsupported(format!("incompatible features {:#x}", incompatible)));
+        }
+        if refcount_order > 6 {
+            return Err(invalid(format!("refcount order {}", refcount_order)));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let size = be_u64(&header[24..32]);
+        let l1_size = bot::be_u32(&header[36..40]) as u64;
+        if l1_size.saturating_mul(cluster_size / 8).saturating_mul(cluster_size) < size {
+            return Err(invalid(format!("L1 table of {} entries can't map {} bytes", l1_size, size)));
+        }
+        let l1_table_offset = be_u64(&header[40..48]);
+        let refcount_table_offset = be_u64(&header[48..56]);
+        let refcount_table_clusters = bot::be_u32(&header[56..60]) as u64;
+        let nb_snapshots = bot::be_u32(&header[60..64]);
+
+        let l1 = read_table(&mut file, l1_table_offset, l1_size)?;
+        let refcount_table = read_table(&mut file, refcount_table_offset, refcount_table_clusters * cluster_size / 8)?;
+        let file_len = file.metadata()?.len();
+        let read_only = if nb_snapshots > 0 {
+            Some(format!("the image has {} snapshots", nb_snapshots))
+        } else if refcount_order < 3 {
+            Some(format!("{}-bit refcounts aren't supported for writing", 1 << refcount_order))
+        } else {
+            None
+        };
+        Ok(Qcow2Disk {
+            file,
+            version,
+            cluster_bits,
+            size,
+            l1_table_offset,
+            l1,
+            l2_cache : Vec::with_capacity(L2_CACHE_TABLES),
+            l2_clock : 0,
+            refcount_table_offset,
+            refcount_table,
+            refcount_order,
+            next_free : (file_len + cluster_size - 1) / cluster_size * cluster_size,
+            read_only,
+            compressed_cache : None,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    fn read_at(&mut self, offset : u64, buffer : &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buffer)
+    }
+
+    fn write_at(&mut self, offset : u64, buffer : &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buffer)
+    }
+
+    fn l2_table(&mut self, l2_offset : u64) -> io::Result<&mut Vec<u64>> {
+        let idx = match self.l2_cache.iter().position(|table| table.offset == l2_offset) {
+            Some(idx) => idx,
+            None => {
+                let entries = self.l2_entries();
+                let entries = read_table(&mut self.file, l2_offset, entries)?;
+                if self.l2_cache.len() >= L2_CACHE_TABLES {
+                    let victim = self.l2_cache.iter().enumerate().min_by_key(|(_, table)| table.last_used).map(|(idx, _)| idx).unwrap();
+                    self.l2_cache.swap_remove(victim);
+                }
+                self.l2_cache.push(CachedL2 { offset : l2_offset, entries, last_used : 0 });
+                self.l2_cache.len() - 1
+            },
+        };
+        self.l2_clock += 1;
+        let table = &mut self.l2_cache[idx];
+        table.last_used = self.l2_clock;
+        Ok(&mut table.entries)
+    }
+
+    fn decode_l2(&self, entry : u64) -> Mapping {
+        if entry & COMPRESSED_FLAG != 0 {
+            // The split between offset and sector count depends on the cluster size.
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry >> offset_bits) & ((1 << (self.cluster_bits - 8)) - 1)) + 1;
+            return Mapping::Compressed { offset, len : sectors * SECTOR_SIZE as u64 - (offset % SECTOR_SIZE as u64) };
+        }
+        let offset = entry & OFFSET_MASK;
+        if self.version >= 3 && entry & ZERO_FLAG != 0 {
+            Mapping::Zero(if offset != 0 { Some(offset) } else { None })
+        } else if offset == 0 {
+            Mapping::Unallocated
+        } else {
+            Mapping::Data(offset)
+        }
+    }
+
+    fn mapping(&mut self, guest_cluster : u64) -> io::Result<Mapping> {
+        let l1_idx = (guest_cluster / self.l2_entries()) as usize;
+        let l2_idx = (guest_cluster % self.l2_entries()) as usize;
+        let l2_offset = *self.l1.get(l1_idx).ok_or_else(|| invalid(format!("cluster {} is past the L1 table", guest_cluster)))? & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+        let entry = self.l2_table(l2_offset)?[l2_idx];
+        Ok(self.decode_l2(entry))
+    }
+
+    fn decompress(&mut self, guest_cluster : u64, offset : u64, len : u64) -> io::Result<&[u8]> {
+        let cached = self.compressed_cache.as_ref().map(|(cluster, _)| *cluster == guest_cluster).unwrap_or(false);
+        if !cached {
+            // The last compressed cluster's sector count can run past the end of the file.
+            let mut compressed = Vec::with_capacity(len as usize);
+            self.file.seek(SeekFrom::Start(offset))?;
+            (&mut self.file).take(len).read_to_end(&mut compressed)?;
+            let mut cluster = vec![0 ; self.cluster_size() as usize];
+            DeflateDecoder::new(&compressed[..]).read_exact(&mut cluster)
+                .map_err(|e| invalid(format!("compressed cluster {} doesn't inflate: {}", guest_cluster, e)))?;
+            self.compressed_cache = Some((guest_cluster, cluster));
+        }
+        Ok(&self.compressed_cache.as_ref().unwrap().1)
+    }
+
+    fn read_segment(&mut self, guest_cluster : u64, in_cluster : usize, out : &mut [u8]) -> io::Result<()> {
+        match self.mapping(guest_cluster)? {
+            Mapping::Unallocated | Mapping::Zero(_) => {
+                for byte in out.iter_mut() {
+                    *byte = 0;
+                }
+                Ok(())
+            },
+            Mapping::Data(host) => self.read_at(host + in_cluster as u64, out),
+            Mapping::Compressed { offset, len } => {
+                let cluster = self.decompress(guest_cluster, offset, len)?;
+                out.copy_from_slice(&cluster[in_cluster .. in_cluster + out.len()]);
+                Ok(())
+            },
+        }
+    }
+
+    fn write_segment(&mut self, guest_cluster : u64, in_cluster : usize, data : &[u8]) -> io::Result<()> {
+        if let Some(ref reason) = self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("QCOW2 image is read-only: {}.", reason)));
+        }
+        let mapping = self.mapping(guest_cluster)?;
+        if let Mapping::Data(host) = mapping {
+            return self.write_at(host + in_cluster as u64, data);
+        }
+        // Anything else gets a whole cluster of its own, starting from what it read as before.
+        let mut cluster = vec![0 ; self.cluster_size() as usize];
+        if let Mapping::Compressed { offset, len } = mapping {
+            cluster.copy_from_slice(self.decompress(guest_cluster, offset, len)?);
+        }
+        cluster[in_cluster .. in_cluster + data.len()].copy_from_slice(data);
+        let host = match mapping {
+            Mapping::Zero(Some(host)) => host,
+            _ => self.allocate_cluster()?,
+        };
+        self.write_at(host, &cluster)?;
+        self.set_l2_entry(guest_cluster, host | COPIED_FLAG)?;
+        if self.compressed_cache.as_ref().map(|(cached, _)| *cached == guest_cluster).unwrap_or(false) {
+            self.compressed_cache = None;
+        }
+        Ok(())
+    }
+
+    fn set_l2_entry(&mut self, guest_cluster : u64, entry : u64) -> io::Result<()> {
+        let l1_idx = (guest_cluster / self.l2_entries()) as usize;
+        let l2_idx = (guest_cluster % self.l2_entries()) as usize;
+        let mut l2_offset = self.l1[l1_idx] & OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster()?;
+            let zeros = vec![0 ; self.cluster_size() as usize];
+            self.write_at(l2_offset, &zeros)?;
+            self.l1[l1_idx] = l2_offset | COPIED_FLAG;
+            let l1_entry_offset = self.l1_table_offset + l1_idx as u64 * 8;
+            self.write_at(l1_entry_offset, &be_u64_bytes(l2_offset | COPIED_FLAG))?;
+        }
+        self.l2_table(l2_offset)?[l2_idx] = entry;
+        self.write_at(l2_offset + l2_idx as u64 * 8, &be_u64_bytes(entry))
+    }
+
+    // Claims the cluster at the end of the file. Its refcount is set before anything
+    // points at it, so a crash leaks it rather than leaving a dangling reference.
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.next_free;
+        self.next_free += self.cluster_size();
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    fn set_refcount(&mut self, host_offset : u64, value : u64) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        let refcount_bits = 1u64 << self.refcount_order;
+        let per_block = cluster_size * 8 / refcount_bits;
+        let cluster_idx = host_offset >> self.cluster_bits;
+        let table_idx = (cluster_idx / per_block) as usize;
+        if table_idx >= self.refcount_table.len() {
+            self.grow_refcount_table(table_idx)?;
+        }
+        let mut block = self.refcount_table[table_idx] & !(SECTOR_SIZE as u64 - 1);
+        if block == 0 {
+            // A new refcount block may well have to count itself.
+            block = self.next_free;
+            self.next_free += cluster_size;
+            self.write_at(block, &vec![0 ; cluster_size as usize])?;
+            self.refcount_table[table_idx] = block;
+            let table_entry_offset = self.refcount_table_offset + table_idx as u64 * 8;
+            self.write_at(table_entry_offset, &be_u64_bytes(block))?;
+            self.set_refcount(block, 1)?;
+        }
+        let entry_bytes = (refcount_bits / 8) as usize;
+        let pos = block + (cluster_idx % per_block) * entry_bytes as u64;
+        self.write_at(pos, &be_u64_bytes(value)[8 - entry_bytes ..])
+    }
+
+    // Moves the refcount table to the end of the file with room for at least entry `needed`.
+    // Doubling leaves far more spare coverage than the new table and its refcount blocks take up,
+    // so counting them never has to grow the table again. The header only switches over once
+    // the new table is complete; until then a crash leaks clusters but loses nothing.
+    fn grow_refcount_table(&mut self, needed : usize) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        let per_cluster = (cluster_size / 8) as usize;
+        let len = (needed + 1).max(self.refcount_table.len() * 2);
+        let clusters = ((len + per_cluster - 1) / per_cluster) as u64;
+        if clusters > u32::max_value() as u64 {
+            return Err(unsupported(format!("a refcount table of {} clusters", clusters)));
+        }
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = (self.refcount_table.len() * 8) as u64 / cluster_size;
+
+        let new_offset = self.next_free;
+        self.next_free += clusters * cluster_size;
+        self.refcount_table.resize(clusters as usize * per_cluster, 0);
+        self.refcount_table_offset = new_offset;
+        let mut bytes = Vec::with_capacity(self.refcount_table.len() * 8);
+        for entry in self.refcount_table.iter() {
+            bytes.extend_from_slice(&be_u64_bytes(*entry));
+        }
+        self.write_at(new_offset, &bytes)?;
+        for cluster in 0 .. clusters {
+            self.set_refcount(new_offset + cluster * cluster_size, 1)?;
+        }
+
+        let mut header = [0 ; 12];
+        header[0..8].copy_from_slice(&be_u64_bytes(new_offset));
+        header[8..12].copy_from_slice(&bot::be_u32_bytes(clusters as u32));
+        self.write_at(48, &header)?;
+        for cluster in 0 .. old_clusters {
+            self.set_refcount(old_offset + cluster * cluster_size, 0)?;
+        }
+        Ok(())
+    }
+
+    // The (guest cluster, offset into it, range of the buffer) pieces of a transfer
+    // of `len` bytes starting at `lba`.
+    fn segments(&self, lba : u64, len : usize) -> Vec<(u64, usize, std::ops::Range<usize>)> {
+        let cluster_size = self.cluster_size();
+        let mut segments = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let guest = lba * SECTOR_SIZE as u64 + pos as u64;
+            let in_cluster = (guest % cluster_size) as usize;
+            let count = (cluster_size as usize - in_cluster).min(len - pos);
+            segments.push((guest / cluster_size, in_cluster, pos .. pos + count));
+            pos += count;
+        }
+        segments
+    }
+}
+
+fn read_up_to(file : &mut File, buffer : &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled ..])? {
+            0 => break,
+            red => filled += red,
+        }
+    }
+    Ok(filled)
+}
+
+fn read_table(file : &mut File, offset : u64, entries : u64) -> io::Result<Vec<u64>> {
+    let mut bytes = vec![0 ; entries as usize * 8];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes.chunks(8).map(be_u64).collect())
+}
+
+impl BlockDevice for Qcow2Disk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+    fn block_count(&self) -> u64 {
+        self.size / SECTOR_SIZE as u64
+    }
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        for (cluster, in_cluster, range) in self.segments(lba, buffer.len()) {
+            self.read_segment(cluster, in_cluster, &mut buffer[range])?;
+        }
+        Ok(())
+    }
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        for (cluster, in_cluster, range) in self.segments(lba, buffer.len()) {
+            self.write_segment(cluster, in_cluster, &buffer[range])?;
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_dev::tests::TempFile;
+
+    const CLUSTER_BITS : u32 = 9;
+    const CLUSTER : usize = 1 << CLUSTER_BITS;
+    const SIZE : u64 = 1 << 20;
+
+    // Header, L1 table, refcount table and one refcount block, a cluster each,
+    // with the file padded out to `file_len` bytes.
+    fn new_image(name : &str, version : u32, file_len : u64) -> TempFile {
+        let temp = TempFile::new(name, file_len.max(4 * CLUSTER as u64));
+        let mut image = vec![0 ; 4 * CLUSTER];
+        image[0..4].copy_from_slice(&bot::be_u32_bytes(MAGIC));
+        image[4..8].copy_from_slice(&bot::be_u32_bytes(version));
+        image[20..24].copy_from_slice(&bot::be_u32_bytes(CLUSTER_BITS));
+        image[24..32].copy_from_slice(&be_u64_bytes(SIZE));
+        let l1_size = SIZE / (CLUSTER as u64 / 8 * CLUSTER as u64);
+        image[36..40].copy_from_slice(&bot::be_u32_bytes(l1_size as u32));
+        image[40..48].copy_from_slice(&be_u64_bytes(CLUSTER as u64));
+        image[48..56].copy_from_slice(&be_u64_bytes(2 * CLUSTER as u64));
+        image[56..60].copy_from_slice(&bot::be_u32_bytes(1));
+        if version == 3 {
+            image[96..100].copy_from_slice(&bot::be_u32_bytes(4));
+            image[100..104].copy_from_slice(&bot::be_u32_bytes(HEADER_LEN as u32));
+        }
+        image[2 * CLUSTER .. 2 * CLUSTER + 8].copy_from_slice(&be_u64_bytes(3 * CLUSTER as u64));
+        for cluster in 0 .. 4 {
+            image[3 * CLUSTER + 2 * cluster + 1] = 1;
+        }
+        let mut file = OpenOptions::new().write(true).open(&temp.0).unwrap();
+        file.write_all(&image).unwrap();
+        temp
+    }
+
+    fn pattern(len : usize, seed : u8) -> Vec<u8> {
+        (0 .. len).map(|idx| (idx as u8).wrapping_mul(13).wrapping_add(seed)).collect()
+    }
+
+    fn read(disk : &mut Qcow2Disk, lba : u64, blocks : usize) -> Vec<u8> {
+        let mut buffer = vec![0 ; blocks * SECTOR_SIZE];
+        disk.read_blocks(lba, &mut buffer).unwrap();
+        buffer
+    }
+
+    fn read_u64(path : &Path, offset : u64) -> u64 {
+        let mut file = File::open(path).unwrap();
+        let mut bytes = [0 ; 8];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut bytes).unwrap();
+        be_u64(&bytes)
+    }
+
+    fn write_u64(path : &Path, offset : u64, val : u64) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&be_u64_bytes(val)).unwrap();
+    }
+
+    #[test]
+    fn write_to_unallocated_cluster_survives_reopen() {
+        for &version in &[2, 3] {
+            let temp = new_image(&format!("qcow2-v{}", version), version, 0);
+            let data = pattern(3 * SECTOR_SIZE, version as u8);
+            {
+                let mut disk = Qcow2Disk::open(&temp.0).unwrap();
+                assert_eq!(disk.block_count(), SIZE / SECTOR_SIZE as u64);
+                assert_eq!(read(&mut disk, 100, 3), vec![0 ; 3 * SECTOR_SIZE]);
+                disk.write_blocks(100, &data).unwrap();
+                disk.flush().unwrap();
+            }
+            let mut disk = Qcow2Disk::open(&temp.0).unwrap();
+            assert_eq!(read(&mut disk, 100, 3), data);
+            assert_eq!(read(&mut disk, 99, 1), vec![0 ; SECTOR_SIZE]);
+            assert_eq!(read(&mut disk, 103, 1), vec![0 ; SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn zero_flag_cluster_reads_as_zeros() {
+        let temp = new_image("qcow2-zero", 3, 0);
+        {
+            let mut disk = Qcow2Disk::open(&temp.0).unwrap();
+            disk.write_blocks(0, &pattern(SECTOR_SIZE, 1)).unwrap();
+        }
+        let l2_offset = read_u64(&temp.0, CLUSTER as u64) & OFFSET_MASK;
+        let entry = read_u64(&temp.0, l2_offset);
+        write_u64(&temp.0, l2_offset, entry | ZERO_FLAG);
+
+        let mut disk = Qcow2Disk::open(&temp.0).unwrap();
+        assert_eq!(read(&mut disk, 0, 1), vec![0 ; SECTOR_SIZE]);
+        // A write lands in the preallocated cluster.
+        let len = std::fs::metadata(&temp.0).unwrap().len();
+        let data = pattern(SECTOR_SIZE, 2);
+        disk.write_blocks(0, &data).unwrap();
+        assert_eq!(read(&mut disk, 0, 1), data);
+        assert_eq!(std::fs::metadata(&temp.0).unwrap().len(), len);
+        assert_eq!(read_u64(&temp.0, l2_offset), (entry & OFFSET_MASK) | COPIED_FLAG);
+    }
+
+    #[test]
+    fn refcount_table_grows_when_full() {
+        // One table cluster of 16-bit refcounts covers 64 * 256 clusters; start past that.
+        let covered = (CLUSTER as u64 / 8) * (CLUSTER as u64 * 8 / 16) * CLUSTER as u64;
+        let temp = new_image("qcow2-grow", 3, covered + CLUSTER as u64);
+        let data = pattern(2 * SECTOR_SIZE, 5);
+        {
+            let mut disk = Qcow2Disk::open(&temp.0).unwrap();
+            disk.write_blocks(10, &data).unwrap();
+            assert!(disk.refcount_table.len() > CLUSTER / 8);
+        }
+        let table_offset = read_u64(&temp.0, 48);
+        assert!(table_offset > covered);
+        // The old table's cluster, the third one, is freed.
+        assert_eq!(read_u64(&temp.0, 3 * CLUSTER as u64) >> 16 & 0xFFFF, 0);
+        let mut disk = Qcow2Disk::open(&temp.0).unwrap();
+        assert_eq!(disk.refcount_table_offset, table_offset);
+        assert_eq!(read(&mut disk, 10, 2), data);
+        disk.write_blocks(2000, &data).unwrap();
+        assert_eq!(read(&mut disk, 2000, 2), data);
+    }
+
+    #[test]
+    fn l2_cache_evicts_least_recently_used() {
+        let temp = new_image("qcow2-l2-cache", 3, 0);
+        let mut disk = Qcow2Disk::open(&temp.0).unwrap();
+        // Each L2 table maps 64 clusters, 64 sectors apiece here.
+        let per_table = (CLUSTER / 8) as u64;
+        for table in 0 .. L2_CACHE_TABLES as u64 + 4 {
+            disk.write_blocks(table * per_table, &pattern(SECTOR_SIZE, table as u8)).unwrap();
+            read(&mut disk, 0, 1);
+        }
+        assert_eq!(disk.l2_cache.len(), L2_CACHE_TABLES);
+        let first = disk.l1[0] & OFFSET_MASK;
+        let second = disk.l1[1] & OFFSET_MASK;
+        assert!(disk.l2_cache.iter().any(|table| table.offset == first));
+        assert!(!disk.l2_cache.iter().any(|table| table.offset == second));
+        assert_eq!(read(&mut disk, per_table, 1), pattern(SECTOR_SIZE, 1));
+    }
+}
@@ -0,0 +1,381 @@
+use crate::*;
+use bot::{Cbw, Csw, DataDirection};
+use sense::SenseData;
+use std::fmt;
+use std::io;
+
+// READ(10)/WRITE(10) carry a 16-bit transfer length.
+pub const MAX_TRANSFER_BLOCKS_LIMIT : usize = 0xFFFF;
+pub const DEFAULT_MAX_TRANSFER_BLOCKS : usize = 128;
+
+/// A SCSI direct-access device on one LUN of a Bulk-Only Transport channel.
+/// To use several LUNs at once, give each `ScsiDisk` a clone of a `bot::SharedChannel`.
+pub struct ScsiDisk<C : bot::BotTransport> {
+    channel : C,
+    lun : u8,
+    block_size : usize,
+    block_count : u64,
+    max_transfer_blocks : usize,
+    last_csw : Option<Csw>,
+    last_sense : Option<SenseData>,
+}
+
+impl <C : bot::BotTransport> ScsiDisk<C> {
+    pub fn new(channel : C) -> Result<ScsiDisk<C>, StorageError> {
+        ScsiDisk::with_lun(channel, 0)
+    }
+
+    pub fn with_lun(mut channel : C, lun : u8) -> Result<ScsiDisk<C>, StorageError> {
+        let (block_count, block_size) = bot::read_capacity(&mut channel, lun)?;
+        Ok(ScsiDisk {
+            channel,
+            lun,
+            block_size : block_size as usize,
+            block_count,
+            max_transfer_blocks : DEFAULT_MAX_TRANSFER_BLOCKS,
+            last_csw : None,
+            last_sense : None,
+        })
+    }
+
+    pub fn lun(&self) -> u8 {
+        self.lun
+    }
+
+    pub fn last_csw(&self) -> Option<Csw> {
+        self.last_csw
+    }
+
+    /// Sense data from the most recent failed command.
+    pub fn last_sense(&self) -> Option<SenseData> {
+        self.last_sense
+    }
+
+    pub fn max_transfer_blocks(&self) -> usize {
+        self.max_transfer_blocks
+    }
+
+    pub fn set_max_transfer_blocks(&mut self, blocks : usize) {
+        self.max_transfer_blocks = blocks.max(1).min(MAX_TRANSFER_BLOCKS_LIMIT);
+    }
+
+    // Returns how many data bytes were transferred.
+    fn run(&mut self, cbw : &Cbw, data : bot::DataStage) -> io::Result<usize> {
+        let (csw, transferred) = bot::execute_data(&mut self.channel, cbw, data)?;
+        self.last_csw = Some(csw);
+        if csw.status != 0 {
+            let sense = self.fetch_sense()?;
+            return Err(StorageError::CommandFailed { opcode : cbw.cdb[0], status : csw.status, sense }.into());
+        }
+        Ok(transferred)
+    }
+
+    // A failure to fetch sense shouldn't hide the command failure, unless the device is gone.
+    fn fetch_sense(&mut self) -> Result<Option<SenseData>, StorageError> {
+        let sense = match bot::request_sense(&mut self.channel, self.lun) {
+            Ok(sense) => sense,
+            Err(e) if e.is_disconnect() => return Err(e),
+            Err(e) => {
+                eprintln!("SCSI: REQUEST SENSE failed: {}", e);
+                None
+            },
+        };
+        self.last_sense = sense;
+        Ok(sense)
+    }
+
+    fn rw10_cdb(opcode : u8, lba : u64, blocks : usize) -> io::Result<[u8 ; 10]> {
+        if lba + blocks as u64 > u32::max_value() as u64 + 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Block {} is past the range addressable by READ(10)/WRITE(10).", lba)));
+        }
+        let mut cdb = [0 ; 10];
+        cdb[0] = opcode;
+        cdb[2..6].copy_from_slice(&bot::be_u32_bytes(lba as u32));
+        cdb[7] = (blocks >> 8) as u8;
+        cdb[8] = blocks as u8;
+        Ok(cdb)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LunInfo {
+    pub lun : u8,
+    /// `None` if the LUN didn't answer INQUIRY.
+    pub inquiry : Option<bot::InquiryData>,
+    /// `(block_count, block_size)`, or `None` if the LUN has no readable medium.
+    pub capacity : Option<(u64, u32)>,
+}
+
+impl fmt::Display for LunInfo {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self.inquiry {
+            Some(ref inquiry) => write!(f, "LUN {}: {} {} {}{}", self.lun, inquiry.vendor, inquiry.product, inquiry.revision,
+                if inquiry.removable { " (removable)" } else { "" })?,
+            None => write!(f, "LUN {}: no INQUIRY data", self.lun)?,
+        }
+        match self.capacity {
+            Some((count, size)) => write!(f, ", {} blocks of {} bytes", count, size),
+            None => write!(f, ", no medium"),
+        }
+    }
+}
+
+/// Runs GET MAX LUN, then INQUIRY and READ CAPACITY on every LUN.
+/// A LUN whose INQUIRY or capacity can't be read (e.g. an empty card slot) is listed without it;
+/// only losing the device ends the enumeration early.
+pub fn enumerate_luns<C : bot::BotTransport>(channel : &mut C) -> Result<Vec<LunInfo>, StorageError> {
+    let max_lun = channel.max_lun()?;
+    let mut luns = Vec::new();
+    for lun in 0 ..= max_lun {
+        let inquiry = match bot::inquiry(channel, lun) {
+            Ok(inquiry) => Some(inquiry),
+            Err(e) if e.is_disconnect() => return Err(e),
+            Err(e) => {
+                eprintln!("SCSI: No INQUIRY data for LUN {}: {}", lun, e);
+                None
+            },
+        };
+        let capacity = match bot::read_capacity(channel, lun) {
+            Ok(capacity) => Some(capacity),
+            Err(e) if e.is_disconnect() => return Err(e),
+            Err(e) => {
+                eprintln!("SCSI: No capacity for LUN {}: {}", lun, e);
+                None
+            },
+        };
+        luns.push(LunInfo { lun, inquiry, capacity });
+    }
+    Ok(luns)
+}
+
+impl <C : bot::BotTransport> BlockDevice for ScsiDisk<C> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        let block_size = self.block_size;
+        let mut cur_lba = lba;
+        for chunk in buffer.chunks_mut(self.max_transfer_blocks * block_size) {
+            let blocks = chunk.len() / block_size;
+            let cdb = ScsiDisk::<C>::rw10_cdb(0x28, cur_lba, blocks)?;
+            let cbw = Cbw::new(self.lun, &cdb, DataDirection::In, chunk.len() as u32);
+            let chunk_len = chunk.len();
+            let red = self.run(&cbw, bot::DataStage::In(chunk))?;
+            if red < chunk_len {
+                let residue = self.last_csw.map(|csw| csw.data_residue).unwrap_or(0);
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Short read at block {}: got {} of {} bytes (residue {}).", cur_lba, red, chunk_len, residue)));
+            }
+            cur_lba += blocks as u64;
+        }
+        Ok(())
+    }
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        let block_size = self.block_size;
+        let mut cur_lba = lba;
+        for chunk in buffer.chunks(self.max_transfer_blocks * block_size) {
+            let blocks = chunk.len() / block_size;
+            let cdb = ScsiDisk::<C>::rw10_cdb(0x2A, cur_lba, blocks)?;
+            let cbw = Cbw::new(self.lun, &cdb, DataDirection::Out, chunk.len() as u32);
+            let written = self.run(&cbw, bot::DataStage::Out(chunk))?;
+            if written < chunk.len() {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, format!("Short write at block {}: device took {} of {} bytes.", cur_lba, written, chunk.len())));
+            }
+            cur_lba += blocks as u64;
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        // Plenty of sticks reject SYNCHRONIZE CACHE; that just means there is nothing to sync.
+        let cbw = Cbw::new(self.lun, &[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataDirection::None, 0);
+        let csw = bot::execute(&mut self.channel, &cbw, &mut Vec::new())?;
+        self.last_csw = Some(csw);
+        if csw.status != 0 {
+            self.fetch_sense()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKS : u64 = 64;
+
+    fn emulated() -> ScsiDisk<EmulatedTarget<RamDisk>> {
+        ScsiDisk::new(EmulatedTarget::new(RamDisk::new(512, BLOCKS))).unwrap()
+    }
+
+    fn rw10(opcode : u8, lun : u8, lba : u32, blocks : u16) -> Cbw {
+        let cdb = [opcode, 0, (lba >> 24) as u8, (lba >> 16) as u8, (lba >> 8) as u8, lba as u8, 0, (blocks >> 8) as u8, blocks as u8, 0];
+        let direction = if opcode == 0x28 { DataDirection::In } else { DataDirection::Out };
+        Cbw::new(lun, &cdb, direction, blocks as u32 * 512)
+    }
+
+    fn pattern(len : usize, seed : u8) -> Vec<u8> {
+        (0 .. len).map(|idx| (idx as u8).wrapping_mul(13).wrapping_add(seed) ^ (idx >> 9) as u8).collect()
+    }
+
+    #[test]
+    fn inquiry_and_read_capacity() {
+        let mut disk = emulated();
+        assert_eq!(disk.block_size(), 512);
+        assert_eq!(disk.block_count(), BLOCKS);
+
+        let inquiry = bot::inquiry(&mut disk.channel, 0).unwrap();
+        assert_eq!(inquiry.peripheral_type, 0);
+        assert!(inquiry.removable);
+        assert_eq!(inquiry.vendor, "RUSTEMU");
+        assert_eq!(inquiry.product, "Emulated Disk");
+        assert_eq!(inquiry.revision, "0001");
+
+        // Asking for more than the 36 bytes INQUIRY has leaves the rest as residue.
+        let cbw = Cbw::new(0, &[0x12, 0, 0, 0, 36, 0], DataDirection::In, 64);
+        let mut data = Vec::new();
+        let csw = bot::execute(&mut disk.channel, &cbw, &mut data).unwrap();
+        assert_eq!((csw.tag, csw.status, csw.data_residue), (cbw.tag, bot::CSW_STATUS_PASSED, 28));
+        assert_eq!(data.len(), 36);
+
+        let cbw = Cbw::new(0, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataDirection::In, 8);
+        let csw = bot::execute(&mut disk.channel, &cbw, &mut data).unwrap();
+        assert_eq!((csw.status, csw.data_residue), (bot::CSW_STATUS_PASSED, 0));
+        assert_eq!(data, vec![0, 0, 0, BLOCKS as u8 - 1, 0, 0, 2, 0]);
+
+        let luns = enumerate_luns(&mut disk.channel).unwrap();
+        assert_eq!(luns.len(), 1);
+        assert_eq!(luns[0].inquiry, Some(inquiry));
+        assert_eq!(luns[0].capacity, Some((BLOCKS, 512)));
+    }
+
+    // An emulated target that claims a second LUN it doesn't have.
+    struct ExtraLun(EmulatedTarget<RamDisk>);
+
+    impl scsi::CommunicationChannel for ExtraLun {
+        fn in_transfer<B : scsi::Buffer>(&mut self, buffer : &mut B) -> Result<usize, scsi::ScsiError> {
+            self.0.in_transfer(buffer)
+        }
+        fn out_transfer<B : scsi::Buffer>(&mut self, bytes : &mut B) -> Result<usize, scsi::ScsiError> {
+            self.0.out_transfer(bytes)
+        }
+    }
+
+    impl bot::BotTransport for ExtraLun {
+        fn bulk_only_reset(&mut self) -> Result<(), StorageError> {
+            self.0.bulk_only_reset()
+        }
+        fn max_lun(&mut self) -> Result<u8, StorageError> {
+            Ok(1)
+        }
+        fn read_into(&mut self, buffer : &mut [u8]) -> Result<usize, StorageError> {
+            self.0.read_into(buffer)
+        }
+        fn write_from(&mut self, buffer : &[u8]) -> Result<usize, StorageError> {
+            self.0.write_from(buffer)
+        }
+    }
+
+    #[test]
+    fn enumerate_luns_lists_failing_lun() {
+        let mut channel = ExtraLun(EmulatedTarget::new(RamDisk::new(512, BLOCKS)));
+        let luns = enumerate_luns(&mut channel).unwrap();
+        assert_eq!(luns.len(), 2);
+        assert_eq!(luns[0].inquiry.as_ref().map(|inquiry| inquiry.vendor.as_str()), Some("RUSTEMU"));
+        assert_eq!(luns[0].capacity, Some((BLOCKS, 512)));
+        assert_eq!((luns[1].lun, luns[1].inquiry.is_none(), luns[1].capacity), (1, true, None));
+        assert_eq!(luns[1].to_string(), "LUN 1: no INQUIRY data, no medium");
+    }
+
+    #[test]
+    fn read_write_round_trip() {
+        let mut disk = emulated();
+        // Small enough that both transfers are split into several commands.
+        disk.set_max_transfer_blocks(3);
+        let data = pattern(8 * 512, 1);
+        disk.write_blocks(5, &data).unwrap();
+        let csw = disk.last_csw().unwrap();
+        assert_eq!((csw.status, csw.data_residue), (bot::CSW_STATUS_PASSED, 0));
+
+        let mut back = vec![0 ; data.len()];
+        disk.read_blocks(5, &mut back).unwrap();
+        assert!(back == data);
+        let csw = disk.last_csw().unwrap();
+        assert_eq!((csw.status, csw.data_residue), (bot::CSW_STATUS_PASSED, 0));
+
+        let last = pattern(512, 2);
+        disk.write_blocks(BLOCKS - 1, &last).unwrap();
+        disk.flush().unwrap();
+        assert_eq!(disk.last_csw().unwrap().status, bot::CSW_STATUS_PASSED);
+        assert!(disk.last_sense().is_none());
+
+        let bytes = disk.channel.into_inner().into_inner();
+        assert!(bytes[.. 5 * 512].iter().all(|&b| b == 0));
+        assert!(&bytes[5 * 512 .. 13 * 512] == &data[..]);
+        assert!(&bytes[(BLOCKS as usize - 1) * 512 ..] == &last[..]);
+    }
+
+    #[test]
+    fn request_sense_after_out_of_range_lba() {
+        let mut disk = emulated();
+        // `read_blocks` refuses this itself, so send the command directly.
+        let cbw = rw10(0x28, 0, BLOCKS as u32, 1);
+        let mut buffer = vec![0 ; 512];
+        let err = disk.run(&cbw, bot::DataStage::In(&mut buffer)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let csw = disk.last_csw().unwrap();
+        assert_eq!((csw.tag, csw.status, csw.data_residue), (cbw.tag, bot::CSW_STATUS_FAILED, 512));
+        let sense = disk.last_sense().unwrap();
+        assert_eq!((sense.key, sense.asc, sense.ascq), (sense::KEY_ILLEGAL_REQUEST, 0x21, 0x00));
+
+        // A rejected write still takes the data stage but leaves the medium alone.
+        let cbw = rw10(0x2A, 0, BLOCKS as u32 - 1, 2);
+        let data = pattern(2 * 512, 3);
+        let (csw, _) = bot::execute_data(&mut disk.channel, &cbw, bot::DataStage::Out(&data)).unwrap();
+        assert_eq!((csw.status, csw.data_residue), (bot::CSW_STATUS_FAILED, 2 * 512));
+        let sense = bot::request_sense(&mut disk.channel, 0).unwrap().unwrap();
+        assert_eq!((sense.key, sense.asc), (sense::KEY_ILLEGAL_REQUEST, 0x21));
+
+        // Reading the sense clears it.
+        let sense = bot::request_sense(&mut disk.channel, 0).unwrap().unwrap();
+        assert!(sense.is_no_sense());
+        let mut back = vec![0xFF ; 512];
+        disk.read_blocks(BLOCKS - 1, &mut back).unwrap();
+        assert!(back.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn bad_lun_cbw() {
+        let mut disk = emulated();
+        let cbw = Cbw::new(1, &[0x00, 0, 0, 0, 0, 0], DataDirection::None, 0);
+        let csw = bot::execute(&mut disk.channel, &cbw, &mut Vec::new()).unwrap();
+        assert_eq!((csw.tag, csw.status, csw.data_residue), (cbw.tag, bot::CSW_STATUS_FAILED, 0));
+
+        // The padded data stage doesn't count as data.
+        let cbw = Cbw::new(1, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataDirection::In, 8);
+        let mut data = Vec::new();
+        let csw = bot::execute(&mut disk.channel, &cbw, &mut data).unwrap();
+        assert_eq!((csw.status, csw.data_residue), (bot::CSW_STATUS_FAILED, 8));
+        assert!(data.is_empty());
+
+        let cbw = rw10(0x2A, 1, 0, 1);
+        let (csw, _) = bot::execute_data(&mut disk.channel, &cbw, bot::DataStage::Out(&pattern(512, 4))).unwrap();
+        assert_eq!((csw.status, csw.data_residue), (bot::CSW_STATUS_FAILED, 512));
+
+        let sense = bot::request_sense(&mut disk.channel, 1).unwrap().unwrap();
+        assert_eq!((sense.key, sense.asc), (sense::KEY_ILLEGAL_REQUEST, 0x25));
+        let mut back = vec![0xFF ; 512];
+        disk.read_blocks(0, &mut back).unwrap();
+        assert!(back.iter().all(|&b| b == 0));
+
+        let target = disk.channel.into_inner();
+        match ScsiDisk::with_lun(EmulatedTarget::new(target), 1) {
+            Err(StorageError::CommandFailed { opcode : 0x25, sense : Some(sense), .. }) => assert_eq!(sense.asc, 0x25),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("LUN 1 shouldn't exist"),
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use crate::*;
+use libusb::{Context, Device};
+use std::fmt;
+
+pub const MASS_STORAGE_CLASS : u8 = 0x08;
+const STRING_TIMEOUT : Duration = Duration::from_secs(1);
+
+/// Which device to pick. Every field that is set has to match.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DeviceFilter {
+    pub vendor_id : Option<u16>,
+    pub product_id : Option<u16>,
+    pub serial : Option<String>,
+    pub bus : Option<u8>,
+    /// Port numbers from the root hub down, as in the "1-2.3" sysfs name's "2.3".
+    pub ports : Option<Vec<u8>>,
+    /// Matches the device class or the class of any of its interfaces.
+    pub interface_class : Option<u8>,
+}
+
+fn parse_hex_u16(flag : &str, text : &str) -> Result<u16, SelectError> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| SelectError::InvalidFilter(format!("{} expects a hex ID, got \"{}\"", flag, text)))
+}
+
+fn parse_u8(flag : &str, text : &str) -> Result<u8, SelectError> {
+    let parsed = if text.starts_with("0x") {
+        u8::from_str_radix(&text[2..], 16)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| SelectError::InvalidFilter(format!("{} expects a number from 0 to 255, got \"{}\"", flag, text)))
+}
+
+fn parse_ports(text : &str) -> Result<Vec<u8>, SelectError> {
+    text.split('.').map(|port| parse_u8("--port", port)).collect()
+}
+
+impl DeviceFilter {
+    /// Any device with a mass-storage interface.
+    pub fn mass_storage() -> DeviceFilter {
+        DeviceFilter {
+            interface_class : Some(MASS_STORAGE_CLASS),
+            ..DeviceFilter::default()
+        }
+    }
+
+    /// Pulls the selection flags (`--vid`, `--pid`, `--serial`, `--bus`, `--port`, `--class`)
+    /// out of `args`, returning the filter if any were given and the remaining arguments.
+    pub fn from_args(args : &[String]) -> Result<(Option<DeviceFilter>, Vec<String>), SelectError> {
+        let mut filter = DeviceFilter::mass_storage();
+        let mut found = false;
+        let mut rest = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let flag = arg.as_str();
+            match flag {
+                "--vid" | "--pid" | "--serial" | "--bus" | "--port" | "--class" => {},
+                _ => {
+                    rest.push(arg.clone());
+                    continue;
+                },
+            }
+            let value = iter.next().ok_or_else(|| SelectError::InvalidFilter(format!("{} needs a value", flag)))?;
+            match flag {
+                "--vid" => filter.vendor_id = Some(parse_hex_u16(flag, value)?),
+                "--pid" => filter.product_id = Some(parse_hex_u16(flag, value)?),
+                "--serial" => filter.serial = Some(value.clone()),
+                "--bus" => filter.bus = Some(parse_u8(flag, value)?),
+                "--port" => filter.ports = Some(parse_ports(value)?),
+                _ => filter.interface_class = if value == "any" { None } else { Some(parse_u8(flag, value)?) },
+            }
+            found = true;
+        }
+        Ok((if found { Some(filter) } else { None }, rest))
+    }
+
+    /// Whether `info` passes the filter. A serial filter only matches infos whose
+    /// serial has been read.
+    pub fn matches(&self, info : &DeviceInfo) -> bool {
+        self.matches_without_serial(info)
+            && self.serial.as_ref().map(|serial| Some(serial) == info.serial.as_ref()).unwrap_or(true)
+    }
+
+    fn matches_without_serial(&self, info : &DeviceInfo) -> bool {
+        self.vendor_id.map(|vid| vid == info.vendor_id).unwrap_or(true)
+            && self.product_id.map(|pid| pid == info.product_id).unwrap_or(true)
+            && self.bus.map(|bus| bus == info.bus).unwrap_or(true)
+            && self.ports.as_ref().map(|ports| Some(ports) == info.ports.as_ref()).unwrap_or(true)
+            && self.interface_class.map(|class| info.classes.contains(&class)).unwrap_or(true)
+    }
+
+    /// Picks the one candidate that matches, failing if there are none or several.
+    pub fn choose<T>(&self, candidates : Vec<(T, DeviceInfo)>) -> Result<(T, DeviceInfo), SelectError> {
+        let mut found : Vec<_> = candidates.into_iter().filter(|(_, info)| self.matches(info)).collect();
+        match found.len() {
+            0 => Err(SelectError::NoMatch { filter : self.to_string() }),
+            1 => Ok(found.remove(0)),
+            _ => Err(SelectError::MultipleMatches {
+                filter : self.to_string(),
+                matches : found.iter().map(|(_, info)| info.to_string()).collect(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for DeviceFilter {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(vid) = self.vendor_id {
+            parts.push(format!("vid {:04x}", vid));
+        }
+        if let Some(pid) = self.product_id {
+            parts.push(format!("pid {:04x}", pid));
+        }
+        if let Some(ref serial) = self.serial {
+            parts.push(format!("serial \"{}\"", serial));
+        }
+        if let Some(bus) = self.bus {
+            parts.push(format!("bus {}", bus));
+        }
+        if let Some(ref ports) = self.ports {
+            parts.push(format!("port {}", fmt_ports(ports)));
+        }
+        if let Some(class) = self.interface_class {
+            parts.push(format!("class 0x{:02x}", class));
+        }
+        if parts.is_empty() {
+            write!(f, "any device")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+fn fmt_ports(ports : &[u8]) -> String {
+    ports.iter().map(|port| port.to_string()).collect::<Vec<_>>().join(".")
+}
+
+/// What we know about a device without claiming it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeviceInfo {
+    pub bus : u8,
+    pub address : u8,
+    pub ports : Option<Vec<u8>>,
+    pub vendor_id : u16,
+    pub product_id : u16,
+    pub serial : Option<String>,
+    /// The device class followed by every interface class it offers.
+    pub classes : Vec<u8>,
+}
+
+impl DeviceInfo {
+    pub fn from_device(device : &mut Device) -> Result<DeviceInfo, StorageError> {
+        let desc = device.device_descriptor()?;
+        let mut classes = vec![desc.class_code()];
+        for config_idx in 0..desc.num_configurations() {
+            let config = match device.config_descriptor(config_idx) {
+                Ok(config) => config,
+                Err(_) => continue,
+            };
+            for interface in config.interfaces() {
+                for interface_desc in interface.descriptors() {
+                    classes.push(interface_desc.class_code());
+                }
+            }
+        }
+        Ok(DeviceInfo {
+            bus : device.bus_number(),
+            address : device.address(),
+            ports : port_chain(device.bus_number(), device.address()),
+            vendor_id : desc.vendor_id(),
+            product_id : desc.product_id(),
+            serial : None,
+            classes,
+        })
+    }
+
+    // Reading the serial means opening the device, so it's only done when a filter asks for it.
+    fn read_serial(&mut self, device : &mut Device) -> Result<Option<String>, StorageError> {
+        let desc = device.device_descriptor()?;
+        if desc.serial_number_string_index().is_none() {
+            return Ok(None);
+        }
+        let handle = device.open()?;
+        let language = match handle.read_languages(STRING_TIMEOUT)?.first() {
+            Some(language) => *language,
+            None => return Ok(None),
+        };
+        let serial = handle.read_serial_number_string(language, &desc, STRING_TIMEOUT)?;
+        self.serial = Some(serial.clone());
+        Ok(Some(serial))
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:04x} on bus {}", self.vendor_id, self.product_id, self.bus)?;
+        match self.ports {
+            Some(ref ports) => write!(f, " port {}", fmt_ports(ports))?,
+            None => write!(f, " address {}", self.address)?,
+        }
+        if let Some(ref serial) = self.serial {
+            write!(f, " serial \"{}\"", serial)?;
+        }
+        Ok(())
+    }
+}
+
+// libusb 0.3 doesn't wrap libusb_get_port_numbers, but sysfs names each device
+// "<bus>-<port>.<port>..." next to its busnum and devnum.
+#[cfg(target_os = "linux")]
+fn port_chain(bus : u8, address : u8) -> Option<Vec<u8>> {
+    let read_num = |path : PathBuf| std::fs::read_to_string(path).ok().and_then(|text| text.trim().parse::<u8>().ok());
+    for entry in std::fs::read_dir("/sys/bus/usb/devices").ok()? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Interfaces have a ':' in the name and root hubs are "usbN".
+        if name.contains(':') || !name.contains('-') {
+            continue;
+        }
+        if read_num(entry.path().join("busnum")) == Some(bus) && read_num(entry.path().join("devnum")) == Some(address) {
+            return name.splitn(2, '-').nth(1).and_then(|ports| parse_ports(ports).ok());
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn port_chain(_bus : u8, _address : u8) -> Option<Vec<u8>> {
+    None
+}
+
+/// Lists every device matching `filter`.
+pub fn find_devices<'a>(context : &'a Context, filter : &DeviceFilter) -> Result<Vec<(Device<'a>, DeviceInfo)>, StorageError> {
+    let mut found = Vec::new();
+    for mut device in context.devices()?.iter() {
+        let mut info = match DeviceInfo::from_device(&mut device) {
+            Ok(info) => info,
+            Err(_) => continue,
+        };
+        if !filter.matches_without_serial(&info) {
+            continue;
+        }
+        if filter.serial.is_some() {
+            if let Err(e) = info.read_serial(&mut device) {
+                eprintln!("SELECT: Could not read serial of {}: {}", info, e);
+                continue;
+            }
+        }
+        if filter.matches(&info) {
+            found.push((device, info));
+        }
+    }
+    Ok(found)
+}
+
+/// Finds the one device matching `filter`, failing if there are none or several.
+pub fn select_device<'a>(context : &'a Context, filter : &DeviceFilter) -> Result<(Device<'a>, DeviceInfo), StorageError> {
+    Ok(filter.choose(find_devices(context, filter)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text : &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    fn info(bus : u8, address : u8, vendor_id : u16, product_id : u16, serial : Option<&str>) -> DeviceInfo {
+        DeviceInfo {
+            bus,
+            address,
+            ports : Some(vec![address]),
+            vendor_id,
+            product_id,
+            serial : serial.map(String::from),
+            classes : vec![0, MASS_STORAGE_CLASS],
+        }
+    }
+
+    #[test]
+    fn from_args_takes_out_selection_flags() {
+        let (filter, rest) = DeviceFilter::from_args(&args("list --vid 0x0781 --pid 5583 -v --serial AB12 --bus 0x02 --port 1.4.2")).unwrap();
+        assert_eq!(filter, Some(DeviceFilter {
+            vendor_id : Some(0x0781),
+            product_id : Some(0x5583),
+            serial : Some("AB12".to_string()),
+            bus : Some(2),
+            ports : Some(vec![1, 4, 2]),
+            interface_class : Some(MASS_STORAGE_CLASS),
+        }));
+        assert_eq!(rest, args("list -v"));
+
+        let (filter, rest) = DeviceFilter::from_args(&args("--class any export out.img")).unwrap();
+        assert_eq!(filter, Some(DeviceFilter::default()));
+        assert_eq!(rest, args("export out.img"));
+
+        assert_eq!(DeviceFilter::from_args(&args("list")).unwrap(), (None, args("list")));
+    }
+
+    #[test]
+    fn from_args_rejects_bad_values() {
+        let error = |text| match DeviceFilter::from_args(&args(text)) {
+            Err(SelectError::InvalidFilter(msg)) => msg,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert_eq!(error("list --vid"), "--vid needs a value");
+        assert_eq!(error("--pid 0xgg"), "--pid expects a hex ID, got \"0xgg\"");
+        assert_eq!(error("--bus 256"), "--bus expects a number from 0 to 255, got \"256\"");
+        assert_eq!(error("--port 1..2"), "--port expects a number from 0 to 255, got \"\"");
+    }
+
+    #[test]
+    fn choose_needs_exactly_one_match() {
+        let candidates = || vec![
+            (1, info(1, 3, 0x0781, 0x5583, Some("AB12"))),
+            (2, info(1, 4, 0x0781, 0x5583, None)),
+            (3, info(2, 3, 0x090c, 0x1000, Some("CD34"))),
+        ];
+        let filter = DeviceFilter { bus : Some(2), ..DeviceFilter::mass_storage() };
+        assert_eq!(filter.choose(candidates()).unwrap().0, 3);
+
+        // A serial filter skips devices whose serial is unknown.
+        let filter = DeviceFilter { serial : Some("AB12".to_string()), ..DeviceFilter::default() };
+        assert_eq!(filter.choose(candidates()).unwrap().0, 1);
+
+        let filter = DeviceFilter { vendor_id : Some(0x0781), ..DeviceFilter::mass_storage() };
+        match filter.choose(candidates()) {
+            Err(SelectError::MultipleMatches { filter, matches }) => {
+                assert_eq!(filter, "vid 0781, class 0x08");
+                assert_eq!(matches, vec!["0781:5583 on bus 1 port 3 serial \"AB12\"", "0781:5583 on bus 1 port 4"]);
+            },
+            other => panic!("unexpected result {:?}", other.map(|(id, _)| id)),
+        }
+
+        let filter = DeviceFilter { interface_class : Some(0x03), ..DeviceFilter::default() };
+        match filter.choose(candidates()) {
+            Err(SelectError::NoMatch { filter }) => assert_eq!(filter, "class 0x03"),
+            other => panic!("unexpected result {:?}", other.map(|(id, _)| id)),
+        }
+    }
+}
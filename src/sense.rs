@@ -0,0 +1,275 @@
+use crate::*;
+use std::fmt;
+
+pub const KEY_NO_SENSE : u8 = 0x00;
+pub const KEY_RECOVERED_ERROR : u8 = 0x01;
+pub const KEY_NOT_READY : u8 = 0x02;
+pub const KEY_MEDIUM_ERROR : u8 = 0x03;
+pub const KEY_HARDWARE_ERROR : u8 = 0x04;
+pub const KEY_ILLEGAL_REQUEST : u8 = 0x05;
+pub const KEY_UNIT_ATTENTION : u8 = 0x06;
+pub const KEY_DATA_PROTECT : u8 = 0x07;
+pub const KEY_ABORTED_COMMAND : u8 = 0x0B;
+
+const SENSE_KEYS : [&'static str ; 16] = [
+    "NO SENSE",
+    "RECOVERED ERROR",
+    "NOT READY",
+    "MEDIUM ERROR",
+    "HARDWARE ERROR",
+    "ILLEGAL REQUEST",
+    "UNIT ATTENTION",
+    "DATA PROTECT",
+    "BLANK CHECK",
+    "VENDOR SPECIFIC",
+    "COPY ABORTED",
+    "ABORTED COMMAND",
+    "RESERVED (0x0C)",
+    "VOLUME OVERFLOW",
+    "MISCOMPARE",
+    "COMPLETED",
+];
+
+// (ASC, ASCQ, text) for the codes block devices actually report; see SPC-4 Annex D for the rest.
+const ASC_TEXT : &'static [(u8, u8, &'static str)] = &[
+    (0x00, 0x00, "NO ADDITIONAL SENSE INFORMATION"),
+    (0x00, 0x06, "I/O PROCESS TERMINATED"),
+    (0x00, 0x16, "OPERATION IN PROGRESS"),
+    (0x02, 0x00, "NO SEEK COMPLETE"),
+    (0x03, 0x00, "PERIPHERAL DEVICE WRITE FAULT"),
+    (0x04, 0x00, "LOGICAL UNIT NOT READY, CAUSE NOT REPORTABLE"),
+    (0x04, 0x01, "LOGICAL UNIT IS IN PROCESS OF BECOMING READY"),
+    (0x04, 0x02, "LOGICAL UNIT NOT READY, INITIALIZING COMMAND REQUIRED"),
+    (0x04, 0x03, "LOGICAL UNIT NOT READY, MANUAL INTERVENTION REQUIRED"),
+    (0x04, 0x04, "LOGICAL UNIT NOT READY, FORMAT IN PROGRESS"),
+    (0x04, 0x07, "LOGICAL UNIT NOT READY, OPERATION IN PROGRESS"),
+    (0x04, 0x09, "LOGICAL UNIT NOT READY, SELF-TEST IN PROGRESS"),
+    (0x05, 0x00, "LOGICAL UNIT DOES NOT RESPOND TO SELECTION"),
+    (0x08, 0x00, "LOGICAL UNIT COMMUNICATION FAILURE"),
+    (0x08, 0x01, "LOGICAL UNIT COMMUNICATION TIME-OUT"),
+    (0x08, 0x02, "LOGICAL UNIT COMMUNICATION PARITY ERROR"),
+    (0x0C, 0x00, "WRITE ERROR"),
+    (0x0C, 0x02, "WRITE ERROR - AUTO REALLOCATION FAILED"),
+    (0x0C, 0x03, "WRITE ERROR - RECOMMEND REASSIGNMENT"),
+    (0x10, 0x00, "ID CRC OR ECC ERROR"),
+    (0x11, 0x00, "UNRECOVERED READ ERROR"),
+    (0x11, 0x01, "READ RETRIES EXHAUSTED"),
+    (0x11, 0x02, "ERROR TOO LONG TO CORRECT"),
+    (0x11, 0x04, "UNRECOVERED READ ERROR - AUTO REALLOCATE FAILED"),
+    (0x14, 0x00, "RECORDED ENTITY NOT FOUND"),
+    (0x14, 0x01, "RECORD NOT FOUND"),
+    (0x15, 0x00, "RANDOM POSITIONING ERROR"),
+    (0x17, 0x00, "RECOVERED DATA WITH NO ERROR CORRECTION APPLIED"),
+    (0x18, 0x00, "RECOVERED DATA WITH ERROR CORRECTION APPLIED"),
+    (0x1A, 0x00, "PARAMETER LIST LENGTH ERROR"),
+    (0x20, 0x00, "INVALID COMMAND OPERATION CODE"),
+    (0x21, 0x00, "LOGICAL BLOCK ADDRESS OUT OF RANGE"),
+    (0x24, 0x00, "INVALID FIELD IN CDB"),
+    (0x25, 0x00, "LOGICAL UNIT NOT SUPPORTED"),
+    (0x26, 0x00, "INVALID FIELD IN PARAMETER LIST"),
+    (0x27, 0x00, "WRITE PROTECTED"),
+    (0x27, 0x01, "HARDWARE WRITE PROTECTED"),
+    (0x27, 0x02, "LOGICAL UNIT SOFTWARE WRITE PROTECTED"),
+    (0x28, 0x00, "NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED"),
+    (0x29, 0x00, "POWER ON, RESET, OR BUS DEVICE RESET OCCURRED"),
+    (0x29, 0x01, "POWER ON OCCURRED"),
+    (0x29, 0x02, "SCSI BUS RESET OCCURRED"),
+    (0x29, 0x03, "BUS DEVICE RESET FUNCTION OCCURRED"),
+    (0x2A, 0x01, "MODE PARAMETERS CHANGED"),
+    (0x2C, 0x00, "COMMAND SEQUENCE ERROR"),
+    (0x30, 0x00, "INCOMPATIBLE MEDIUM INSTALLED"),
+    (0x30, 0x01, "CANNOT READ MEDIUM - UNKNOWN FORMAT"),
+    (0x31, 0x00, "MEDIUM FORMAT CORRUPTED"),
+    (0x32, 0x00, "NO DEFECT SPARE LOCATION AVAILABLE"),
+    (0x3A, 0x00, "MEDIUM NOT PRESENT"),
+    (0x3A, 0x01, "MEDIUM NOT PRESENT - TRAY CLOSED"),
+    (0x3A, 0x02, "MEDIUM NOT PRESENT - TRAY OPEN"),
+    (0x3E, 0x00, "LOGICAL UNIT HAS NOT SELF-CONFIGURED YET"),
+    (0x3E, 0x01, "LOGICAL UNIT FAILURE"),
+    (0x3F, 0x01, "MICROCODE HAS BEEN CHANGED"),
+    (0x3F, 0x0E, "REPORTED LUNS DATA HAS CHANGED"),
+    (0x40, 0x00, "RAM FAILURE"),
+    (0x44, 0x00, "INTERNAL TARGET FAILURE"),
+    (0x47, 0x00, "SCSI PARITY ERROR"),
+    (0x4E, 0x00, "OVERLAPPED COMMANDS ATTEMPTED"),
+    (0x53, 0x02, "MEDIUM REMOVAL PREVENTED"),
+    (0x5D, 0x00, "FAILURE PREDICTION THRESHOLD EXCEEDED"),
+    (0x5E, 0x00, "LOW POWER CONDITION ON"),
+    (0x65, 0x00, "VOLTAGE FAULT"),
+];
+
+pub fn key_name(key : u8) -> &'static str {
+    SENSE_KEYS[(key & 0x0F) as usize]
+}
+
+/// Looks up the text for an ASC/ASCQ pair, if it is one we know.
+pub fn asc_text(asc : u8, ascq : u8) -> Option<&'static str> {
+    ASC_TEXT.iter().find(|(a, q, _)| *a == asc && *q == ascq).map(|(_, _, text)| *text)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SenseFormat {
+    Fixed,
+    Descriptor,
+}
+
+/// Sense data returned by REQUEST SENSE, in either fixed (0x70/0x71) or
+/// descriptor (0x72/0x73) format.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SenseData {
+    pub format : SenseFormat,
+    pub deferred : bool,
+    pub key : u8,
+    pub asc : u8,
+    pub ascq : u8,
+    pub information : Option<u64>,
+}
+
+impl SenseData {
+    pub fn from_bytes(bytes : &[u8]) -> Option<SenseData> {
+        let response_code = *bytes.get(0)? & 0x7F;
+        match response_code {
+            0x70 | 0x71 => SenseData::from_fixed(bytes),
+            0x72 | 0x73 => SenseData::from_descriptor(bytes),
+            _ => None,
+        }
+    }
+
+    fn from_fixed(bytes : &[u8]) -> Option<SenseData> {
+        if bytes.len() < 3 {
+            return None;
+        }
+        // The additional length says how much of bytes 8.. is meaningful.
+        let valid_len = bytes.get(7).map(|len| 8 + *len as usize).unwrap_or(bytes.len()).min(bytes.len());
+        let information = if bytes[0] & 0x80 != 0 && valid_len >= 7 {
+            Some(bot::be_u32(&bytes[3..7]) as u64)
+        } else {
+            None
+        };
+        Some(SenseData {
+            format : SenseFormat::Fixed,
+            deferred : bytes[0] & 0x7F == 0x71,
+            key : bytes[2] & 0x0F,
+            asc : if valid_len > 12 { bytes[12] } else { 0 },
+            ascq : if valid_len > 13 { bytes[13] } else { 0 },
+            information,
+        })
+    }
+
+    fn from_descriptor(bytes : &[u8]) -> Option<SenseData> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let valid_len = bytes.get(7).map(|len| 8 + *len as usize).unwrap_or(bytes.len()).min(bytes.len());
+        let mut information = None;
+        let mut idx = 8;
+        while idx + 2 <= valid_len {
+            let desc_type = bytes[idx];
+            let desc_len = bytes[idx + 1] as usize;
+            // Information descriptor: type 0x00, length 0x0A, VALID bit, reserved, 8-byte field.
+            if desc_type == 0x00 && desc_len >= 0x0A && idx + 12 <= valid_len && bytes[idx + 2] & 0x80 != 0 {
+                let field = &bytes[idx + 4 .. idx + 12];
+                information = Some((bot::be_u32(&field[0..4]) as u64) << 32 | bot::be_u32(&field[4..8]) as u64);
+            }
+            idx += 2 + desc_len;
+        }
+        Some(SenseData {
+            format : SenseFormat::Descriptor,
+            deferred : bytes[0] & 0x7F == 0x73,
+            key : bytes[1] & 0x0F,
+            asc : bytes[2],
+            ascq : bytes[3],
+            information,
+        })
+    }
+
+    pub fn key_name(&self) -> &'static str {
+        key_name(self.key)
+    }
+
+    pub fn asc_text(&self) -> Option<&'static str> {
+        asc_text(self.asc, self.ascq)
+    }
+
+    /// True if the device reported nothing wrong, which some devices do for a failed command.
+    pub fn is_no_sense(&self) -> bool {
+        self.key == KEY_NO_SENSE && self.asc == 0 && self.ascq == 0
+    }
+
+    pub fn is_medium_not_present(&self) -> bool {
+        self.key == KEY_NOT_READY && self.asc == 0x3A
+    }
+}
+
+impl fmt::Display for SenseData {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.key_name())?;
+        match self.asc_text() {
+            Some(text) => write!(f, ": {}", text)?,
+            None => write!(f, ": ASC 0x{:02X} ASCQ 0x{:02X}", self.asc, self.ascq)?,
+        }
+        if let Some(info) = self.information {
+            write!(f, " (information {})", info)?;
+        }
+        if self.deferred {
+            write!(f, " (deferred)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_fixed_format() {
+        // Current error with a valid information field, as a failed READ(10) reports it.
+        let bytes = [0xF0, 0, 0x03, 0x00, 0x01, 0x02, 0x03, 0x0A, 0, 0, 0, 0, 0x11, 0x00, 0, 0, 0, 0];
+        let sense = SenseData::from_bytes(&bytes).unwrap();
+        assert_eq!(sense, SenseData { format : SenseFormat::Fixed, deferred : false, key : KEY_MEDIUM_ERROR, asc : 0x11, ascq : 0, information : Some(0x0001_0203) });
+        assert_eq!(sense.to_string(), "MEDIUM ERROR: UNRECOVERED READ ERROR (information 66051)");
+
+        // Deferred, no information, and an additional length that stops short of the ASC.
+        let bytes = [0x71, 0, 0x02, 0, 0, 0, 0, 0x04, 0, 0, 0, 0, 0x3A, 0x00];
+        let sense = SenseData::from_bytes(&bytes).unwrap();
+        assert_eq!((sense.deferred, sense.key, sense.asc, sense.information), (true, KEY_NOT_READY, 0, None));
+        assert_eq!(sense.to_string(), "NOT READY: NO ADDITIONAL SENSE INFORMATION (deferred)");
+
+        let bytes = [0x70, 0, 0x02, 0, 0, 0, 0, 0x0A, 0, 0, 0, 0, 0x3A, 0x00, 0, 0, 0, 0];
+        assert!(SenseData::from_bytes(&bytes).unwrap().is_medium_not_present());
+    }
+
+    #[test]
+    fn decodes_descriptor_format() {
+        // A stream commands descriptor to skip, then an information descriptor.
+        let bytes = [
+            0x72, 0x05, 0x24, 0x00, 0, 0, 0, 0x12,
+            0x04, 0x04, 0, 0, 0, 0,
+            0x00, 0x0A, 0x80, 0, 0, 0, 0, 0x01, 0, 0, 0, 0x02,
+        ];
+        let sense = SenseData::from_bytes(&bytes).unwrap();
+        assert_eq!(sense, SenseData { format : SenseFormat::Descriptor, deferred : false, key : KEY_ILLEGAL_REQUEST, asc : 0x24, ascq : 0, information : Some(0x1_0000_0002) });
+        assert_eq!(sense.to_string(), "ILLEGAL REQUEST: INVALID FIELD IN CDB (information 4294967298)");
+
+        // An information descriptor without the VALID bit, cut off by the additional length.
+        let bytes = [0x73, 0x06, 0x29, 0x00, 0, 0, 0, 0x0C, 0x00, 0x0A, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0x07];
+        let sense = SenseData::from_bytes(&bytes).unwrap();
+        assert_eq!((sense.deferred, sense.key, sense.asc, sense.information), (true, KEY_UNIT_ATTENTION, 0x29, None));
+        let bytes = [0x72, 0x06, 0x29, 0x00, 0, 0, 0, 0x08, 0x00, 0x0A, 0x80, 0, 0, 0, 0, 0];
+        assert_eq!(SenseData::from_bytes(&bytes).unwrap().information, None);
+    }
+
+    #[test]
+    fn unknown_codes_are_shown_as_numbers() {
+        let bytes = [0x70, 0, 0x06, 0, 0, 0, 0, 0x0A, 0, 0, 0, 0, 0x99, 0x42, 0, 0, 0, 0];
+        let sense = SenseData::from_bytes(&bytes).unwrap();
+        assert_eq!(sense.asc_text(), None);
+        assert_eq!(sense.to_string(), "UNIT ATTENTION: ASC 0x99 ASCQ 0x42");
+        assert_eq!(key_name(0x0C), "RESERVED (0x0C)");
+
+        assert_eq!(SenseData::from_bytes(&[]), None);
+        assert_eq!(SenseData::from_bytes(&[0x7F, 0, 0x05]), None);
+        assert_eq!(SenseData::from_bytes(&[0x72, 0x05]), None);
+    }
+}
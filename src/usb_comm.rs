@@ -1,4 +1,38 @@
 use crate::*;
+use libusb::{Device, DeviceDescriptor, DeviceHandle};
+
+const BULK_ONLY_RESET : u8 = 0xFF;
+const GET_MAX_LUN : u8 = 0xFE;
+const CONTROL_TIMEOUT : Duration = Duration::from_secs(5);
+
+/// Timeouts for a `UsbClient`'s bulk transfers, and how failed commands are retried.
+/// A transfer that fails is never re-issued on its own; see `bot::RetryPolicy`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TransportConfig {
+    /// Timeout for sending a CBW.
+    pub command_timeout : Duration,
+    /// Timeout for each data-stage transfer.
+    pub data_timeout : Duration,
+    /// Timeout for reading a CSW.
+    pub status_timeout : Duration,
+    /// Passed to `bot::execute_data`, which retries whole commands after reset recovery.
+    pub retry : bot::RetryPolicy,
+    /// Cap on the time one SCSI command may take, across all its transfers and retries.
+    pub command_deadline : Option<Duration>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> TransportConfig {
+        TransportConfig {
+            command_timeout : Duration::from_secs(5),
+            data_timeout : Duration::from_secs(30),
+            status_timeout : Duration::from_secs(30),
+            retry : bot::RetryPolicy::default(),
+            command_deadline : None,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 struct Endpoint {
     config: u8,
@@ -18,22 +52,50 @@ pub struct UsbClient<'a> {
     read_endpoint: ReadEndpoint,
     write_endpoint: WriteEndpoint,
     had_kernel : bool,
+    claimed : bool,
+    reattach_kernel : bool,
+    transport_error : Option<libusb::Error>,
+    config : TransportConfig,
+    stage : bot::TransferStage,
+    deadline : Option<Instant>,
 }
 
 impl<'a> UsbClient<'a> {
-    pub fn from_device(device: &mut Device<'a>) -> Result<UsbClient<'a>, RawStringErr> {
-        let desc = device.device_descriptor().unwrap();
-        let (rd, wd) = UsbClient::find_bulk_endpoints(device, &desc).unwrap();
-        let mut hndl = device.open().map_err(|e| format!("Open err: {:?}", e)).unwrap();
-        let had_kernel = if hndl.kernel_driver_active(rd.0.iface).map_err(|e| format!("Error checking kernel: {:?}", e)).unwrap() {
-            hndl.detach_kernel_driver(rd.0.iface).map_err(|e| format!("Found kernel detach err: {:?}", e)).unwrap();
+    pub fn from_device(device: &mut Device<'a>) -> Result<UsbClient<'a>, StorageError> {
+        UsbClient::from_device_with_reattach(device, true)
+    }
+
+    /// Like `from_device`, but with `reattach_kernel` false the kernel driver is
+    /// left detached when the client is dropped.
+    pub fn from_device_with_reattach(device: &mut Device<'a>, reattach_kernel : bool) -> Result<UsbClient<'a>, StorageError> {
+        let desc = device.device_descriptor()?;
+        let (rd, wd) = UsbClient::find_bulk_endpoints(device, &desc)?;
+        let mut hndl = device.open()?;
+        let had_kernel = if hndl.kernel_driver_active(rd.0.iface)? {
+            hndl.detach_kernel_driver(rd.0.iface)?;
             true
         } else {false};
-        hndl.reset().map_err(|e| format!("Found reset err: {:?}", e)).unwrap();
-        hndl.set_active_configuration(rd.0.config).map_err(|e| format!("Could not set active config: {:?}", e)).unwrap();
-        hndl.claim_interface(rd.0.iface).map_err(|e| format!("Could not claim iface {}: {:?}", rd.0.iface, e)).unwrap();
-        Ok(UsbClient::new(hndl, rd, wd, had_kernel))
+        // From here on, dropping the client on an early return hands the device back to the kernel.
+        let mut client = UsbClient {
+            device_handle : hndl,
+            read_endpoint : rd,
+            write_endpoint : wd,
+            had_kernel,
+            claimed : false,
+            reattach_kernel,
+            transport_error : None,
+            config : TransportConfig::default(),
+            stage : bot::TransferStage::Command,
+            deadline : None,
+        };
+        client.device_handle.reset()?;
+        client.device_handle.set_active_configuration(rd.0.config)?;
+        client.device_handle.claim_interface(rd.0.iface)?;
+        client.claimed = true;
+        Ok(client)
     }
+
+    /// Wraps a handle whose interface has already been claimed; it is released on drop.
     pub fn new(
         device_handle: DeviceHandle<'a>,
         read_endpoint: ReadEndpoint,
@@ -45,32 +107,68 @@ impl<'a> UsbClient<'a> {
             read_endpoint,
             write_endpoint,
             had_kernel,
+            claimed : true,
+            reattach_kernel : true,
+            transport_error : None,
+            config : TransportConfig::default(),
+            stage : bot::TransferStage::Command,
+            deadline : None,
         }
     }
 
-    pub fn pull_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
-        let endpoint = self.read_endpoint.0;
-        let timeout = Duration::from_secs(30);
-        let rval = self
-            .device_handle
-            .read_bulk(endpoint.address, buffer, timeout)
-            .map_err(|e| format!("Read Error: {:?}", e)).unwrap();
-        Ok(rval)
+    pub fn transport_config(&self) -> TransportConfig {
+        self.config
     }
 
-    pub fn push_bytes(&mut self, buffer: &[u8]) -> Result<usize, String> {
-        let endpoint = self.write_endpoint.0;
-        let timeout = Duration::from_secs(30);
-        let rval = self
-            .device_handle
-            .write_bulk(endpoint.address, buffer, timeout)
-            .map_err(|e| format!("Write Error: {:?}", e)).unwrap();
-        Ok(rval)
+    pub fn set_transport_config(&mut self, config : TransportConfig) {
+        self.config = config;
+    }
+
+    /// Whether to reattach the kernel driver on drop if `from_device` detached it. Defaults to true.
+    pub fn set_reattach_kernel_driver(&mut self, reattach : bool) {
+        self.reattach_kernel = reattach;
+    }
+
+    pub fn had_kernel_driver(&self) -> bool {
+        self.had_kernel
+    }
+
+    // The current stage's timeout, cut short by the command deadline if there is one.
+    fn transfer_timeout(&self) -> Result<Duration, libusb::Error> {
+        let timeout = match self.stage {
+            bot::TransferStage::Command => self.config.command_timeout,
+            bot::TransferStage::Data => self.config.data_timeout,
+            bot::TransferStage::Status => self.config.status_timeout,
+        };
+        match self.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(libusb::Error::Timeout);
+                }
+                Ok(timeout.min(deadline - now))
+            },
+            None => Ok(timeout),
+        }
+    }
+
+    // A failed bulk transfer may already have moved part of its data, and libusb doesn't
+    // say how much, so these fail fast and leave retrying to `bot::execute_data`.
+    pub fn pull_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, libusb::Error> {
+        let timeout = self.transfer_timeout()?;
+        self.device_handle
+            .read_bulk(self.read_endpoint.0.address, buffer, timeout)
+    }
+
+    pub fn push_bytes(&mut self, buffer: &[u8]) -> Result<usize, libusb::Error> {
+        let timeout = self.transfer_timeout()?;
+        self.device_handle
+            .write_bulk(self.write_endpoint.0.address, buffer, timeout)
     }
     fn find_bulk_endpoints(
         device: &mut Device,
         desc: &DeviceDescriptor,
-    ) -> Result<(ReadEndpoint, WriteEndpoint), RawStringErr> {
+    ) -> Result<(ReadEndpoint, WriteEndpoint), StorageError> {
 
         let is_scsi_bulk_device =
             desc.class_code() == 8 && desc.sub_class_code() == 6 && desc.protocol_code() == 80;
@@ -93,10 +191,10 @@ impl<'a> UsbClient<'a> {
                         interface_desc.endpoint_descriptors().collect();
                     let endpoint_a = endpoints
                         .pop()
-                        .ok_or(format!("Found no endpoints in interface!")).unwrap();
+                        .ok_or_else(|| StorageError::Device(format!("Found no endpoints in interface!")))?;
                     let endpoint_b = endpoints
                         .pop()
-                        .ok_or(format!("Only found 1 endpoint in interface!")).unwrap();
+                        .ok_or_else(|| StorageError::Device(format!("Only found 1 endpoint in interface!")))?;
                     let (read_desc, write_desc) = if endpoint_a.direction() == libusb::Direction::In
                         && endpoint_b.direction() == libusb::Direction::Out
                     {
@@ -106,7 +204,7 @@ impl<'a> UsbClient<'a> {
                     {
                         (endpoint_b, endpoint_a)
                     } else {
-                        return Err(RawStringErr::from(format!(
+                        return Err(StorageError::Device(format!(
                             "Both endpoints are in the same direction!"
                         )));
                     };
@@ -127,28 +225,99 @@ impl<'a> UsbClient<'a> {
                 }
             }
         }
-        Err(RawStringErr::from(
-            "Could not find bulk read/write endpoints!",
+        Err(StorageError::Device(
+            "Could not find bulk read/write endpoints!".to_owned(),
         ))
     }
 }
 
 impl <'a> Drop for UsbClient<'a> {
     fn drop(&mut self) {
+        let iface = self.read_endpoint.0.iface;
+        if self.claimed {
+            if let Err(e) = self.device_handle.release_interface(iface) {
+                eprintln!("UsbClient: Could not release interface {}: {:?}", iface, e);
+            }
+        }
+        if self.had_kernel && self.reattach_kernel {
+            if let Err(e) = self.device_handle.attach_kernel_driver(iface) {
+                eprintln!("UsbClient: Could not reattach kernel driver to interface {}: {:?}", iface, e);
+            }
+        }
+    }
+}
+
+impl <'a> bot::BotTransport for UsbClient<'a> {
+    fn take_transport_error(&mut self) -> Option<StorageError> {
+        self.transport_error.take().map(StorageError::Transport)
+    }
+
+    fn bulk_only_reset(&mut self) -> Result<(), StorageError> {
+        let request_type = libusb::request_type(libusb::Direction::Out, libusb::RequestType::Class, libusb::Recipient::Interface);
+        let iface = self.read_endpoint.0.iface as u16;
+        self.device_handle.write_control(request_type, BULK_ONLY_RESET, 0, iface, &[], CONTROL_TIMEOUT)?;
+        Ok(())
+    }
+
+    fn clear_halt(&mut self, direction : bot::DataDirection) -> Result<(), StorageError> {
+        let address = match direction {
+            bot::DataDirection::Out => self.write_endpoint.0.address,
+            _ => self.read_endpoint.0.address,
+        };
+        self.device_handle.clear_halt(address)?;
+        Ok(())
+    }
+
+    fn begin_command(&mut self) {
+        self.deadline = self.config.command_deadline.map(|limit| Instant::now() + limit);
+    }
+
+    fn command_deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn retry_policy(&self) -> bot::RetryPolicy {
+        self.config.retry
+    }
+
+    fn set_stage(&mut self, stage : bot::TransferStage) {
+        self.stage = stage;
+    }
+
+    fn read_into(&mut self, buffer : &mut [u8]) -> Result<usize, StorageError> {
+        Ok(self.pull_bytes(buffer)?)
+    }
+
+    fn write_from(&mut self, buffer : &[u8]) -> Result<usize, StorageError> {
+        Ok(self.push_bytes(buffer)?)
+    }
+
+    fn max_lun(&mut self) -> Result<u8, StorageError> {
+        let request_type = libusb::request_type(libusb::Direction::In, libusb::RequestType::Class, libusb::Recipient::Interface);
+        let iface = self.read_endpoint.0.iface as u16;
+        let mut max_lun = [0 ; 1];
+        match self.device_handle.read_control(request_type, GET_MAX_LUN, 0, iface, &mut max_lun, CONTROL_TIMEOUT) {
+            Ok(1) => Ok(max_lun[0].min(15)),
+            // Single-LUN devices are allowed to stall the request.
+            Ok(_) | Err(libusb::Error::Pipe) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
     }
 }
 
 impl <'a> scsi::CommunicationChannel for UsbClient<'a> {
     fn in_transfer<B : scsi::Buffer> (&mut self, buffer: &mut B) -> Result<usize, scsi::ScsiError> {
-        let mut shim = Vec::with_capacity(buffer.capacity());
-        shim.resize(buffer.capacity(), 0);
-        let rval = self.pull_bytes(shim.as_mut_slice())
-        .map_err(|e|{ 
-            eprintln!("Got error in read: {:?}", e);
-            scsi::ScsiError::from_cause(scsi::ErrorCause::UsbTransferError{ direction: scsi::UsbTransferDirection::In})
-        }).unwrap();
-        for byte in shim {
-            buffer.push_byte(byte).unwrap();
+        let mut shim = vec![0 ; buffer.capacity() - buffer.size()];
+        let rval = match self.pull_bytes(shim.as_mut_slice()) {
+            Ok(rval) => rval,
+            Err(e) => {
+                eprintln!("Got error in read: {:?}", e);
+                self.transport_error = Some(e);
+                return Err(scsi::ScsiError::from_cause(scsi::ErrorCause::UsbTransferError{ direction: scsi::UsbTransferDirection::In}));
+            }
+        };
+        for byte in &shim[.. rval] {
+            buffer.push_byte(*byte)?;
         }
         Ok(rval)
     }
@@ -158,11 +327,14 @@ impl <'a> scsi::CommunicationChannel for UsbClient<'a> {
         while bytes.size() > 0 {
             shim.push(bytes.pull_byte()?);
         }
-        let rval = self.push_bytes(shim.as_ref())
-        .map_err(|e|{ 
-            eprintln!("Got error in write: {:?}", e);
-            scsi::ScsiError::from_cause(scsi::ErrorCause::UsbTransferError{ direction: scsi::UsbTransferDirection::Out})
-        }).unwrap();
+        let rval = match self.push_bytes(shim.as_ref()) {
+            Ok(rval) => rval,
+            Err(e) => {
+                eprintln!("Got error in write: {:?}", e);
+                self.transport_error = Some(e);
+                return Err(scsi::ScsiError::from_cause(scsi::ErrorCause::UsbTransferError{ direction: scsi::UsbTransferDirection::Out}));
+            }
+        };
         Ok(rval)
     }
 }
\ No newline at end of file
@@ -0,0 +1,413 @@
+use crate::*;
+use std::io;
+
+// See Microsoft's "Virtual Hard Disk Image Format Specification".
+const FOOTER_COOKIE : &[u8] = b"conectix";
+const DYNAMIC_COOKIE : &[u8] = b"cxsparse";
+const FOOTER_LEN : usize = 512;
+const DYNAMIC_HEADER_LEN : usize = 1024;
+const SECTOR_SIZE : usize = 512;
+
+const FOOTER_CHECKSUM : usize = 64;
+const DYNAMIC_CHECKSUM : usize = 36;
+
+const TYPE_FIXED : u32 = 2;
+const TYPE_DYNAMIC : u32 = 3;
+const TYPE_DIFFERENCING : u32 = 4;
+
+const BAT_UNALLOCATED : u32 = 0xFFFF_FFFF;
+
+fn be_u64(bytes : &[u8]) -> u64 {
+    (bot::be_u32(&bytes[0..4]) as u64) << 32 | bot::be_u32(&bytes[4..8]) as u64
+}
+
+fn invalid(message : String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("VHD: {}", message))
+}
+
+// The one's complement of the byte sum, skipping the checksum field itself.
+fn checksum(bytes : &[u8], field : usize) -> u32 {
+    let sum = bytes.iter().enumerate()
+        .filter(|(idx, _)| *idx < field || *idx >= field + 4)
+        .fold(0u32, |sum, (_, byte)| sum.wrapping_add(*byte as u32));
+    !sum
+}
+
+fn check_checksum(bytes : &[u8], field : usize, what : &str) -> io::Result<()> {
+    let stored = bot::be_u32(&bytes[field .. field + 4]);
+    let computed = checksum(bytes, field);
+    if stored != computed {
+        return Err(invalid(format!("{} checksum is {:#010x}, expected {:#010x}", what, stored, computed)));
+    }
+    Ok(())
+}
+
+/// Reads the footer at the end of `file`, returning it with its offset, or None if
+/// the file doesn't end in one. Footers written by some old tools are 511 bytes long.
+pub fn read_footer(file : &mut File) -> io::Result<Option<([u8 ; FOOTER_LEN], u64)>> {
+    let len = file.metadata()?.len();
+    if len < FOOTER_LEN as u64 {
+        return Ok(None);
+    }
+    let mut tail = [0 ; FOOTER_LEN];
+    file.seek(SeekFrom::Start(len - FOOTER_LEN as u64))?;
+    file.read_exact(&mut tail)?;
+    if &tail[.. FOOTER_COOKIE.len()] == FOOTER_COOKIE {
+        return Ok(Some((tail, len - FOOTER_LEN as u64)));
+    }
+    if &tail[1 .. 1 + FOOTER_COOKIE.len()] == FOOTER_COOKIE {
+        let mut footer = [0 ; FOOTER_LEN];
+        footer[.. FOOTER_LEN - 1].copy_from_slice(&tail[1 ..]);
+        return Ok(Some((footer, len - FOOTER_LEN as u64 + 1)));
+    }
+    Ok(None)
+}
+
+// Block allocation state for a dynamic disk.
+struct DynamicLayout {
+    bat_offset : u64,
+    bat : Vec<u32>,
+    block_size : u64,
+    // Each block's sector bitmap, padded to whole sectors.
+    bitmap_len : u64,
+    footer : [u8 ; FOOTER_LEN],
+    footer_offset : u64,
+}
+
+/// A fixed or dynamic VHD as a block device with 512-byte blocks.
+/// Writes to an unallocated part of a dynamic disk append a new block where the footer
+/// was and move the footer after it. Differencing disks aren't supported.
+pub struct VhdDisk {
+    file : File,
+    size : u64,
+    dynamic : Option<DynamicLayout>,
+}
+
+impl VhdDisk {
+    pub fn open<P : AsRef<Path>>(path : P) -> io::Result<VhdDisk> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        VhdDisk::from_file(file)
+    }
+
+    pub fn from_file(mut file : File) -> io::Result<VhdDisk> {
+        let (footer, footer_offset) = read_footer(&mut file)?.ok_or_else(|| invalid("no footer".to_owned()))?;
+        check_checksum(&footer, FOOTER_CHECKSUM, "footer")?;
+        let size = be_u64(&footer[48..56]);
+        let dynamic = match bot::be_u32(&footer[60..64]) {
+            TYPE_FIXED => {
+                if footer_offset < size {
+                    return Err(invalid(format!("fixed disk of {} bytes has its footer at {}", size, footer_offset)));
+                }
+                None
+            },
+            TYPE_DYNAMIC => Some(VhdDisk::read_dynamic_header(&mut file, footer, footer_offset, size)?),
+            TYPE_DIFFERENCING => return Err(io::Error::new(io::ErrorKind::Other, "VHD: differencing disks aren't supported.")),
+            other => return Err(invalid(format!("disk type {}", other))),
+        };
+        Ok(VhdDisk { file, size, dynamic })
+    }
+
+    fn read_dynamic_header(file : &mut File, footer : [u8 ; FOOTER_LEN], footer_offset : u64, size : u64) -> io::Result<DynamicLayout> {
+        let mut header = [0 ; DYNAMIC_HEADER_LEN];
+        file.seek(SeekFrom::Start(be_u64(&footer[16..24])))?;
+        file.read_exact(&mut header)?;
+        if &header[.. DYNAMIC_COOKIE.len()] != DYNAMIC_COOKIE {
+            return Err(invalid("dynamic header has a bad cookie".to_owned()));
+        }
+        check_checksum(&header, DYNAMIC_CHECKSUM, "dynamic header")?;
+        let bat_offset = be_u64(&header[16..24]);
+        let entries = bot::be_u32(&header[28..32]) as u64;
+        let block_size = bot::be_u32(&header[32..36]) as u64;
+        if block_size == 0 || block_size % SECTOR_SIZE as u64 != 0 {
+            return Err(invalid(format!("block size {}", block_size)));
+        }
+        if entries.saturating_mul(block_size) < size {
+            return Err(invalid(format!("{} blocks of {} bytes can't hold {} bytes", entries, block_size, size)));
+        }
+        let mut bat_bytes = vec![0 ; entries as usize * 4];
+        file.seek(SeekFrom::Start(bat_offset))?;
+        file.read_exact(&mut bat_bytes)?;
+        let sectors_per_block = block_size / SECTOR_SIZE as u64;
+        let bitmap_sectors = (sectors_per_block / 8 + SECTOR_SIZE as u64 - 1) / SECTOR_SIZE as u64;
+        Ok(DynamicLayout {
+            bat_offset,
+            bat : bat_bytes.chunks(4).map(bot::be_u32).collect(),
+            block_size,
+            bitmap_len : bitmap_sectors * SECTOR_SIZE as u64,
+            footer,
+            footer_offset,
+        })
+    }
+
+    fn read_at(&mut self, offset : u64, buffer : &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buffer)
+    }
+
+    fn write_at(&mut self, offset : u64, buffer : &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buffer)
+    }
+
+    // The (block index, offset into it, range of the buffer) pieces of a transfer
+    // of `len` bytes starting at `lba` on a dynamic disk.
+    fn segments(block_size : u64, lba : u64, len : usize) -> Vec<(usize, u64, std::ops::Range<usize>)> {
+        let mut segments = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let guest = lba * SECTOR_SIZE as u64 + pos as u64;
+            let in_block = guest % block_size;
+            let count = ((block_size - in_block) as usize).min(len - pos);
+            segments.push(((guest / block_size) as usize, in_block, pos .. pos + count));
+            pos += count;
+        }
+        segments
+    }
+
+    // Where data for block `idx` starts in the file, if it's allocated.
+    fn block_data_offset(layout : &DynamicLayout, idx : usize) -> Option<u64> {
+        match layout.bat[idx] {
+            BAT_UNALLOCATED => None,
+            sector => Some(sector as u64 * SECTOR_SIZE as u64 + layout.bitmap_len),
+        }
+    }
+
+    // The sector bitmap of allocated block `idx`; sectors whose bit is clear read as zeros.
+    fn read_bitmap(&mut self, idx : usize) -> io::Result<Vec<u8>> {
+        let (offset, len) = {
+            let layout = self.dynamic.as_ref().unwrap();
+            (layout.bat[idx] as u64 * SECTOR_SIZE as u64, layout.bitmap_len as usize)
+        };
+        let mut bitmap = vec![0 ; len];
+        self.read_at(offset, &mut bitmap)?;
+        Ok(bitmap)
+    }
+
+    // Sets the bits for `sectors` sectors of block `idx` from `first` on, writing the
+    // bitmap back only if that changes it. The data goes down before this is called.
+    fn mark_present(&mut self, idx : usize, first : u64, sectors : u64) -> io::Result<()> {
+        let mut bitmap = self.read_bitmap(idx)?;
+        let mut changed = false;
+        for sector in first .. first + sectors {
+            let (byte, mask) = ((sector / 8) as usize, 0x80 >> (sector % 8));
+            if bitmap[byte] & mask == 0 {
+                bitmap[byte] |= mask;
+                changed = true;
+            }
+        }
+        if changed {
+            let offset = self.dynamic.as_ref().unwrap().bat[idx] as u64 * SECTOR_SIZE as u64;
+            self.write_at(offset, &bitmap)?;
+        }
+        Ok(())
+    }
+
+    // Appends block `idx`, zeroed apart from `data` at `in_block`, with every sector marked present.
+    // The block and the moved footer go down before the BAT entry that points at them.
+    fn allocate_block(&mut self, idx : usize, in_block : u64, data : &[u8]) -> io::Result<()> {
+        let (block_offset, bitmap_len, block_size, footer, bat_entry_offset) = {
+            let layout = self.dynamic.as_ref().unwrap();
+            let block_offset = (layout.footer_offset + SECTOR_SIZE as u64 - 1) / SECTOR_SIZE as u64 * SECTOR_SIZE as u64;
+            (block_offset, layout.bitmap_len, layout.block_size, layout.footer, layout.bat_offset + idx as u64 * 4)
+        };
+        if block_offset / SECTOR_SIZE as u64 >= BAT_UNALLOCATED as u64 {
+            return Err(invalid("the file is too large to address another block".to_owned()));
+        }
+        let mut block = vec![0 ; (bitmap_len + block_size) as usize];
+        for byte in block[.. bitmap_len as usize].iter_mut() {
+            *byte = 0xFF;
+        }
+        let start = (bitmap_len + in_block) as usize;
+        block[start .. start + data.len()].copy_from_slice(data);
+        self.write_at(block_offset, &block)?;
+        let footer_offset = block_offset + block.len() as u64;
+        self.write_at(footer_offset, &footer)?;
+        let sector = (block_offset / SECTOR_SIZE as u64) as u32;
+        self.write_at(bat_entry_offset, &bot::be_u32_bytes(sector))?;
+        let layout = self.dynamic.as_mut().unwrap();
+        layout.bat[idx] = sector;
+        layout.footer_offset = footer_offset;
+        Ok(())
+    }
+}
+
+impl BlockDevice for VhdDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+    fn block_count(&self) -> u64 {
+        self.size / SECTOR_SIZE as u64
+    }
+    fn read_blocks(&mut self, lba : u64, buffer : &mut [u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        let block_size = match self.dynamic {
+            Some(ref layout) => layout.block_size,
+            None => return self.read_at(lba * SECTOR_SIZE as u64, buffer),
+        };
+        for (idx, in_block, range) in VhdDisk::segments(block_size, lba, buffer.len()) {
+            match VhdDisk::block_data_offset(self.dynamic.as_ref().unwrap(), idx) {
+                Some(offset) => {
+                    self.read_at(offset + in_block, &mut buffer[range.clone()])?;
+                    let bitmap = self.read_bitmap(idx)?;
+                    let first = in_block / SECTOR_SIZE as u64;
+                    for (sector, data) in (first ..).zip(buffer[range].chunks_mut(SECTOR_SIZE)) {
+                        if bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) == 0 {
+                            for byte in data.iter_mut() {
+                                *byte = 0;
+                            }
+                        }
+                    }
+                },
+                None => {
+                    for byte in buffer[range].iter_mut() {
+                        *byte = 0;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+    fn write_blocks(&mut self, lba : u64, buffer : &[u8]) -> io::Result<()> {
+        check_block_range(self, lba, buffer.len())?;
+        let block_size = match self.dynamic {
+            Some(ref layout) => layout.block_size,
+            None => return self.write_at(lba * SECTOR_SIZE as u64, buffer),
+        };
+        for (idx, in_block, range) in VhdDisk::segments(block_size, lba, buffer.len()) {
+            match VhdDisk::block_data_offset(self.dynamic.as_ref().unwrap(), idx) {
+                Some(offset) => {
+                    self.write_at(offset + in_block, &buffer[range.clone()])?;
+                    self.mark_present(idx, in_block / SECTOR_SIZE as u64, (range.len() / SECTOR_SIZE) as u64)?;
+                },
+                None => self.allocate_block(idx, in_block, &buffer[range])?,
+            }
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_dev::tests::TempFile;
+
+    const BLOCK : usize = 4096;
+    const SIZE : u64 = 64 << 10;
+    // Footer copy, dynamic header, one sector of BAT.
+    const FOOTER_AT : u64 = 2048;
+
+    fn dynamic_image(name : &str) -> TempFile {
+        let temp = TempFile::new(name, 0);
+        let mut footer = [0 ; FOOTER_LEN];
+        footer[.. 8].copy_from_slice(FOOTER_COOKIE);
+        footer[12..16].copy_from_slice(&bot::be_u32_bytes(0x10000));
+        footer[20..24].copy_from_slice(&bot::be_u32_bytes(FOOTER_LEN as u32));
+        footer[44..48].copy_from_slice(&bot::be_u32_bytes(SIZE as u32));
+        footer[52..56].copy_from_slice(&bot::be_u32_bytes(SIZE as u32));
+        footer[60..64].copy_from_slice(&bot::be_u32_bytes(TYPE_DYNAMIC));
+        let sum = checksum(&footer, FOOTER_CHECKSUM);
+        footer[64..68].copy_from_slice(&bot::be_u32_bytes(sum));
+        let mut header = [0 ; DYNAMIC_HEADER_LEN];
+        header[.. 8].copy_from_slice(DYNAMIC_COOKIE);
+        for byte in header[8..16].iter_mut() {
+            *byte = 0xFF;
+        }
+        header[20..24].copy_from_slice(&bot::be_u32_bytes(1536));
+        header[24..28].copy_from_slice(&bot::be_u32_bytes(0x10000));
+        header[28..32].copy_from_slice(&bot::be_u32_bytes((SIZE / BLOCK as u64) as u32));
+        header[32..36].copy_from_slice(&bot::be_u32_bytes(BLOCK as u32));
+        let sum = checksum(&header, DYNAMIC_CHECKSUM);
+        header[36..40].copy_from_slice(&bot::be_u32_bytes(sum));
+
+        let mut image = Vec::new();
+        image.extend_from_slice(&footer);
+        image.extend_from_slice(&header);
+        image.extend_from_slice(&[0xFF ; SECTOR_SIZE]);
+        image.extend_from_slice(&footer);
+        File::create(&temp.0).unwrap().write_all(&image).unwrap();
+        temp
+    }
+
+    fn pattern(len : usize, seed : u8) -> Vec<u8> {
+        (0 .. len).map(|idx| (idx as u8).wrapping_mul(7).wrapping_add(seed)).collect()
+    }
+
+    fn read(disk : &mut VhdDisk, lba : u64, blocks : usize) -> Vec<u8> {
+        let mut buffer = vec![0 ; blocks * SECTOR_SIZE];
+        disk.read_blocks(lba, &mut buffer).unwrap();
+        buffer
+    }
+
+    fn patch(path : &Path, offset : u64, bytes : &[u8]) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn write_allocates_block_and_moves_footer() {
+        let temp = dynamic_image("vhd-allocate");
+        let data = pattern(2 * SECTOR_SIZE, 3);
+        {
+            let mut disk = VhdDisk::open(&temp.0).unwrap();
+            assert_eq!(disk.block_count(), SIZE / SECTOR_SIZE as u64);
+            assert_eq!(read(&mut disk, 9, 2), vec![0 ; 2 * SECTOR_SIZE]);
+            disk.write_blocks(9, &data).unwrap();
+            disk.flush().unwrap();
+        }
+        let new_footer = FOOTER_AT + SECTOR_SIZE as u64 + BLOCK as u64;
+        assert_eq!(std::fs::metadata(&temp.0).unwrap().len(), new_footer + FOOTER_LEN as u64);
+        let mut file = File::open(&temp.0).unwrap();
+        assert_eq!(read_footer(&mut file).unwrap().unwrap().1, new_footer);
+
+        let mut disk = VhdDisk::open(&temp.0).unwrap();
+        assert_eq!(disk.dynamic.as_ref().unwrap().bat[1], (FOOTER_AT / SECTOR_SIZE as u64) as u32);
+        assert_eq!(read(&mut disk, 9, 2), data);
+        assert_eq!(read(&mut disk, 8, 1), vec![0 ; SECTOR_SIZE]);
+        assert_eq!(read(&mut disk, 0, 8), vec![0 ; 8 * SECTOR_SIZE]);
+        // Writing to the allocated block again stays in place.
+        disk.write_blocks(15, &data[.. SECTOR_SIZE]).unwrap();
+        assert_eq!(read(&mut disk, 15, 1), &data[.. SECTOR_SIZE]);
+        assert_eq!(std::fs::metadata(&temp.0).unwrap().len(), new_footer + FOOTER_LEN as u64);
+    }
+
+    #[test]
+    fn sectors_missing_from_bitmap_read_as_zeros() {
+        let temp = dynamic_image("vhd-bitmap");
+        let data = pattern(BLOCK, 9);
+        {
+            let mut disk = VhdDisk::open(&temp.0).unwrap();
+            disk.write_blocks(0, &data).unwrap();
+        }
+        // Only sectors 0 and 7 are present.
+        patch(&temp.0, FOOTER_AT, &[0x81]);
+        let mut disk = VhdDisk::open(&temp.0).unwrap();
+        let mut expected = vec![0 ; BLOCK];
+        expected[.. SECTOR_SIZE].copy_from_slice(&data[.. SECTOR_SIZE]);
+        expected[7 * SECTOR_SIZE ..].copy_from_slice(&data[7 * SECTOR_SIZE ..]);
+        assert_eq!(read(&mut disk, 0, 8), expected);
+
+        let sector = pattern(SECTOR_SIZE, 1);
+        disk.write_blocks(3, &sector).unwrap();
+        assert_eq!(read(&mut disk, 3, 1), sector);
+        assert_eq!(disk.read_bitmap(0).unwrap()[0], 0x91);
+    }
+
+    #[test]
+    fn checksum_mismatch_is_rejected() {
+        let temp = dynamic_image("vhd-footer-checksum");
+        patch(&temp.0, FOOTER_AT + 30, &[1]);
+        let err = VhdDisk::open(&temp.0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("footer checksum"));
+
+        let temp = dynamic_image("vhd-header-checksum");
+        patch(&temp.0, FOOTER_LEN as u64 + 40, &[1]);
+        let err = VhdDisk::open(&temp.0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("dynamic header checksum"));
+    }
+}